@@ -15,6 +15,8 @@ pub struct BanyanS3Auth {
 
 #[derive(Debug, Deserialize)]
 // TODO get this right... this should be what's in the firestore db
+// only is_s3_enabled gets looked at so far
+#[allow(dead_code)]
 pub struct BanyanUser {
     pub id: String,
     pub is_s3_enabled: bool,
@@ -106,7 +108,7 @@ impl BanyanS3Auth {
         Ok(skw.0)
     }
 
-    pub fn has_write_permission_to_bucket(&self, credentials: Option<Credentials>, bucket_name: String) -> S3Result<bool> {
+    pub fn has_write_permission_to_bucket(&self, _credentials: Option<Credentials>, _bucket_name: String) -> S3Result<bool> {
         unimplemented!("see how you're passing an 'auth' into wnfss3service? is that good...? is there a better way to do this?");
     }
 }
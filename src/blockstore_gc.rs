@@ -0,0 +1,479 @@
+use std::{collections::HashSet, str::FromStr, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use libipld::{cbor::DagCborCodec, codec::Codec, pb::DagPbCodec, Cid, Ipld, IpldCodec};
+use tokio::task::JoinHandle;
+use wnfs::BlockStore;
+
+use crate::{
+    blockstores::AnyBlockStore,
+    bucket_registry::{BucketRegistry, BucketRoot},
+    multipart_uploads::{CloudStorageForMultipartConstruction, GC_LEASE},
+    wnfs_buckets::{composite_segments, run_wnfs_anyhow},
+};
+
+/// what gc keeps, and whether it actually deletes anything
+#[derive(Debug, Clone)]
+pub struct GcConfig {
+    /// roots committed within this long ago are kept alive, as well as the current ones
+    pub retention: chrono::Duration,
+    /// blocks written within this long ago are never swept, in case whatever wrote them hasn't linked them in yet.
+    /// blocks in stores that can't say when they were written are only swept when this is zero.
+    pub grace: chrono::Duration,
+    /// report what would be swept without sweeping it
+    pub dry_run: bool,
+}
+
+/// what a gc run found and did
+#[derive(Debug, Default)]
+pub struct GcReport {
+    pub roots: usize,
+    pub reachable: usize,
+    /// blocks something points at that weren't in the store
+    pub missing: usize,
+    pub listed: usize,
+    pub unreachable: usize,
+    /// unreachable blocks spared because they're younger than the grace period
+    pub too_young: usize,
+    pub swept: usize,
+    pub failed: usize,
+}
+
+impl std::fmt::Display for GcReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} roots, {} reachable blocks ({} missing), {} blocks in the store, {} unreachable, {} too young to sweep, {} swept, {} failed to sweep",
+            self.roots,
+            self.reachable,
+            self.missing,
+            self.listed,
+            self.unreachable,
+            self.too_young,
+            self.swept,
+            self.failed
+        )
+    }
+}
+
+/// runs one mark and sweep over the blockstore.
+///
+/// the store is listed before anything is marked, so a block written during marking can't be swept:
+/// it either isn't in the listing, or it's linked in by the time marking looks for it.
+/// the exception is a block written before the listing and linked in after marking, which is what the grace period is for.
+pub async fn run_gc(
+    store: AnyBlockStore,
+    registry: &BucketRegistry,
+    multipart_cloud_storage: &CloudStorageForMultipartConstruction,
+    config: &GcConfig,
+) -> Result<GcReport> {
+    let mut report = GcReport::default();
+
+    let listed = store.list_blocks().await?;
+    report.listed = listed.len();
+
+    // everything the registry could still point a bucket at
+    let mut roots: Vec<BucketRoot> = registry
+        .list_roots()
+        .await
+        .map_err(|e| anyhow!("couldn't list bucket roots: {:?}", e))?;
+    roots.extend(
+        registry
+            .list_commits_since(Utc::now() - config.retention)
+            .await
+            .map_err(|e| anyhow!("couldn't list root commits: {:?}", e))?
+            .into_iter()
+            .map(|commit| commit.root),
+    );
+    // snapshots are kept for as long as they're around, however old they are
+    roots.extend(
+        registry
+            .list_snapshots(None)
+            .await
+            .map_err(|e| anyhow!("couldn't list snapshots: {:?}", e))?
+            .into_iter()
+            .map(|snapshot| snapshot.root),
+    );
+    report.roots = roots.len();
+    // parts of direct mode uploads that haven't been completed yet
+    let mut start: Vec<Cid> = multipart_cloud_storage
+        .list_live_segments()
+        .await?
+        .into_iter()
+        .map(|segment| Cid::from_str(&segment.forest_cid))
+        .collect::<Result<_, _>>()?;
+
+    let mark_store = store.clone();
+    let (reachable, missing) = run_wnfs_anyhow(move || async move {
+        for root in &roots {
+            start.extend(walk_start(root, &mark_store).await?);
+        }
+        mark(start, &mark_store).await
+    })
+    .await?;
+    report.reachable = reachable.len();
+    report.missing = missing;
+    // a block we couldn't read could have linked to anything, so nothing unreachable is safe to sweep
+    if missing > 0 {
+        return Err(anyhow!(
+            "{} reachable blocks couldn't be read, so nothing was swept. {}",
+            missing,
+            report
+        ));
+    }
+
+    for block in listed {
+        if reachable.contains(&block.cid) {
+            continue;
+        }
+        report.unreachable += 1;
+        // without a time, there's no telling how young a block is
+        let too_young = match block.modified {
+            Some(modified) => modified > Utc::now() - config.grace,
+            None => !config.grace.is_zero(),
+        };
+        if too_young {
+            report.too_young += 1;
+            continue;
+        }
+        if config.dry_run {
+            log::info!("blockstore gc: would sweep {}", block.cid);
+            continue;
+        }
+        match store.delete_block(&block.cid).await {
+            Ok(()) => report.swept += 1,
+            Err(e) => {
+                log::error!("blockstore gc: couldn't sweep {}. error was {}", block.cid, e);
+                report.failed += 1;
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// adds the cids a block links to onto `links`. only dag-cbor blocks (wnfs's own structures) and dag-pb blocks
+/// (public buckets' files) have links; everything else, like wnfs's encrypted raw blocks, is a leaf.
+pub(crate) fn block_links(cid: &Cid, bytes: &[u8], links: &mut Vec<Cid>) -> Result<()> {
+    let ipld: Ipld = match IpldCodec::try_from(cid.codec()) {
+        Ok(IpldCodec::DagCbor) => DagCborCodec.decode(bytes)?,
+        Ok(IpldCodec::DagPb) => DagPbCodec.decode(bytes)?,
+        _ => return Ok(()),
+    };
+    ipld.references(links);
+    Ok(())
+}
+
+/// where a walk over everything reachable from `root` has to start: the root itself, and the segments of its composite objects.
+/// segments are only linked to from inside encrypted files, where a walk following links can't see them.
+pub(crate) async fn walk_start(root: &BucketRoot, store: &impl BlockStore) -> Result<Vec<Cid>> {
+    let mut start = vec![Cid::from_str(root.root_cid())?];
+    for segment in composite_segments(root, store).await? {
+        start.push(Cid::from_str(&segment.forest_cid)?);
+    }
+    Ok(start)
+}
+
+/// walks the dag from `start`, returning every cid it reaches and how many of them weren't in the store
+async fn mark(start: Vec<Cid>, store: &impl BlockStore) -> Result<(HashSet<Cid>, usize)> {
+    let mut reachable = HashSet::new();
+    let mut missing = 0;
+    let mut stack = start;
+    while let Some(cid) = stack.pop() {
+        if !reachable.insert(cid) {
+            continue;
+        }
+        // leaves don't need reading
+        if !matches!(
+            IpldCodec::try_from(cid.codec()),
+            Ok(IpldCodec::DagCbor | IpldCodec::DagPb)
+        ) {
+            continue;
+        }
+        let bytes = match store.get_block(&cid).await {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                log::warn!("blockstore gc: {} is reachable but couldn't be read: {}", cid, e);
+                missing += 1;
+                continue;
+            }
+        };
+        block_links(&cid, &bytes, &mut stack)?;
+    }
+    Ok((reachable, missing))
+}
+
+/// spawns a task that runs gc every `interval`.
+/// every replica can run one of these, but only the one holding the gc lease actually collects.
+pub fn spawn_gc(
+    store: AnyBlockStore,
+    registry: Arc<BucketRegistry>,
+    multipart_cloud_storage: Arc<CloudStorageForMultipartConstruction>,
+    interval: Duration,
+    config: GcConfig,
+) -> JoinHandle<()> {
+    // identifies this replica to the lease
+    let holder = uuid::Uuid::new_v4().to_string();
+    // like the multipart sweeper's, held a little past the next tick so the holder gets to renew it first
+    let lease_ttl =
+        chrono::Duration::from_std(interval * 2).unwrap_or_else(|_| chrono::Duration::hours(1));
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            match multipart_cloud_storage
+                .try_acquire_sweep_lease(GC_LEASE, &holder, lease_ttl)
+                .await
+            {
+                Ok(true) => {}
+                Ok(false) => {
+                    log::debug!("blockstore gc: another replica holds the gc lease. skipping.");
+                    continue;
+                }
+                Err(e) => {
+                    log::error!("blockstore gc: couldn't acquire gc lease. skipping. error was {}", e);
+                    continue;
+                }
+            }
+            log::info!("blockstore gc: starting");
+            match run_gc(
+                store.clone(),
+                &registry,
+                &multipart_cloud_storage,
+                &config,
+            )
+            .await
+            {
+                Ok(report) => log::info!("blockstore gc: done. {}", report),
+                Err(e) => log::error!("blockstore gc: failed. nothing was swept. error was {}", e),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, time::SystemTime};
+
+    use bytes::Bytes;
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::{
+        fs_blockstore::FsBlockStore,
+        multipart_staging::MemoryStagingStore,
+        multipart_uploads::{MultipartLimits, MultipartMode, SafeString},
+        wnfs_buckets::WnfsBuckets,
+    };
+
+    struct Fixture {
+        dir: PathBuf,
+        fs: FsBlockStore,
+        registry: Arc<BucketRegistry>,
+        buckets: Arc<WnfsBuckets>,
+        multipart: CloudStorageForMultipartConstruction,
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    /// buckets and direct mode uploads over a blockstore on disk, which knows when its blocks were written
+    async fn fixture() -> Fixture {
+        let dir = std::env::temp_dir().join(format!("gc-{}", uuid::Uuid::new_v4()));
+        let fs = FsBlockStore::new(dir.clone()).await.unwrap();
+        let registry = Arc::new(BucketRegistry::in_memory());
+        let buckets = Arc::new(WnfsBuckets::new(registry.clone(), AnyBlockStore::Fs(fs.clone())));
+        let multipart = CloudStorageForMultipartConstruction::new(
+            Box::new(MemoryStagingStore::default()),
+            buckets.clone(),
+            MultipartMode::Direct,
+            MultipartLimits {
+                max_part_size: 1 << 20,
+                max_object_size: 1 << 20,
+            },
+        );
+        Fixture {
+            dir,
+            fs,
+            registry,
+            buckets,
+            multipart,
+        }
+    }
+
+    fn config(retention: chrono::Duration, grace: chrono::Duration, dry_run: bool) -> GcConfig {
+        GcConfig {
+            retention,
+            grace,
+            dry_run,
+        }
+    }
+
+    impl Fixture {
+        async fn gc(&self, config: GcConfig) -> Result<GcReport> {
+            run_gc(self.buckets.blockstore(), &self.registry, &self.multipart, &config).await
+        }
+
+        async fn put(&self, bucket_name: &str, key: &str, content: &'static str) {
+            let body = futures::stream::iter([Ok(Bytes::from(content))]);
+            self.buckets
+                .write_object(bucket_name, key, body, "\"test\"", "test")
+                .await
+                .unwrap();
+        }
+
+        async fn get(&self, bucket_name: &str, key: &str) -> String {
+            let body: Vec<Bytes> = self
+                .buckets
+                .read_object(bucket_name, key, None)
+                .await
+                .unwrap()
+                .map(|chunk| chunk.unwrap())
+                .collect()
+                .await;
+            String::from_utf8(body.concat()).unwrap()
+        }
+
+        /// uploads one part of `key` in direct mode, leaving the upload open
+        async fn upload_part(&self, key: &str, content: &'static str) -> String {
+            let upload = self.upload(key);
+            self.multipart
+                .create_multipart_upload_folder(upload.0.clone(), upload.1.clone(), upload.2.clone())
+                .await
+                .unwrap();
+            let body = futures::stream::iter([Ok::<_, std::io::Error>(Bytes::from(content))]);
+            self.multipart
+                .upload_part(upload.0, upload.1, upload.2, 1, None, body)
+                .await
+                .unwrap()
+        }
+
+        async fn finish_upload(&self, key: &str, etag: String) {
+            let (bucket, key, upload_id) = self.upload(key);
+            self.multipart
+                .finish_upload(bucket, key, upload_id, vec![(1, etag)], "test")
+                .await
+                .unwrap();
+        }
+
+        fn upload(&self, key: &str) -> (SafeString, SafeString, SafeString) {
+            (
+                SafeString::new("photos".to_string()),
+                SafeString::new(key.to_string()),
+                SafeString::new(format!("upload-{}", key)),
+            )
+        }
+
+        /// a block nothing links to, written `age` ago
+        async fn orphan(&self, content: &[u8], age: Duration) -> Cid {
+            let cid = self
+                .fs
+                .clone()
+                .put_block(content.to_vec(), IpldCodec::Raw)
+                .await
+                .unwrap();
+            std::fs::File::options()
+                .write(true)
+                .open(self.fs.path_for(&cid))
+                .unwrap()
+                .set_modified(SystemTime::now() - age)
+                .unwrap();
+            cid
+        }
+
+        async fn has(&self, cid: &Cid) -> bool {
+            self.fs.get_block(cid).await.is_ok()
+        }
+    }
+
+    const DAY: Duration = Duration::from_secs(60 * 60 * 24);
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn everything_buckets_can_still_reach_survives() {
+        let fixture = fixture().await;
+        fixture.buckets.create_bucket("photos", false, "test").await.unwrap();
+        fixture.put("photos", "cat.txt", "one").await;
+        let etag = fixture.upload_part("composite.txt", "made of parts").await;
+        fixture.finish_upload("composite.txt", etag).await;
+        fixture
+            .buckets
+            .create_snapshot("photos", "then", None, "test")
+            .await
+            .unwrap();
+        fixture.put("photos", "cat.txt", "two").await;
+        let between = Utc::now();
+        tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+        fixture.put("photos", "cat.txt", "three").await;
+        let live = fixture.upload_part("live.txt", "still uploading").await;
+        let orphan = fixture.orphan(b"nobody wants me", DAY).await;
+
+        // "two" is only in a commit, which is kept for as long as it's retained
+        let report = fixture
+            .gc(config(chrono::Duration::hours(1), chrono::Duration::zero(), false))
+            .await
+            .unwrap();
+        assert_eq!(report.missing, 0);
+        assert!(report.swept > 0);
+        assert!(!fixture.has(&orphan).await);
+        fixture
+            .buckets
+            .restore_bucket("photos", between, Some("photos-then"), "test")
+            .await
+            .unwrap();
+        assert_eq!(fixture.get("photos-then", "cat.txt").await, "two");
+
+        // "one" is only in a snapshot, which is kept for as long as it's around, however old
+        fixture
+            .gc(config(chrono::Duration::zero(), chrono::Duration::zero(), false))
+            .await
+            .unwrap();
+        assert_eq!(fixture.get("photos--snap--then", "cat.txt").await, "one");
+        assert_eq!(fixture.get("photos--snap--then", "composite.txt").await, "made of parts");
+        assert_eq!(fixture.get("photos", "cat.txt").await, "three");
+        assert_eq!(fixture.get("photos", "composite.txt").await, "made of parts");
+        // the part of an upload that hasn't been completed is only known to the staging store
+        fixture.finish_upload("live.txt", live).await;
+        assert_eq!(fixture.get("photos", "live.txt").await, "still uploading");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn only_unreachable_blocks_older_than_the_grace_period_are_swept() {
+        let fixture = fixture().await;
+        let old = fixture.orphan(b"old", 2 * DAY).await;
+        let young = fixture.orphan(b"young", Duration::from_secs(60)).await;
+
+        let grace = chrono::Duration::days(1);
+        let report = fixture.gc(config(chrono::Duration::zero(), grace, true)).await.unwrap();
+        assert_eq!((report.unreachable, report.too_young, report.swept), (2, 1, 0));
+        assert!(fixture.has(&old).await && fixture.has(&young).await);
+
+        let report = fixture.gc(config(chrono::Duration::zero(), grace, false)).await.unwrap();
+        assert_eq!((report.unreachable, report.too_young, report.swept), (2, 1, 1));
+        assert!(!fixture.has(&old).await);
+        assert!(fixture.has(&young).await);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn an_unreadable_reachable_block_stops_the_sweep() {
+        let fixture = fixture().await;
+        fixture.buckets.create_bucket("photos", true, "test").await.unwrap();
+        fixture.put("photos", "cat.txt", "one").await;
+        let orphan = fixture.orphan(b"nobody wants me", DAY).await;
+        let root = fixture.registry.get_root("photos").await.unwrap().unwrap();
+        fixture
+            .fs
+            .delete_block(&Cid::from_str(root.root_cid()).unwrap())
+            .await
+            .unwrap();
+
+        let error = fixture
+            .gc(config(chrono::Duration::zero(), chrono::Duration::zero(), false))
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("nothing was swept"), "{}", error);
+        assert!(fixture.has(&orphan).await);
+    }
+}
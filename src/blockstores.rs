@@ -0,0 +1,444 @@
+use std::{borrow::Cow, path::PathBuf, str::FromStr};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::{future::BoxFuture, FutureExt};
+use libipld::{Cid, IpldCodec};
+use reqwest::Url;
+use wnfs::BlockStore;
+
+use crate::{
+    compressed_blockstore::CompressedBlockStore,
+    fs_blockstore::FsBlockStore,
+    gcs_blockstore::GcsBlockStore,
+    kubo_blockstore::KuboBlockStore,
+    mutex_memory_blockstore::MutexMemoryBlockStore,
+    replicated_blockstore::ReplicatedBlockStore,
+    s3_blockstore::{S3BlockStore, S3BlockStoreConfig},
+    tiered_blockstore::{CacheMode, TieredBlockStore},
+};
+
+/// which kind of blockstore the buckets' blocks live in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum BlockstoreBackend {
+    /// memory. everything is lost on restart!
+    Memory,
+    /// a directory on local disk
+    Fs,
+    /// a google cloud storage bucket
+    Gcs,
+    /// a bucket in any s3-compatible object store
+    S3,
+    /// an IPFS node, through kubo's RPC API
+    Kubo,
+}
+
+/// the blockstore's command line settings
+#[derive(clap::Args, Debug)]
+pub struct BlockstoreArgs {
+    /// Where the blocks of buckets' WNFS trees are kept
+    #[arg(long, value_enum, default_value_t = BlockstoreBackend::Memory)]
+    pub blockstore: BlockstoreBackend,
+
+    /// Directory for blocks, when keeping them on local disk
+    #[arg(long)]
+    pub blockstore_dir: Option<PathBuf>,
+
+    /// Cloud storage bucket for blocks, when keeping them in gcs
+    #[arg(long, default_value = "wnfs_blocks")]
+    pub blockstore_gcs_bucket: String,
+
+    /// Cloud storage endpoint to use instead of google's, e.g. a fake-gcs-server for testing.
+    /// Requests to it aren't authenticated.
+    #[arg(long)]
+    pub blockstore_gcs_endpoint: Option<String>,
+
+    /// Root URL of the S3-compatible object store for blocks, when keeping them in one
+    #[arg(long)]
+    pub blockstore_s3_endpoint: Option<reqwest::Url>,
+
+    /// Bucket for blocks, when keeping them in an S3-compatible object store
+    #[arg(long, default_value = "wnfs-blocks")]
+    pub blockstore_s3_bucket: String,
+
+    /// Prefix for the keys of blocks, when keeping them in an S3-compatible object store
+    #[arg(long, default_value = "")]
+    pub blockstore_s3_prefix: String,
+
+    /// Region of the S3-compatible object store for blocks
+    #[arg(long, default_value = "us-east-1")]
+    pub blockstore_s3_region: String,
+
+    /// Access key id for the S3-compatible object store for blocks
+    #[arg(long, env = "BLOCKSTORE_S3_ACCESS_KEY_ID", hide_env_values = true)]
+    pub blockstore_s3_access_key_id: Option<String>,
+
+    /// Secret access key for the S3-compatible object store for blocks
+    #[arg(long, env = "BLOCKSTORE_S3_SECRET_ACCESS_KEY", hide_env_values = true)]
+    pub blockstore_s3_secret_access_key: Option<String>,
+
+    /// Root URL of the Kubo RPC API for blocks, when keeping them in an IPFS node
+    #[arg(long, default_value = "http://127.0.0.1:5001")]
+    pub blockstore_kubo_endpoint: reqwest::Url,
+
+    /// Whether to pin blocks in the IPFS node, so its garbage collection leaves them alone.
+    /// Our gc only knows which blocks are ours by their pins, so it refuses to run with this off.
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    pub blockstore_kubo_pin: bool,
+
+    /// Another blockstore to keep a copy of every block in, as <kind>:<where>, e.g. gcs:wnfs-blocks-replica.
+    /// Can be given more than once. Settings that don't fit in that, like credentials, are shared with the main blockstore.
+    #[arg(long)]
+    pub blockstore_replica: Vec<BlockstoreSpec>,
+
+    /// How many copies of a block have to be written for the write to succeed, when keeping replicas.
+    /// Defaults to all of them.
+    #[arg(long)]
+    pub blockstore_write_quorum: Option<usize>,
+
+    /// Zstd level to compress blocks with before storing them. Unset means blocks are stored as they are.
+    /// Blocks stored either way can be read either way. Doesn't work with kubo, which names blocks by what it's given.
+    #[arg(long)]
+    pub blockstore_compression_level: Option<i32>,
+
+    /// How many bytes of blocks to cache in memory in front of a remote blockstore. 0 turns the cache off.
+    #[arg(long, default_value_t = 0)]
+    pub blockstore_cache_bytes: usize,
+
+    /// Directory to cache blocks in on local disk, in front of a remote blockstore
+    #[arg(long)]
+    pub blockstore_cache_dir: Option<PathBuf>,
+
+    /// Whether writes wait for the remote blockstore, when caching in front of one
+    #[arg(long, value_enum, default_value_t = CacheMode::WriteThrough)]
+    pub blockstore_cache_mode: CacheMode,
+}
+
+/// the ways getting a block can go wrong that are worth telling apart. blockstores return these inside anyhow errors.
+#[derive(Debug)]
+pub enum BlockError {
+    NotFound(Cid),
+    /// the bytes we got don't hash to the cid
+    Corrupt(Cid),
+}
+
+impl std::fmt::Display for BlockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BlockError::NotFound(cid) => write!(f, "CID not found in blockstore: {}", cid),
+            BlockError::Corrupt(cid) => write!(f, "block {} is corrupt: its hash doesn't match", cid),
+        }
+    }
+}
+
+impl std::error::Error for BlockError {}
+
+/// a block, as a listing of a blockstore sees it
+#[derive(Debug, Clone)]
+pub struct ListedBlock {
+    pub cid: Cid,
+    /// when the block was written, if the store keeps track
+    pub modified: Option<DateTime<Utc>>,
+}
+
+/// whichever blockstore we were configured with.
+/// wnfs's BlockStore has generic methods, so it can't be a trait object; this does the dispatching instead.
+#[derive(Debug, Clone)]
+pub enum AnyBlockStore {
+    Memory(MutexMemoryBlockStore),
+    Fs(FsBlockStore),
+    Gcs(GcsBlockStore),
+    S3(S3BlockStore),
+    Kubo(KuboBlockStore),
+    Tiered(TieredBlockStore),
+    Replicated(ReplicatedBlockStore),
+    Compressed(CompressedBlockStore),
+}
+
+impl AnyBlockStore {
+    /// lists every block in the store. wnfs never needs to, but gc does.
+    /// stores can wrap other stores, so this is boxed, or the compiler can't work out that it's Send.
+    pub fn list_blocks(&self) -> BoxFuture<'_, Result<Vec<ListedBlock>>> {
+        async move {
+            match self {
+                AnyBlockStore::Memory(store) => Ok(store
+                    .list_blocks()?
+                    .into_iter()
+                    .map(|cid| ListedBlock {
+                        cid,
+                        modified: None,
+                    })
+                    .collect()),
+                AnyBlockStore::Fs(store) => store.list_blocks().await,
+                AnyBlockStore::Gcs(store) => store.list_blocks().await,
+                AnyBlockStore::S3(store) => store.list_blocks().await,
+                AnyBlockStore::Kubo(store) => store.list_blocks().await,
+                AnyBlockStore::Tiered(store) => store.list_blocks().await,
+                AnyBlockStore::Replicated(store) => store.list_blocks().await,
+                AnyBlockStore::Compressed(store) => store.list_blocks().await,
+            }
+        }
+        .boxed()
+    }
+
+    /// removes a block from the store. wnfs never needs to, but gc does. boxed for the same reason as list_blocks.
+    pub fn delete_block<'a>(&'a self, cid: &'a Cid) -> BoxFuture<'a, Result<()>> {
+        async move {
+            match self {
+                AnyBlockStore::Memory(store) => store.delete_block(cid),
+                AnyBlockStore::Fs(store) => store.delete_block(cid).await,
+                AnyBlockStore::Gcs(store) => store.delete_block(cid).await,
+                AnyBlockStore::S3(store) => store.delete_block(cid).await,
+                AnyBlockStore::Kubo(store) => store.delete_block(cid).await,
+                AnyBlockStore::Tiered(store) => store.delete_block(cid).await,
+                AnyBlockStore::Replicated(store) => store.delete_block(cid).await,
+                AnyBlockStore::Compressed(store) => store.delete_block(cid).await,
+            }
+        }
+        .boxed()
+    }
+}
+
+impl AnyBlockStore {
+    /// stores `bytes` under `cid`, without checking that they're the block `cid` names.
+    /// only stores that address objects by whatever name they're given can do this, which is what compression needs.
+    /// all of them overwrite whatever was already stored under `cid`, except gcs, which keeps it and just marks it as used.
+    pub async fn put_stored(&self, cid: &Cid, bytes: Vec<u8>) -> Result<()> {
+        match self {
+            AnyBlockStore::Memory(store) => store.put_stored(cid, bytes),
+            AnyBlockStore::Fs(store) => store.put_stored(cid, &bytes).await,
+            AnyBlockStore::Gcs(store) => store.put_stored(cid, bytes).await,
+            AnyBlockStore::S3(store) => store.put_stored(cid, bytes).await,
+            _ => Err(anyhow!("this blockstore can only store blocks under their own cids")),
+        }
+    }
+
+    /// gets whatever is stored under `cid`, without checking it
+    pub async fn get_stored(&self, cid: &Cid) -> Result<Vec<u8>> {
+        match self {
+            AnyBlockStore::Memory(store) => store.get_stored(cid),
+            AnyBlockStore::Fs(store) => store.get_stored(cid).await,
+            AnyBlockStore::Gcs(store) => store.get_stored(cid).await,
+            AnyBlockStore::S3(store) => store.get_stored(cid).await,
+            _ => Err(anyhow!("this blockstore can only hand out checked blocks")),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl BlockStore for AnyBlockStore {
+    async fn put_block(&mut self, bytes: Vec<u8>, codec: IpldCodec) -> Result<Cid> {
+        match self {
+            AnyBlockStore::Memory(store) => store.put_block(bytes, codec).await,
+            AnyBlockStore::Fs(store) => store.put_block(bytes, codec).await,
+            AnyBlockStore::Gcs(store) => store.put_block(bytes, codec).await,
+            AnyBlockStore::S3(store) => store.put_block(bytes, codec).await,
+            AnyBlockStore::Kubo(store) => store.put_block(bytes, codec).await,
+            AnyBlockStore::Tiered(store) => store.put_block(bytes, codec).await,
+            AnyBlockStore::Replicated(store) => store.put_block(bytes, codec).await,
+            AnyBlockStore::Compressed(store) => store.put_block(bytes, codec).await,
+        }
+    }
+
+    async fn get_block<'a>(&'a self, cid: &Cid) -> Result<Cow<'a, Vec<u8>>> {
+        match self {
+            AnyBlockStore::Memory(store) => store.get_block(cid).await,
+            AnyBlockStore::Fs(store) => store.get_block(cid).await,
+            AnyBlockStore::Gcs(store) => store.get_block(cid).await,
+            AnyBlockStore::S3(store) => store.get_block(cid).await,
+            AnyBlockStore::Kubo(store) => store.get_block(cid).await,
+            AnyBlockStore::Tiered(store) => store.get_block(cid).await,
+            AnyBlockStore::Replicated(store) => store.get_block(cid).await,
+            AnyBlockStore::Compressed(store) => store.get_block(cid).await,
+        }
+    }
+}
+
+/// a blockstore named on the command line, as `<kind>:<where>`:
+/// `memory:`, `fs:/var/blocks`, `gcs:my-bucket`, `s3:https://host/bucket/prefix/` or `kubo:http://127.0.0.1:5001`.
+/// settings that don't fit in the name, like credentials, come from the main blockstore's arguments.
+#[derive(Debug, Clone)]
+pub enum BlockstoreSpec {
+    Memory,
+    Fs(PathBuf),
+    Gcs(String),
+    S3 {
+        endpoint: Url,
+        bucket: String,
+        prefix: String,
+    },
+    Kubo(Url),
+}
+
+impl FromStr for BlockstoreSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (kind, location) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow!("blockstores look like <kind>:<where>, e.g. fs:/var/blocks"))?;
+        Ok(match kind {
+            "memory" => BlockstoreSpec::Memory,
+            "fs" => BlockstoreSpec::Fs(location.into()),
+            "gcs" => BlockstoreSpec::Gcs(location.to_string()),
+            "s3" => {
+                let mut endpoint = Url::parse(location)?;
+                let path = endpoint.path().trim_start_matches('/').to_string();
+                let (bucket, prefix) = path.split_once('/').unwrap_or((&path, ""));
+                if bucket.is_empty() {
+                    return Err(anyhow!("s3 blockstores need a bucket, e.g. s3:https://host/bucket/"));
+                }
+                let (bucket, prefix) = (bucket.to_string(), prefix.to_string());
+                endpoint.set_path("/");
+                BlockstoreSpec::S3 {
+                    endpoint,
+                    bucket,
+                    prefix,
+                }
+            }
+            "kubo" => BlockstoreSpec::Kubo(Url::parse(location)?),
+            _ => return Err(anyhow!("unknown kind of blockstore {:?}", kind)),
+        })
+    }
+}
+
+impl BlockstoreSpec {
+    /// opens the blockstore, compressing in front of it if the arguments say to
+    pub async fn open(self, args: &BlockstoreArgs) -> Result<AnyBlockStore> {
+        let store = match self {
+            BlockstoreSpec::Memory => AnyBlockStore::Memory(MutexMemoryBlockStore::new()),
+            BlockstoreSpec::Fs(dir) => AnyBlockStore::Fs(FsBlockStore::new(dir).await?),
+            BlockstoreSpec::Gcs(bucket) => AnyBlockStore::Gcs(
+                GcsBlockStore::new(bucket, args.blockstore_gcs_endpoint.clone()).await?,
+            ),
+            BlockstoreSpec::S3 {
+                endpoint,
+                bucket,
+                prefix,
+            } => AnyBlockStore::S3(S3BlockStore::new(S3BlockStoreConfig {
+                endpoint,
+                bucket,
+                prefix,
+                region: args.blockstore_s3_region.clone(),
+                access_key_id: args
+                    .blockstore_s3_access_key_id
+                    .clone()
+                    .ok_or_else(|| anyhow!("the s3 blockstore needs an access key id"))?,
+                secret_access_key: args
+                    .blockstore_s3_secret_access_key
+                    .clone()
+                    .ok_or_else(|| anyhow!("the s3 blockstore needs a secret access key"))?,
+            })),
+            BlockstoreSpec::Kubo(endpoint) => {
+                AnyBlockStore::Kubo(KuboBlockStore::new(endpoint, args.blockstore_kubo_pin))
+            }
+        };
+        Ok(match args.blockstore_compression_level {
+            Some(level) => AnyBlockStore::Compressed(CompressedBlockStore::new(store, level)?),
+            None => store,
+        })
+    }
+}
+
+impl BlockstoreArgs {
+    /// the main blockstore, as the arguments describe it
+    fn spec(&self) -> Result<BlockstoreSpec> {
+        Ok(match self.blockstore {
+            BlockstoreBackend::Memory => BlockstoreSpec::Memory,
+            BlockstoreBackend::Fs => BlockstoreSpec::Fs(
+                self.blockstore_dir
+                    .clone()
+                    .ok_or_else(|| anyhow!("the fs blockstore needs a blockstore directory"))?,
+            ),
+            BlockstoreBackend::Gcs => BlockstoreSpec::Gcs(self.blockstore_gcs_bucket.clone()),
+            BlockstoreBackend::S3 => BlockstoreSpec::S3 {
+                endpoint: self
+                    .blockstore_s3_endpoint
+                    .clone()
+                    .ok_or_else(|| anyhow!("the s3 blockstore needs an endpoint"))?,
+                bucket: self.blockstore_s3_bucket.clone(),
+                prefix: self.blockstore_s3_prefix.clone(),
+            },
+            BlockstoreBackend::Kubo => BlockstoreSpec::Kubo(self.blockstore_kubo_endpoint.clone()),
+        })
+    }
+}
+
+pub async fn blockstore_from_config(args: &BlockstoreArgs) -> Result<AnyBlockStore> {
+    let spec = args.spec()?;
+    // kubo names blocks by the bytes it's given, so it would name compressed blocks wrong.
+    // better to say so before anything is opened than to find out from the first one
+    if args.blockstore_compression_level.is_some()
+        && std::iter::once(&spec)
+            .chain(&args.blockstore_replica)
+            .any(|spec| matches!(spec, BlockstoreSpec::Kubo(_)))
+    {
+        return Err(anyhow!(
+            "--blockstore-compression-level doesn't work with kubo blockstores, which name blocks by what they're given"
+        ));
+    }
+    let mut store = spec.open(args).await?;
+    if !args.blockstore_replica.is_empty() {
+        let mut replicas = vec![store];
+        for spec in &args.blockstore_replica {
+            replicas.push(spec.clone().open(args).await?);
+        }
+        let write_quorum = args.blockstore_write_quorum.unwrap_or(replicas.len());
+        store = AnyBlockStore::Replicated(ReplicatedBlockStore::new(replicas, write_quorum)?);
+    }
+    // caching memory in memory doesn't help anybody
+    if (args.blockstore == BlockstoreBackend::Memory && args.blockstore_replica.is_empty())
+        || (args.blockstore_cache_bytes == 0 && args.blockstore_cache_dir.is_none())
+    {
+        return Ok(store);
+    }
+    let disk = match &args.blockstore_cache_dir {
+        Some(dir) => Some(FsBlockStore::new(dir.clone()).await?),
+        None => None,
+    };
+    Ok(AnyBlockStore::Tiered(TieredBlockStore::new(
+        store,
+        args.blockstore_cache_bytes,
+        disk,
+        args.blockstore_cache_mode,
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        blockstore: BlockstoreArgs,
+    }
+
+    fn args(args: &[&str]) -> BlockstoreArgs {
+        Cli::parse_from(std::iter::once("s3-server").chain(args.iter().copied())).blockstore
+    }
+
+    #[tokio::test]
+    async fn compression_is_refused_in_front_of_kubo() {
+        let refused = [
+            args(&["--blockstore", "kubo", "--blockstore-compression-level", "3"]),
+            args(&[
+                "--blockstore-replica",
+                "kubo:http://127.0.0.1:5001",
+                "--blockstore-compression-level",
+                "3",
+            ]),
+        ];
+        for args in refused {
+            let err = blockstore_from_config(&args).await.unwrap_err();
+            assert!(err.to_string().contains("kubo"), "{}", err);
+        }
+        let store = blockstore_from_config(&args(&["--blockstore-compression-level", "3"]))
+            .await
+            .unwrap();
+        assert!(matches!(store, AnyBlockStore::Compressed(_)));
+    }
+}
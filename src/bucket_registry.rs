@@ -0,0 +1,825 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use firestore::{
+    errors::FirestoreError, timestamp_utils::from_timestamp, FirestoreDb, FirestoreQueryDirection,
+    FirestoreTimestamp, FirestoreWritePrecondition,
+};
+use s3s::{s3_error, S3Result};
+use serde::{Deserialize, Serialize};
+use sha3::Sha3_256;
+use wnfs::{
+    private::{PrivateNodeHeader, PrivateRef, RevisionKey},
+    Hasher,
+};
+
+const BUCKET_ROOTS_COLLECTION: &str = "BUCKET_ROOTS";
+const BUCKET_LIFECYCLES_COLLECTION: &str = "BUCKET_LIFECYCLES";
+const ROOT_COMMITS_COLLECTION: &str = "ROOT_COMMITS";
+const BUCKET_SNAPSHOTS_COLLECTION: &str = "BUCKET_SNAPSHOTS";
+
+/// the registry endpoint that keeps the registry in memory instead of in firestore
+pub const MEMORY_REGISTRY: &str = "memory";
+
+/// what goes between a bucket's name and a snapshot's to read the snapshot as a bucket, like `photos--snap--before-cleanup`.
+/// it has to be something s3s lets through as a bucket name, so it's only reserved by us: create_bucket refuses names with it.
+pub const SNAPSHOT_SEPARATOR: &str = "--snap--";
+
+/// whether a bucket name is really a bucket and one of its snapshots, which can be read but not written
+pub fn is_snapshot(bucket_name: &str) -> bool {
+    bucket_name.contains(SNAPSHOT_SEPARATOR)
+}
+
+/// the bucket a name is about: the bucket itself, or for a snapshot, the bucket it's a snapshot of
+pub fn snapshotted_bucket(bucket_name: &str) -> &str {
+    bucket_name
+        .split_once(SNAPSHOT_SEPARATOR)
+        .map_or(bucket_name, |(bucket_name, _)| bucket_name)
+}
+
+/// the name a snapshot is read as, which is also its id in the registry
+pub fn snapshot_id(bucket_name: &str, snapshot_name: &str) -> String {
+    format!("{}{}{}", bucket_name, SNAPSHOT_SEPARATOR, snapshot_name)
+}
+
+/// what it takes to find a private node in its forest and decrypt it.
+/// wnfs only serializes a PrivateRef encrypted under its parent's key, so we keep the two halves of one ourselves, in hex.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredPrivateRef {
+    /// the hash of the node's saturated name, which is what the forest keys it under
+    pub saturated_name_hash: String,
+    /// the key of the node's current revision, which the content key is derived from
+    pub revision_key: String,
+}
+
+impl StoredPrivateRef {
+    /// the ref of the revision of a node `header` is the header of
+    pub fn of(header: &PrivateNodeHeader) -> Self {
+        Self {
+            saturated_name_hash: hex::encode(saturated_name_hash(header)),
+            revision_key: hex::encode(header.derive_revision_key().0.as_bytes()),
+        }
+    }
+
+    pub fn to_private_ref(&self) -> anyhow::Result<PrivateRef> {
+        Ok(PrivateRef::with_revision_key(
+            decode_32(&self.saturated_name_hash)?,
+            RevisionKey::from(decode_32(&self.revision_key)?),
+        ))
+    }
+}
+
+/// the hash of a node's saturated name, which is what a forest keys the node's blocks under
+pub fn saturated_name_hash(header: &PrivateNodeHeader) -> [u8; 32] {
+    Sha3_256::hash(&header.get_saturated_name())
+}
+
+fn decode_32(hex_str: &str) -> anyhow::Result<[u8; 32]> {
+    hex::decode(hex_str)?
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("expected 32 bytes of hex, got {}", hex_str))
+}
+
+/// everything we need to open a bucket's WNFS tree
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum BucketRoot {
+    /// an encrypted tree: where the forest is, and how to find the root directory in it
+    Private {
+        /// cid of the serialized private forest, as a string
+        forest_cid: String,
+        /// the private ref of the bucket's root directory
+        root_ref: StoredPrivateRef,
+    },
+    /// a plaintext tree, which anyone with the cid can read, e.g. through an IPFS gateway
+    Public {
+        /// cid of the bucket's public root directory, as a string
+        public_root_cid: String,
+    },
+}
+
+impl BucketRoot {
+    /// the cid of the block everything in the bucket hangs off: the forest of a private bucket, or a public one's root directory
+    pub fn root_cid(&self) -> &str {
+        match self {
+            BucketRoot::Private { forest_cid, .. } => forest_cid,
+            BucketRoot::Public { public_root_cid } => public_root_cid,
+        }
+    }
+
+    pub fn is_public(&self) -> bool {
+        matches!(self, BucketRoot::Public { .. })
+    }
+}
+
+/// a bucket's current root, along with the bucket's name, which firestore keeps as the document id
+#[derive(Debug, Clone, Deserialize)]
+struct NamedBucketRoot {
+    #[serde(alias = "_firestore_id")]
+    name: String,
+    #[serde(flatten)]
+    root: BucketRoot,
+}
+
+/// a record of a bucket being pointed at a root. every root a bucket has ever had gets one,
+/// so together they're the bucket's history.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RootCommit {
+    pub bucket: String,
+    pub root: BucketRoot,
+    /// stored as a firestore timestamp, so commits can be filtered and ordered by it in queries
+    #[serde(with = "firestore::serialize_as_timestamp")]
+    pub committed_at: DateTime<Utc>,
+    /// who made the change: the access key of the S3 request, or the admin command that made it
+    pub actor: String,
+}
+
+/// a root of a bucket pinned under a name, so it can still be read, and gc keeps it, however the bucket changes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub bucket: String,
+    pub name: String,
+    pub root: BucketRoot,
+    pub created_at: DateTime<Utc>,
+    pub actor: String,
+}
+
+/// a lifecycle rule aborting incomplete multipart uploads of keys starting with `prefix`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AbortIncompleteUploadRule {
+    pub id: Option<String>,
+    pub prefix: String,
+    pub days_after_initiation: u32,
+}
+
+/// the lifecycle configuration of a bucket. we only support aborting incomplete multipart uploads for now.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BucketLifecycle {
+    pub abort_incomplete_multipart_upload: Vec<AbortIncompleteUploadRule>,
+}
+
+impl BucketLifecycle {
+    /// how long an upload of `key` gets before it's abandoned, if any rule says so.
+    /// when several rules match, the strictest one wins.
+    pub fn abort_after(&self, key: &str) -> Option<chrono::Duration> {
+        self.abort_incomplete_multipart_upload
+            .iter()
+            .filter(|rule| key.starts_with(&rule.prefix))
+            .map(|rule| chrono::Duration::days(rule.days_after_initiation.into()))
+            .min()
+    }
+}
+
+/// whether a write failed because what it was conditioned on had changed, rather than for any reason retrying won't fix
+fn is_conflict(e: &FirestoreError) -> bool {
+    match e {
+        FirestoreError::DataConflictError(_) | FirestoreError::DataNotFoundError(_) => true,
+        FirestoreError::DatabaseError(e) => {
+            e.public.code == "FailedPrecondition" || e.public.code == "Aborted"
+        }
+        _ => false,
+    }
+}
+
+/// maps bucket names to their current WNFS roots and settings.
+pub struct BucketRegistry {
+    backend: RegistryBackend,
+}
+
+/// where the registry keeps what it knows
+enum RegistryBackend {
+    Firestore(Arc<FirestoreDb>),
+    /// everything is lost on restart!
+    Memory(Mutex<MemoryRegistry>),
+}
+
+/// the registry's collections, for keeping in memory
+#[derive(Default)]
+struct MemoryRegistry {
+    roots: BTreeMap<String, BucketRoot>,
+    /// in the order they were made
+    commits: Vec<RootCommit>,
+    snapshots: BTreeMap<String, Snapshot>,
+    lifecycles: BTreeMap<String, BucketLifecycle>,
+}
+
+impl BucketRegistry {
+    /// connects to the registry at `registry_endpoint`, or with `memory`, keeps the registry in memory
+    pub async fn new(registry_endpoint: String) -> Result<Self> {
+        if registry_endpoint == MEMORY_REGISTRY {
+            return Ok(Self::in_memory());
+        }
+        let database_connection = Arc::new(FirestoreDb::new(registry_endpoint).await?);
+        Ok(Self {
+            backend: RegistryBackend::Firestore(database_connection),
+        })
+    }
+
+    pub fn in_memory() -> Self {
+        Self {
+            backend: RegistryBackend::Memory(Default::default()),
+        }
+    }
+
+    /// returns the current root of a bucket, or None if there's no such bucket
+    pub async fn get_root(&self, bucket_name: &str) -> S3Result<Option<BucketRoot>> {
+        let database_connection = match &self.backend {
+            RegistryBackend::Firestore(database_connection) => database_connection,
+            RegistryBackend::Memory(memory) => {
+                return Ok(memory.lock().unwrap().roots.get(bucket_name).cloned())
+            }
+        };
+        database_connection
+            .fluent()
+            .select()
+            .by_id_in(BUCKET_ROOTS_COLLECTION)
+            .obj()
+            .one(bucket_name)
+            .await
+            .map_err(|e| {
+                s3_error!(
+                    InternalError,
+                    "Error looking up bucket root in registry: {}",
+                    e
+                )
+            })
+    }
+
+    /// returns the current roots of all the buckets
+    pub async fn list_roots(&self) -> S3Result<Vec<BucketRoot>> {
+        Ok(self
+            .list_buckets()
+            .await?
+            .into_iter()
+            .map(|(_, root)| root)
+            .collect())
+    }
+
+    /// returns every bucket's name and current root
+    pub async fn list_buckets(&self) -> S3Result<Vec<(String, BucketRoot)>> {
+        let database_connection = match &self.backend {
+            RegistryBackend::Firestore(database_connection) => database_connection,
+            RegistryBackend::Memory(memory) => {
+                return Ok(memory
+                    .lock()
+                    .unwrap()
+                    .roots
+                    .iter()
+                    .map(|(name, root)| (name.clone(), root.clone()))
+                    .collect())
+            }
+        };
+        let buckets: Vec<NamedBucketRoot> = database_connection
+            .fluent()
+            .select()
+            .from(BUCKET_ROOTS_COLLECTION)
+            .obj()
+            .query()
+            .await
+            .map_err(|e| {
+                s3_error!(
+                    InternalError,
+                    "Error listing buckets in registry: {}",
+                    e
+                )
+            })?;
+        Ok(buckets
+            .into_iter()
+            .map(|bucket| (bucket.name, bucket.root))
+            .collect())
+    }
+
+    /// returns the root commits made since `since`, across all buckets
+    pub async fn list_commits_since(&self, since: DateTime<Utc>) -> S3Result<Vec<RootCommit>> {
+        let database_connection = match &self.backend {
+            RegistryBackend::Firestore(database_connection) => database_connection,
+            RegistryBackend::Memory(memory) => {
+                return Ok(memory
+                    .lock()
+                    .unwrap()
+                    .commits
+                    .iter()
+                    .filter(|commit| commit.committed_at >= since)
+                    .cloned()
+                    .collect())
+            }
+        };
+        database_connection
+            .fluent()
+            .select()
+            .from(ROOT_COMMITS_COLLECTION)
+            .filter(|q| {
+                q.for_all([q
+                    .field("committed_at")
+                    .greater_than_or_equal(FirestoreTimestamp(since))])
+            })
+            .obj()
+            .query()
+            .await
+            .map_err(|e| {
+                s3_error!(
+                    InternalError,
+                    "Error listing root commits in registry: {}",
+                    e
+                )
+            })
+    }
+
+    /// returns every root commit of one bucket, oldest first.
+    /// the query needs a composite index on ROOT_COMMITS of bucket and committed_at, ascending.
+    pub async fn list_bucket_commits(&self, bucket_name: &str) -> S3Result<Vec<RootCommit>> {
+        let database_connection = match &self.backend {
+            RegistryBackend::Firestore(database_connection) => database_connection,
+            RegistryBackend::Memory(memory) => {
+                return Ok(memory
+                    .lock()
+                    .unwrap()
+                    .commits
+                    .iter()
+                    .filter(|commit| commit.bucket == bucket_name)
+                    .cloned()
+                    .collect())
+            }
+        };
+        database_connection
+            .fluent()
+            .select()
+            .from(ROOT_COMMITS_COLLECTION)
+            .filter(|q| q.for_all([q.field("bucket").eq(bucket_name)]))
+            .order_by([("committed_at", FirestoreQueryDirection::Ascending)])
+            .obj()
+            .query()
+            .await
+            .map_err(|e| {
+                s3_error!(
+                    InternalError,
+                    "Error listing root commits of bucket in registry: {}",
+                    e
+                )
+            })
+    }
+
+    /// returns the commit a bucket was at, at `at`: the last one made at or before it.
+    /// None if the bucket didn't exist yet.
+    /// the query needs a composite index on ROOT_COMMITS of bucket and committed_at, descending.
+    pub async fn commit_at(&self, bucket_name: &str, at: DateTime<Utc>) -> S3Result<Option<RootCommit>> {
+        let database_connection = match &self.backend {
+            RegistryBackend::Firestore(database_connection) => database_connection,
+            RegistryBackend::Memory(memory) => {
+                return Ok(memory
+                    .lock()
+                    .unwrap()
+                    .commits
+                    .iter()
+                    .rev()
+                    .find(|commit| commit.bucket == bucket_name && commit.committed_at <= at)
+                    .cloned())
+            }
+        };
+        let commits: Vec<RootCommit> = database_connection
+            .fluent()
+            .select()
+            .from(ROOT_COMMITS_COLLECTION)
+            .filter(|q| {
+                q.for_all([
+                    q.field("bucket").eq(bucket_name),
+                    q.field("committed_at").less_than_or_equal(FirestoreTimestamp(at)),
+                ])
+            })
+            .order_by([("committed_at", FirestoreQueryDirection::Descending)])
+            .limit(1)
+            .obj()
+            .query()
+            .await
+            .map_err(|e| {
+                s3_error!(
+                    InternalError,
+                    "Error looking up root commit in registry: {}",
+                    e
+                )
+            })?;
+        Ok(commits.into_iter().next())
+    }
+
+    /// points a bucket at a new root, and records the commit under `actor`, but only if the bucket's root is still
+    /// `previous`, or for None, there's no bucket yet. both happen in one transaction, or neither does.
+    /// returns false if the bucket was changed from under us, in which case the change should be redone on top of
+    /// whatever's there now.
+    pub async fn set_root(
+        &self,
+        bucket_name: &str,
+        previous: Option<&BucketRoot>,
+        root: &BucketRoot,
+        actor: &str,
+    ) -> S3Result<bool> {
+        let commit = RootCommit {
+            bucket: bucket_name.to_string(),
+            root: root.clone(),
+            committed_at: Utc::now(),
+            actor: actor.to_string(),
+        };
+        let database_connection = match &self.backend {
+            RegistryBackend::Firestore(database_connection) => database_connection,
+            RegistryBackend::Memory(memory) => {
+                let mut memory = memory.lock().unwrap();
+                if memory.roots.get(bucket_name) != previous {
+                    return Ok(false);
+                }
+                memory.roots.insert(bucket_name.to_string(), root.clone());
+                memory.commits.push(commit);
+                return Ok(true);
+            }
+        };
+        let current = database_connection
+            .fluent()
+            .select()
+            .by_id_in(BUCKET_ROOTS_COLLECTION)
+            .one(bucket_name)
+            .await
+            .map_err(|e| {
+                s3_error!(
+                    InternalError,
+                    "Error looking up bucket root in registry: {}",
+                    e
+                )
+            })?;
+        // the root alone can't tell an A -> B -> A from no change at all, so the precondition is on the update time
+        let precondition = match (current, previous) {
+            (None, None) => FirestoreWritePrecondition::Exists(false),
+            (Some(doc), Some(previous)) => {
+                let current_root: BucketRoot = FirestoreDb::deserialize_doc_to(&doc).map_err(|e| {
+                    s3_error!(
+                        InternalError,
+                        "Error reading bucket root from registry: {}",
+                        e
+                    )
+                })?;
+                let Some(update_time) = doc.update_time.filter(|_| &current_root == previous) else {
+                    return Ok(false);
+                };
+                FirestoreWritePrecondition::UpdateTime(from_timestamp(update_time).map_err(|e| {
+                    s3_error!(
+                        InternalError,
+                        "Error reading bucket root from registry: {}",
+                        e
+                    )
+                })?)
+            }
+            _ => return Ok(false),
+        };
+        let write_err = |e| {
+            s3_error!(
+                InternalError,
+                "Error writing bucket root to registry: {}",
+                e
+            )
+        };
+        let mut transaction = database_connection
+            .begin_transaction()
+            .await
+            .map_err(write_err)?;
+        database_connection
+            .fluent()
+            .update()
+            .in_col(ROOT_COMMITS_COLLECTION)
+            .document_id(uuid::Uuid::new_v4().to_string())
+            .object(&commit)
+            .add_to_transaction(&mut transaction)
+            .map_err(write_err)?;
+        database_connection
+            .fluent()
+            .update()
+            .in_col(BUCKET_ROOTS_COLLECTION)
+            .precondition(precondition)
+            .document_id(bucket_name)
+            .object(root)
+            .add_to_transaction(&mut transaction)
+            .map_err(write_err)?;
+        match transaction.commit().await {
+            Ok(_) => Ok(true),
+            Err(e) if is_conflict(&e) => {
+                log::info!("bucket registry: {} was changed from under us, {}", bucket_name, e);
+                Ok(false)
+            }
+            Err(e) => Err(write_err(e)),
+        }
+    }
+
+    /// returns a snapshot of a bucket, or None if there's no such snapshot
+    pub async fn get_snapshot(&self, bucket_name: &str, snapshot_name: &str) -> S3Result<Option<Snapshot>> {
+        let database_connection = match &self.backend {
+            RegistryBackend::Firestore(database_connection) => database_connection,
+            RegistryBackend::Memory(memory) => {
+                return Ok(memory
+                    .lock()
+                    .unwrap()
+                    .snapshots
+                    .get(&snapshot_id(bucket_name, snapshot_name))
+                    .cloned())
+            }
+        };
+        database_connection
+            .fluent()
+            .select()
+            .by_id_in(BUCKET_SNAPSHOTS_COLLECTION)
+            .obj()
+            .one(&snapshot_id(bucket_name, snapshot_name))
+            .await
+            .map_err(|e| {
+                s3_error!(
+                    InternalError,
+                    "Error looking up snapshot in registry: {}",
+                    e
+                )
+            })
+    }
+
+    /// returns the snapshots of one bucket, or of every bucket, oldest first
+    pub async fn list_snapshots(&self, bucket_name: Option<&str>) -> S3Result<Vec<Snapshot>> {
+        let database_connection = match &self.backend {
+            RegistryBackend::Firestore(database_connection) => database_connection,
+            RegistryBackend::Memory(memory) => {
+                let mut snapshots: Vec<Snapshot> = memory
+                    .lock()
+                    .unwrap()
+                    .snapshots
+                    .values()
+                    .filter(|snapshot| bucket_name.is_none_or(|bucket_name| snapshot.bucket == bucket_name))
+                    .cloned()
+                    .collect();
+                snapshots.sort_by_key(|snapshot| snapshot.created_at);
+                return Ok(snapshots);
+            }
+        };
+        let select = database_connection
+            .fluent()
+            .select()
+            .from(BUCKET_SNAPSHOTS_COLLECTION);
+        let select = match bucket_name {
+            Some(bucket_name) => select.filter(|q| q.for_all([q.field("bucket").eq(bucket_name)])),
+            None => select,
+        };
+        let mut snapshots: Vec<Snapshot> = select.obj().query().await.map_err(|e| {
+            s3_error!(
+                InternalError,
+                "Error listing snapshots in registry: {}",
+                e
+            )
+        })?;
+        snapshots.sort_by_key(|snapshot| snapshot.created_at);
+        Ok(snapshots)
+    }
+
+    /// pins a snapshot. names are never reused while the snapshot they name is around.
+    pub async fn create_snapshot(&self, snapshot: &Snapshot) -> S3Result<()> {
+        if self.get_snapshot(&snapshot.bucket, &snapshot.name).await?.is_some() {
+            return Err(s3_error!(
+                InvalidArgument,
+                "Bucket {} already has a snapshot called {}",
+                snapshot.bucket,
+                snapshot.name
+            ));
+        }
+        let database_connection = match &self.backend {
+            RegistryBackend::Firestore(database_connection) => database_connection,
+            RegistryBackend::Memory(memory) => {
+                memory
+                    .lock()
+                    .unwrap()
+                    .snapshots
+                    .insert(snapshot_id(&snapshot.bucket, &snapshot.name), snapshot.clone());
+                return Ok(());
+            }
+        };
+        let _: Snapshot = database_connection
+            .fluent()
+            .insert()
+            .into(BUCKET_SNAPSHOTS_COLLECTION)
+            .document_id(snapshot_id(&snapshot.bucket, &snapshot.name))
+            .object(snapshot)
+            .execute()
+            .await
+            .map_err(|e| {
+                s3_error!(
+                    InternalError,
+                    "Error writing snapshot to registry: {}",
+                    e
+                )
+            })?;
+        Ok(())
+    }
+
+    /// unpins a snapshot, leaving its blocks to gc unless something else still needs them.
+    /// returns whether there was one to delete.
+    pub async fn delete_snapshot(&self, bucket_name: &str, snapshot_name: &str) -> S3Result<bool> {
+        if self.get_snapshot(bucket_name, snapshot_name).await?.is_none() {
+            return Ok(false);
+        }
+        let database_connection = match &self.backend {
+            RegistryBackend::Firestore(database_connection) => database_connection,
+            RegistryBackend::Memory(memory) => {
+                memory
+                    .lock()
+                    .unwrap()
+                    .snapshots
+                    .remove(&snapshot_id(bucket_name, snapshot_name));
+                return Ok(true);
+            }
+        };
+        database_connection
+            .fluent()
+            .delete()
+            .from(BUCKET_SNAPSHOTS_COLLECTION)
+            .document_id(snapshot_id(bucket_name, snapshot_name))
+            .execute()
+            .await
+            .map_err(|e| {
+                s3_error!(
+                    InternalError,
+                    "Error deleting snapshot from registry: {}",
+                    e
+                )
+            })?;
+        Ok(true)
+    }
+
+    /// returns the lifecycle configuration of a bucket, or None if it doesn't have one
+    pub async fn get_lifecycle(&self, bucket_name: &str) -> S3Result<Option<BucketLifecycle>> {
+        let database_connection = match &self.backend {
+            RegistryBackend::Firestore(database_connection) => database_connection,
+            RegistryBackend::Memory(memory) => {
+                return Ok(memory.lock().unwrap().lifecycles.get(bucket_name).cloned())
+            }
+        };
+        database_connection
+            .fluent()
+            .select()
+            .by_id_in(BUCKET_LIFECYCLES_COLLECTION)
+            .obj()
+            .one(bucket_name)
+            .await
+            .map_err(|e| {
+                s3_error!(
+                    InternalError,
+                    "Error looking up bucket lifecycle in registry: {}",
+                    e
+                )
+            })
+    }
+
+    /// replaces the lifecycle configuration of a bucket
+    pub async fn set_lifecycle(&self, bucket_name: &str, lifecycle: &BucketLifecycle) -> S3Result<()> {
+        let database_connection = match &self.backend {
+            RegistryBackend::Firestore(database_connection) => database_connection,
+            RegistryBackend::Memory(memory) => {
+                memory
+                    .lock()
+                    .unwrap()
+                    .lifecycles
+                    .insert(bucket_name.to_string(), lifecycle.clone());
+                return Ok(());
+            }
+        };
+        let _: BucketLifecycle = database_connection
+            .fluent()
+            .update()
+            .in_col(BUCKET_LIFECYCLES_COLLECTION)
+            .document_id(bucket_name)
+            .object(lifecycle)
+            .execute()
+            .await
+            .map_err(|e| {
+                s3_error!(
+                    InternalError,
+                    "Error writing bucket lifecycle to registry: {}",
+                    e
+                )
+            })?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use s3s::{
+        dto::{CopyObjectInput, CopyObjectOutput, CopySource, GetObjectInput, GetObjectOutput},
+        service::S3ServiceBuilder,
+        S3Request, S3,
+    };
+
+    use super::*;
+
+    /// an S3 that only remembers which buckets its handlers were asked about
+    struct BucketRecorder(Arc<Mutex<Vec<String>>>);
+
+    #[async_trait::async_trait]
+    impl S3 for BucketRecorder {
+        async fn get_object(&self, req: S3Request<GetObjectInput>) -> S3Result<GetObjectOutput> {
+            self.0.lock().unwrap().push(req.input.bucket);
+            Err(s3_error!(NoSuchKey))
+        }
+
+        async fn copy_object(&self, req: S3Request<CopyObjectInput>) -> S3Result<CopyObjectOutput> {
+            if let CopySource::Bucket { bucket, .. } = req.input.copy_source {
+                self.0.lock().unwrap().push(bucket.to_string());
+            }
+            Err(s3_error!(NoSuchKey))
+        }
+    }
+
+    #[tokio::test]
+    async fn snapshots_get_through_s3_routing() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let service = S3ServiceBuilder::new(BucketRecorder(seen.clone())).build();
+
+        let snapshot = snapshot_id("photos", "before-cleanup");
+        let get = hyper::Request::get(format!("http://localhost/{}/cat.jpg", snapshot))
+            .body(s3s::Body::empty())
+            .unwrap();
+        let _ = service.call(get).await;
+        let copy = hyper::Request::put("http://localhost/photos/cat-copy.jpg")
+            .header("x-amz-copy-source", format!("/{}/cat.jpg", snapshot))
+            .body(s3s::Body::empty())
+            .unwrap();
+        let _ = service.call(copy).await;
+
+        let seen = seen.lock().unwrap().clone();
+        assert_eq!(seen, vec![snapshot.clone(), snapshot.clone()]);
+        assert!(is_snapshot(&seen[0]));
+        assert_eq!(snapshotted_bucket(&seen[0]), "photos");
+        assert!(!is_snapshot("photos"));
+    }
+
+    fn public_root(cid: &str) -> BucketRoot {
+        BucketRoot::Public {
+            public_root_cid: cid.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn set_root_only_moves_from_the_root_it_was_given() {
+        let registry = BucketRegistry::in_memory();
+        assert!(registry.set_root("photos", None, &public_root("a"), "test").await.unwrap());
+        // the bucket exists now, so it can't be created again
+        assert!(!registry.set_root("photos", None, &public_root("b"), "test").await.unwrap());
+        // nor moved from a root it doesn't have
+        assert!(!registry
+            .set_root("photos", Some(&public_root("b")), &public_root("c"), "test")
+            .await
+            .unwrap());
+        assert!(registry
+            .set_root("photos", Some(&public_root("a")), &public_root("b"), "test")
+            .await
+            .unwrap());
+        assert_eq!(registry.get_root("photos").await.unwrap(), Some(public_root("b")));
+    }
+
+    #[tokio::test]
+    async fn history_is_every_commit_of_the_bucket_oldest_first() {
+        let registry = BucketRegistry::in_memory();
+        registry.set_root("photos", None, &public_root("a"), "alice").await.unwrap();
+        registry.set_root("videos", None, &public_root("x"), "alice").await.unwrap();
+        registry
+            .set_root("photos", Some(&public_root("a")), &public_root("b"), "bob")
+            .await
+            .unwrap();
+        // a lost race leaves no commit behind
+        registry
+            .set_root("photos", Some(&public_root("a")), &public_root("c"), "carol")
+            .await
+            .unwrap();
+
+        let history = registry.list_bucket_commits("photos").await.unwrap();
+        let history: Vec<_> = history
+            .iter()
+            .map(|commit| (commit.root.root_cid(), commit.actor.as_str()))
+            .collect();
+        assert_eq!(history, [("a", "alice"), ("b", "bob")]);
+    }
+
+    #[tokio::test]
+    async fn commit_at_is_the_last_commit_made_by_then() {
+        let registry = BucketRegistry::in_memory();
+        let before = Utc::now();
+        registry.set_root("photos", None, &public_root("a"), "test").await.unwrap();
+        let after_a = Utc::now();
+        // so the next commit can't have the same time
+        tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+        registry
+            .set_root("photos", Some(&public_root("a")), &public_root("b"), "test")
+            .await
+            .unwrap();
+        let after_b = Utc::now();
+
+        assert!(registry.commit_at("photos", before - chrono::Duration::seconds(1)).await.unwrap().is_none());
+        for (at, root) in [(after_a, "a"), (after_b, "b")] {
+            let commit = registry.commit_at("photos", at).await.unwrap().unwrap();
+            assert_eq!(commit.root, public_root(root));
+        }
+        assert!(registry.commit_at("videos", after_b).await.unwrap().is_none());
+    }
+}
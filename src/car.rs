@@ -0,0 +1,864 @@
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashSet},
+    io::{Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+    rc::Rc,
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use chrono::Utc;
+use libipld::{cbor::DagCborCodec, codec::Codec, Cid, Ipld, IpldCodec};
+use wnfs::{
+    private::{PrivateDirectory, PrivateForest, PrivateNode},
+    public::{PublicDirectory, PublicNode},
+    BlockStore, Namefilter,
+};
+
+use crate::{
+    blockstore_gc::{block_links, walk_start},
+    blockstores::{AnyBlockStore, BlockError},
+    bucket_registry::{BucketRegistry, BucketRoot},
+    fs_blockstore::{verify_block, FsBlockStore},
+    wnfs_buckets::{
+        file_content, key_to_path, open_bucket, open_public, run_wnfs_anyhow, store_public_root,
+        store_root, write_plain_file, file_etag, COMPOSITE_DIR,
+    },
+};
+
+/// what every CARv2 starts with: a CARv1 header saying it's version 2
+const CARV2_PRAGMA: [u8; 11] = [
+    0x0a, 0xa1, 0x67, 0x76, 0x65, 0x72, 0x73, 0x69, 0x6f, 0x6e, 0x02,
+];
+/// characteristics, then the offsets and size of the data and the index
+const CARV2_HEADER_SIZE: usize = 40;
+/// the biggest section we'll read: a block well over any size we write, plus its cid.
+/// the length comes from the CAR, so without a limit a bad one could have us allocate anything.
+const MAX_SECTION_SIZE: u64 = 4 * 1024 * 1024 + 128;
+
+/// which version of the CAR format to write
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum CarVersion {
+    /// a header and the blocks. what most tools expect.
+    V1,
+    /// a CARv1 wrapped in a header saying where it is. we don't write an index.
+    V2,
+}
+
+/// writes blocks out as a CAR as they come, so nothing but the current block is ever in memory.
+/// a CARv2's header needs the size of the data, so it's filled in at the end, which is why the output has to be seekable.
+pub struct CarWriter<W: Write + Seek> {
+    out: W,
+    version: CarVersion,
+    data_size: u64,
+}
+
+impl<W: Write + Seek> CarWriter<W> {
+    pub fn new(mut out: W, version: CarVersion, roots: &[Cid]) -> Result<Self> {
+        if version == CarVersion::V2 {
+            out.write_all(&CARV2_PRAGMA)?;
+            out.write_all(&[0; CARV2_HEADER_SIZE])?;
+        }
+        let header = DagCborCodec.encode(&Ipld::Map(BTreeMap::from([
+            (
+                "roots".to_string(),
+                Ipld::List(roots.iter().map(|root| Ipld::Link(*root)).collect()),
+            ),
+            ("version".to_string(), Ipld::Integer(1)),
+        ])))?;
+        let mut writer = Self {
+            out,
+            version,
+            data_size: 0,
+        };
+        writer.write_section(&[&header])?;
+        Ok(writer)
+    }
+
+    pub fn write_block(&mut self, cid: &Cid, bytes: &[u8]) -> Result<()> {
+        self.write_section(&[&cid.to_bytes(), bytes])
+    }
+
+    /// everything in a CARv1 is a varint length followed by that many bytes
+    fn write_section(&mut self, parts: &[&[u8]]) -> Result<()> {
+        let mut length = parts.iter().map(|part| part.len() as u64).sum::<u64>();
+        let mut varint = Vec::with_capacity(10);
+        loop {
+            let byte = (length & 0x7f) as u8;
+            length >>= 7;
+            if length == 0 {
+                varint.push(byte);
+                break;
+            }
+            varint.push(byte | 0x80);
+        }
+        self.out.write_all(&varint)?;
+        self.data_size += varint.len() as u64;
+        for part in parts {
+            self.out.write_all(part)?;
+            self.data_size += part.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// fills in the CARv2 header, if there is one, and flushes everything out
+    pub fn finish(mut self) -> Result<W> {
+        if self.version == CarVersion::V2 {
+            let data_offset = (CARV2_PRAGMA.len() + CARV2_HEADER_SIZE) as u64;
+            self.out.seek(SeekFrom::Start(CARV2_PRAGMA.len() as u64))?;
+            // no characteristics: we don't say anything about the order of the blocks
+            self.out.write_all(&[0; 16])?;
+            self.out.write_all(&data_offset.to_le_bytes())?;
+            self.out.write_all(&self.data_size.to_le_bytes())?;
+            // no index
+            self.out.write_all(&0u64.to_le_bytes())?;
+            self.out.seek(SeekFrom::End(0))?;
+        }
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+/// reads blocks out of a CARv1 or CARv2 one at a time, so nothing but the current block is ever in memory
+pub struct CarReader<R: Read + Seek> {
+    input: R,
+    roots: Vec<Cid>,
+    /// how many bytes of blocks are left to read, if the CAR says. CARv2s can have an index after the blocks.
+    remaining: Option<u64>,
+}
+
+impl<R: Read + Seek> CarReader<R> {
+    pub fn new(mut input: R) -> Result<Self> {
+        let mut header = read_section(&mut input)?
+            .ok_or_else(|| anyhow!("the CAR is empty"))?;
+        let mut remaining = None;
+        if header == CARV2_PRAGMA[1..] {
+            let mut v2_header = [0; CARV2_HEADER_SIZE];
+            input.read_exact(&mut v2_header)?;
+            let data_offset = u64::from_le_bytes(v2_header[16..24].try_into()?);
+            let data_size = u64::from_le_bytes(v2_header[24..32].try_into()?);
+            input.seek(SeekFrom::Start(data_offset))?;
+            header = read_section(&mut input)?
+                .ok_or_else(|| anyhow!("the CARv2 has no data in it"))?;
+            remaining = Some(
+                data_size
+                    .checked_sub(varint_len(header.len() as u64) + header.len() as u64)
+                    .ok_or_else(|| anyhow!("the CARv2's header is bigger than its data"))?,
+            );
+        }
+        let Ipld::Map(header) = DagCborCodec.decode(&header)? else {
+            return Err(anyhow!("the CAR's header isn't a map"));
+        };
+        if header.get("version") != Some(&Ipld::Integer(1)) {
+            return Err(anyhow!("unsupported CAR version: {:?}", header.get("version")));
+        }
+        let roots = match header.get("roots") {
+            Some(Ipld::List(roots)) => roots
+                .iter()
+                .map(|root| match root {
+                    Ipld::Link(cid) => Ok(*cid),
+                    _ => Err(anyhow!("the CAR has a root that isn't a cid")),
+                })
+                .collect::<Result<_>>()?,
+            _ => return Err(anyhow!("the CAR has no roots")),
+        };
+        Ok(Self {
+            input,
+            roots,
+            remaining,
+        })
+    }
+
+    pub fn roots(&self) -> &[Cid] {
+        &self.roots
+    }
+
+    /// the next block, unchecked, or None at the end
+    pub fn next_block(&mut self) -> Result<Option<(Cid, Vec<u8>)>> {
+        if self.remaining == Some(0) {
+            return Ok(None);
+        }
+        let Some(section) = read_section(&mut self.input)? else {
+            return Ok(None);
+        };
+        if let Some(remaining) = &mut self.remaining {
+            *remaining = remaining
+                .checked_sub(varint_len(section.len() as u64) + section.len() as u64)
+                .ok_or_else(|| anyhow!("the CAR's blocks run past the end of its data"))?;
+        }
+        let mut cursor = std::io::Cursor::new(section);
+        let cid = Cid::read_bytes(&mut cursor)?;
+        let start = cursor.position() as usize;
+        let mut section = cursor.into_inner();
+        Ok(Some((cid, section.split_off(start))))
+    }
+}
+
+fn varint_len(mut n: u64) -> u64 {
+    let mut len = 1;
+    while n >= 0x80 {
+        n >>= 7;
+        len += 1;
+    }
+    len
+}
+
+/// reads a varint length and that many bytes, or None if the input ends before the length does
+fn read_section(input: &mut impl Read) -> Result<Option<Vec<u8>>> {
+    let mut length = 0u64;
+    let mut shift = 0;
+    loop {
+        let mut byte = [0];
+        if input.read(&mut byte)? == 0 {
+            if shift == 0 {
+                return Ok(None);
+            }
+            return Err(anyhow!("the CAR ends in the middle of a length"));
+        }
+        if shift > 63 {
+            return Err(anyhow!("the CAR has a length that's too long"));
+        }
+        length |= ((byte[0] & 0x7f) as u64) << shift;
+        shift += 7;
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+    }
+    if length > MAX_SECTION_SIZE {
+        return Err(anyhow!(
+            "the CAR has a section of {} bytes, more than the {} we'll read",
+            length,
+            MAX_SECTION_SIZE
+        ));
+    }
+    let mut section = vec![0; length as usize];
+    input.read_exact(&mut section)?;
+    Ok(Some(section))
+}
+
+/// what an export wrote
+#[derive(Debug)]
+pub struct ExportReport {
+    /// the root of what was exported. for a private bucket, its private ref is the key to everything in the CAR, so look after it!
+    pub root: BucketRoot,
+    pub blocks: usize,
+    pub bytes: u64,
+}
+
+impl std::fmt::Display for ExportReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "exported {} blocks ({} bytes) from root {}",
+            self.blocks,
+            self.bytes,
+            self.root.root_cid()
+        )
+    }
+}
+
+/// copies the objects under `from` in one tree to under `to` in another, re-encrypting them with the target's keys.
+/// composite objects' segment lists are copied along with the plain files; their segments are shared, not copied.
+/// returns the target's new forest and root directory, and how many objects were copied.
+async fn copy_objects(
+    source: &BucketRoot,
+    from: &[String],
+    mut target_forest: Rc<PrivateForest>,
+    mut target_dir: Rc<PrivateDirectory>,
+    to: &[String],
+    store: &mut impl BlockStore,
+) -> Result<(Rc<PrivateForest>, Rc<PrivateDirectory>, usize)> {
+    let rng = &mut rand::thread_rng();
+    let (forest, root_dir) = open_bucket(source, store).await?;
+    let mut copied = 0;
+
+    // the plain files, then the segment lists of the composite objects, which live under a directory of their own
+    let in_composites = |path: &[String]| {
+        let mut composite_path = vec![COMPOSITE_DIR.to_string()];
+        composite_path.extend(path.iter().cloned());
+        composite_path
+    };
+    let mut dirs = vec![
+        (from.to_vec(), to.to_vec(), false, true),
+        (in_composites(from), in_composites(to), true, true),
+    ];
+    while let Some((from, to, composite, top)) = dirs.pop() {
+        let entries = match root_dir.clone().ls(&from, true, forest.clone(), store).await {
+            Ok(op) => op.result,
+            // there might not be any plain files or composite objects to copy
+            Err(_) if top => continue,
+            Err(e) => return Err(e),
+        };
+        for (name, _) in entries {
+            // copying the whole tree: the composite directory gets its own pass
+            if !composite && from.is_empty() && name == COMPOSITE_DIR {
+                continue;
+            }
+            let (mut from, mut to) = (from.clone(), to.clone());
+            from.push(name.clone());
+            to.push(name);
+            let node = root_dir
+                .clone()
+                .get_node(&from, true, forest.clone(), store)
+                .await?
+                .result;
+            let file = match node {
+                Some(PrivateNode::File(file)) => file,
+                Some(PrivateNode::Dir(_)) => {
+                    dirs.push((from, to, composite, false));
+                    continue;
+                }
+                None => continue,
+            };
+            let content = file_content(&file, &forest, store).await?;
+            // whatever was at the key before would shadow, or be shadowed by, what we're copying
+            let shadowing = if composite {
+                to[1..].to_vec()
+            } else {
+                in_composites(&to)
+            };
+            if let Ok(op) = target_dir
+                .clone()
+                .rm(&shadowing, true, target_forest.clone(), store, rng)
+                .await
+            {
+                target_forest = op.forest;
+                target_dir = op.root_dir;
+            }
+            (target_forest, target_dir) = if composite {
+                let op = target_dir
+                    .write(&to, true, Utc::now(), content, target_forest, store, rng)
+                    .await?;
+                (op.forest, op.root_dir)
+            } else {
+                let etag = file_etag(&file)?;
+                write_plain_file(target_dir, &to, content, &etag, target_forest, store, rng).await?
+            };
+            copied += 1;
+        }
+    }
+    Ok((target_forest, target_dir, copied))
+}
+
+/// where an export writes the trees it makes: blocks put in it go into `scratch`, and blocks it doesn't have are read from `primary`.
+/// an export only reads a bucket, so nothing it makes along the way should end up in the primary store.
+struct ScratchStore {
+    scratch: FsBlockStore,
+    primary: AnyBlockStore,
+}
+
+#[async_trait(?Send)]
+impl BlockStore for ScratchStore {
+    async fn put_block(&mut self, bytes: Vec<u8>, codec: IpldCodec) -> Result<Cid> {
+        self.scratch.put_block(bytes, codec).await
+    }
+
+    async fn get_block<'a>(&'a self, cid: &Cid) -> Result<Cow<'a, Vec<u8>>> {
+        match self.scratch.get_block(cid).await {
+            Err(e) if matches!(e.downcast_ref(), Some(BlockError::NotFound(_))) => {
+                self.primary.get_block(cid).await
+            }
+            result => result,
+        }
+    }
+}
+
+/// copies the objects under `prefix` into a tree of their own, with the prefix taken off their keys,
+/// so they can be exported without the rest of the bucket. everything is encrypted afresh, so the copy has its own keys.
+async fn copy_subtree(
+    root: &BucketRoot,
+    prefix: &[String],
+    store: &mut ScratchStore,
+) -> Result<BucketRoot> {
+    let rng = &mut rand::thread_rng();
+    let (forest, root_dir, copied) = copy_objects(
+        root,
+        prefix,
+        Rc::new(PrivateForest::new()),
+        Rc::new(PrivateDirectory::new(Namefilter::default(), Utc::now(), rng)),
+        &[],
+        store,
+    )
+    .await?;
+    if copied == 0 {
+        return Err(anyhow!("there's nothing under {}", prefix.join("/")));
+    }
+    store_root(&forest, &root_dir, store).await
+}
+
+/// copies the files under `from` in one public tree to under `to` in another. files are only linked in, not copied,
+/// since their content is addressed by cid and the same either side. returns the target's new root directory and how many were copied.
+async fn copy_public_objects(
+    source: &Rc<PublicDirectory>,
+    from: &[String],
+    mut target: Rc<PublicDirectory>,
+    to: &[String],
+    store: &mut AnyBlockStore,
+) -> Result<(Rc<PublicDirectory>, usize)> {
+    let mut copied = 0;
+    let mut dirs = vec![(from.to_vec(), to.to_vec())];
+    while let Some((from, to)) = dirs.pop() {
+        for (name, _) in source.clone().ls(&from, store).await?.result {
+            let (mut from, mut to) = (from.clone(), to.clone());
+            from.push(name.clone());
+            to.push(name);
+            match source.clone().get_node(&from, store).await?.result {
+                Some(PublicNode::File(file)) => {
+                    target = target
+                        .write(&to, *file.get_content_cid(), Utc::now(), store)
+                        .await?
+                        .root_dir;
+                    copied += 1;
+                }
+                Some(PublicNode::Dir(_)) => dirs.push((from, to)),
+                None => {}
+            }
+        }
+    }
+    Ok((target, copied))
+}
+
+/// the root of a public bucket's subdirectory at `prefix`, which is a public tree in its own right
+async fn public_subtree(
+    public_root_cid: &str,
+    prefix: &[String],
+    store: &mut ScratchStore,
+) -> Result<BucketRoot> {
+    let root_dir = open_public(public_root_cid, store).await?;
+    match root_dir.get_node(prefix, store).await?.result {
+        Some(PublicNode::Dir(dir)) => store_public_root(&dir, store).await,
+        _ => Err(anyhow!("there's nothing under {}", prefix.join("/"))),
+    }
+}
+
+/// reads every block reachable from `root` out of the store, once each, and hands it to `visit`.
+/// a block that can't be read stops the walk. returns how many blocks were visited.
+async fn walk_blocks(
+    root: &BucketRoot,
+    store: &impl BlockStore,
+    mut visit: impl FnMut(&Cid, &[u8]) -> Result<()>,
+) -> Result<usize> {
+    let mut stack = walk_start(root, store).await?;
+    let mut visited = HashSet::new();
+    while let Some(cid) = stack.pop() {
+        if !visited.insert(cid) {
+            continue;
+        }
+        let bytes = store.get_block(&cid).await?;
+        visit(&cid, &bytes)?;
+        block_links(&cid, &bytes, &mut stack)?;
+    }
+    Ok(visited.len())
+}
+
+/// writes every block reachable from a bucket's root, or just from the objects under `prefix`, to a CAR at `output`.
+/// the CAR's root is the forest, or a public bucket's root directory.
+/// to read anything out of a private bucket's CAR you also need the private ref in the report.
+/// blocks are written as they're found, so exports of any size only need memory for the cids already written.
+pub async fn export_bucket(
+    store: AnyBlockStore,
+    registry: &BucketRegistry,
+    bucket: &str,
+    prefix: Option<String>,
+    output: PathBuf,
+    version: CarVersion,
+) -> Result<ExportReport> {
+    let root = registry
+        .get_root(bucket)
+        .await
+        .map_err(|e| anyhow!("couldn't look up bucket {}: {:?}", bucket, e))?
+        .ok_or_else(|| anyhow!("there's no bucket called {}", bucket))?;
+    export_root(store, root, prefix, output, version).await
+}
+
+/// writes the blocks under a root, or under `prefix` in it, to a CAR at `output`. what export_bucket does once it has the root.
+async fn export_root(
+    store: AnyBlockStore,
+    root: BucketRoot,
+    prefix: Option<String>,
+    output: PathBuf,
+    version: CarVersion,
+) -> Result<ExportReport> {
+    // beside the CAR, since it could be as big
+    let scratch_dir = output.with_extension(format!("scratch-{}", uuid::Uuid::new_v4()));
+    run_wnfs_anyhow(move || async move {
+        let mut store = ScratchStore {
+            scratch: FsBlockStore::new(scratch_dir.clone()).await?,
+            primary: store,
+        };
+        let report = write_car(&mut store, root, prefix, output, version).await;
+        if let Err(e) = tokio::fs::remove_dir_all(&scratch_dir).await {
+            log::warn!("car: couldn't remove the export's scratch directory {:?}: {}", scratch_dir, e);
+        }
+        report
+    })
+    .await
+}
+
+/// what export_root does on its blocking thread
+async fn write_car(
+    store: &mut ScratchStore,
+    root: BucketRoot,
+    prefix: Option<String>,
+    output: PathBuf,
+    version: CarVersion,
+) -> Result<ExportReport> {
+    let root = match prefix.map(|prefix| key_to_path(prefix.trim_matches('/'))) {
+        Some(prefix) if prefix != [""] => match &root {
+            BucketRoot::Private { .. } => copy_subtree(&root, &prefix, store).await?,
+            BucketRoot::Public { public_root_cid } => {
+                public_subtree(public_root_cid, &prefix, store).await?
+            }
+        },
+        _ => root,
+    };
+    let root_cid = Cid::from_str(root.root_cid())?;
+    let file = std::io::BufWriter::new(std::fs::File::create(&output)?);
+    let mut car = CarWriter::new(file, version, &[root_cid])?;
+    let mut bytes_written = 0;
+    let blocks = walk_blocks(&root, store, |cid, bytes| {
+        bytes_written += bytes.len() as u64;
+        car.write_block(cid, bytes)
+    })
+    .await?;
+    car.finish()?;
+
+    Ok(ExportReport {
+        root,
+        blocks,
+        bytes: bytes_written,
+    })
+}
+
+/// what an import did
+#[derive(Debug)]
+pub struct ImportReport {
+    pub blocks: usize,
+    pub bytes: u64,
+    /// how many objects were grafted into an existing bucket. registering the CAR as a new bucket doesn't copy anything.
+    pub grafted: usize,
+}
+
+impl std::fmt::Display for ImportReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "imported {} blocks ({} bytes), grafted {} objects",
+            self.blocks, self.bytes, self.grafted
+        )
+    }
+}
+
+/// loads every block in the CAR at `input` into the store, checking each one against its cid.
+/// `root` has to be one of the CAR's roots.
+async fn load_car(input: &std::path::Path, root: &BucketRoot, store: &mut AnyBlockStore) -> Result<ImportReport> {
+    let mut car = CarReader::new(std::io::BufReader::new(std::fs::File::open(input)?))?;
+    if !car.roots().iter().any(|cid| cid.to_string() == root.root_cid()) {
+        return Err(anyhow!(
+            "the CAR's roots ({:?}) don't include {}",
+            car.roots(),
+            root.root_cid()
+        ));
+    }
+    let mut report = ImportReport {
+        blocks: 0,
+        bytes: 0,
+        grafted: 0,
+    };
+    while let Some((cid, bytes)) = car.next_block()? {
+        verify_block(&cid, &bytes)?;
+        let codec = IpldCodec::try_from(cid.codec())?;
+        report.bytes += bytes.len() as u64;
+        let stored = store.put_block(bytes, codec).await?;
+        // the store names blocks its own way, which has to be the CAR's way too, or nothing will find them
+        if stored != cid {
+            return Err(anyhow!("block {} was stored as {}", cid, stored));
+        }
+        report.blocks += 1;
+    }
+    Ok(report)
+}
+
+/// loads every block in the CAR at `input` into the store, checking each one against its cid, then makes `bucket` out of it.
+/// everything reachable from `root` has to be in the CAR or already in the store, or nothing is pointed at it.
+///
+/// without a prefix, `root` (the forest in the CAR and the private ref of its root directory, or a public root directory)
+/// becomes a new bucket as it is. with one, the objects in it are copied into the existing bucket under the prefix,
+/// re-encrypted with the bucket's keys if it's private. a CAR can only be grafted into a bucket of the same kind.
+/// a graft that races another write to the same bucket fails, rather than losing either, and can just be run again.
+pub async fn import_car(
+    store: AnyBlockStore,
+    registry: &BucketRegistry,
+    input: PathBuf,
+    root: BucketRoot,
+    bucket: &str,
+    prefix: Option<String>,
+    actor: &str,
+) -> Result<ImportReport> {
+    let existing = registry
+        .get_root(bucket)
+        .await
+        .map_err(|e| anyhow!("couldn't look up bucket {}: {:?}", bucket, e))?;
+    let target = match (prefix, existing) {
+        (None, None) => None,
+        (None, Some(_)) => {
+            return Err(anyhow!(
+                "there's already a bucket called {}. give a prefix to graft into it",
+                bucket
+            ))
+        }
+        (Some(_), Some(existing)) if existing.is_public() != root.is_public() => {
+            return Err(anyhow!(
+                "can't graft a {} tree into {}, which is {}",
+                if root.is_public() { "public" } else { "private" },
+                bucket,
+                if existing.is_public() { "public" } else { "private" }
+            ))
+        }
+        (Some(prefix), Some(existing)) => Some((key_to_path(prefix.trim_matches('/')), existing)),
+        (Some(_), None) => return Err(anyhow!("there's no bucket called {} to graft into", bucket)),
+    };
+    let previous = target.as_ref().map(|(_, existing)| existing.clone());
+
+    let (new_root, report) = run_wnfs_anyhow(move || async move {
+        let mut store = store;
+        let mut report = load_car(&input, &root, &mut store).await?;
+        walk_blocks(&root, &store, |_, _| Ok(()))
+            .await
+            .map_err(|e| anyhow!("the CAR doesn't hold everything under its root: {}", e))?;
+        // make sure the bucket can actually be opened before pointing anything at it
+        let source = match &root {
+            BucketRoot::Private { .. } => {
+                open_bucket(&root, &store).await?;
+                None
+            }
+            BucketRoot::Public { public_root_cid } => Some(open_public(public_root_cid, &store).await?),
+        };
+
+        let Some((prefix, existing)) = target else {
+            return Ok((root, report));
+        };
+        if let (Some(source), BucketRoot::Public { public_root_cid }) = (source, &existing) {
+            let target_dir = open_public(public_root_cid, &store).await?;
+            let (target_dir, grafted) =
+                copy_public_objects(&source, &[], target_dir, &prefix, &mut store).await?;
+            report.grafted = grafted;
+            return Ok((store_public_root(&target_dir, &mut store).await?, report));
+        }
+        let (forest, root_dir) = open_bucket(&existing, &store).await?;
+        let (forest, root_dir, grafted) =
+            copy_objects(&root, &[], forest, root_dir, &prefix, &mut store).await?;
+        report.grafted = grafted;
+        Ok((store_root(&forest, &root_dir, &mut store).await?, report))
+    })
+    .await?;
+
+    let set = registry
+        .set_root(bucket, previous.as_ref(), &new_root, actor)
+        .await
+        .map_err(|e| anyhow!("couldn't point bucket {} at the import: {:?}", bucket, e))?;
+    if !set {
+        return Err(anyhow!(
+            "bucket {} changed while the CAR was being imported. the blocks are loaded, so running it again is quicker",
+            bucket
+        ));
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{mutex_memory_blockstore::MutexMemoryBlockStore, unixfs};
+
+    use super::*;
+
+    fn memory_store() -> AnyBlockStore {
+        AnyBlockStore::Memory(MutexMemoryBlockStore::new())
+    }
+
+    /// a private tree with one file in it
+    async fn private_root(key: &str, content: &[u8], store: &mut AnyBlockStore) -> BucketRoot {
+        let rng = &mut rand::thread_rng();
+        let root_dir = Rc::new(PrivateDirectory::new(Namefilter::default(), Utc::now(), rng));
+        let (forest, root_dir) = write_plain_file(
+            root_dir,
+            &key_to_path(key),
+            content.to_vec(),
+            "\"5d41402abc4b2a76b9719d911017c592\"",
+            Rc::new(PrivateForest::new()),
+            store,
+            rng,
+        )
+        .await
+        .unwrap();
+        store_root(&forest, &root_dir, store).await.unwrap()
+    }
+
+    async fn read_private(root: &BucketRoot, key: &str, store: &AnyBlockStore) -> Vec<u8> {
+        let (forest, root_dir) = open_bucket(root, store).await.unwrap();
+        root_dir
+            .read(&key_to_path(key), true, forest, store)
+            .await
+            .unwrap()
+            .result
+    }
+
+    /// a public tree with one file in it
+    async fn public_root(key: &str, content: &[u8], store: &mut AnyBlockStore) -> BucketRoot {
+        let leaves = unixfs::write_leaves(content, store).await.unwrap();
+        let file = unixfs::write_tree(leaves, store).await.unwrap();
+        let op = Rc::new(PublicDirectory::new(Utc::now()))
+            .write(&key_to_path(key), file.cid, Utc::now(), store)
+            .await
+            .unwrap();
+        store_public_root(&op.root_dir, store).await.unwrap()
+    }
+
+    fn car_path() -> PathBuf {
+        std::env::temp_dir().join(format!("import-{}.car", uuid::Uuid::new_v4()))
+    }
+
+    /// exports `root` out of `store`, imports it into an empty store, and hands that back
+    async fn round_trip(root: &BucketRoot, store: AnyBlockStore, version: CarVersion) -> AnyBlockStore {
+        let output = std::env::temp_dir().join(format!("export-{}.car", uuid::Uuid::new_v4()));
+        let report = export_root(store, root.clone(), None, output.clone(), version)
+            .await
+            .unwrap();
+        assert_eq!(report.root.root_cid(), root.root_cid());
+
+        let car = CarReader::new(std::fs::File::open(&output).unwrap()).unwrap();
+        assert_eq!(car.roots(), [Cid::from_str(root.root_cid()).unwrap()]);
+        let mut imported = memory_store();
+        let loaded = load_car(&output, root, &mut imported).await.unwrap();
+        assert_eq!(loaded.blocks, report.blocks);
+        std::fs::remove_file(output).unwrap();
+        imported
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn private_buckets_round_trip() {
+        let mut store = memory_store();
+        let root = private_root("docs/hello.txt", b"hello", &mut store).await;
+
+        for version in [CarVersion::V1, CarVersion::V2] {
+            let imported = round_trip(&root, store.clone(), version).await;
+            assert_eq!(read_private(&root, "docs/hello.txt", &imported).await, b"hello");
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn public_buckets_round_trip() {
+        let mut store = memory_store();
+        let root = public_root("docs/hello.txt", b"hello", &mut store).await;
+
+        let imported = round_trip(&root, store, CarVersion::V1).await;
+        let BucketRoot::Public { public_root_cid } = &root else {
+            unreachable!()
+        };
+        let root_dir = open_public(public_root_cid, &imported).await.unwrap();
+        let content_cid = root_dir
+            .read(&key_to_path("docs/hello.txt"), &imported)
+            .await
+            .unwrap()
+            .result;
+        assert_eq!(unixfs::read_file(&content_cid, &imported).await.unwrap(), b"hello");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn exporting_a_prefix_writes_nothing_to_the_store() {
+        let memory = MutexMemoryBlockStore::new();
+        let mut store = AnyBlockStore::Memory(memory.clone());
+        let root = private_root("docs/hello.txt", b"hello", &mut store).await;
+        let before = memory.list_blocks().unwrap().len();
+
+        let output = car_path();
+        let report = export_root(store, root, Some("docs/".to_string()), output.clone(), CarVersion::V1)
+            .await
+            .unwrap();
+        assert_eq!(memory.list_blocks().unwrap().len(), before);
+        // the scratch store is cleaned up after
+        let stem = output.file_stem().unwrap().to_string_lossy().into_owned();
+        let leftovers = std::fs::read_dir(output.parent().unwrap())
+            .unwrap()
+            .filter(|entry| {
+                let name = entry.as_ref().unwrap().file_name().to_string_lossy().into_owned();
+                name.starts_with(&stem) && name.contains("scratch")
+            })
+            .count();
+        assert_eq!(leftovers, 0);
+
+        let mut imported = memory_store();
+        load_car(&output, &report.root, &mut imported).await.unwrap();
+        std::fs::remove_file(output).unwrap();
+        assert_eq!(read_private(&report.root, "hello.txt", &imported).await, b"hello");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn imports_graft_into_existing_buckets_under_a_prefix() {
+        let mut exporting = memory_store();
+        let exported = private_root("docs/hello.txt", b"hello", &mut exporting).await;
+        let car = car_path();
+        let export = export_root(exporting, exported, None, car.clone(), CarVersion::V1)
+            .await
+            .unwrap();
+
+        let mut store = memory_store();
+        let registry = BucketRegistry::in_memory();
+        let existing = private_root("old.txt", b"old", &mut store).await;
+        registry.set_root("photos", None, &existing, "test").await.unwrap();
+        let report = import_car(
+            store.clone(),
+            &registry,
+            car.clone(),
+            export.root,
+            "photos",
+            Some("imported/".to_string()),
+            "test",
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.grafted, 1);
+        std::fs::remove_file(car).unwrap();
+
+        let grafted = registry.get_root("photos").await.unwrap().unwrap();
+        assert_eq!(read_private(&grafted, "imported/docs/hello.txt", &store).await, b"hello");
+        assert_eq!(read_private(&grafted, "old.txt", &store).await, b"old");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn imports_refuse_corrupt_and_missing_blocks() {
+        let mut exporting = memory_store();
+        let root = public_root("docs/hello.txt", b"hello", &mut exporting).await;
+        let root_cid = Cid::from_str(root.root_cid()).unwrap();
+        let root_block = exporting.get_block(&root_cid).await.unwrap().into_owned();
+        let registry = BucketRegistry::in_memory();
+
+        // a block that isn't what its cid says
+        let car = car_path();
+        let mut writer = CarWriter::new(std::fs::File::create(&car).unwrap(), CarVersion::V1, &[root_cid])
+            .unwrap();
+        writer.write_block(&root_cid, b"jello").unwrap();
+        writer.finish().unwrap();
+        let import = import_car(memory_store(), &registry, car.clone(), root.clone(), "photos", None, "test");
+        assert!(import.await.is_err());
+
+        // only the root directory, without the files under it
+        let mut writer = CarWriter::new(std::fs::File::create(&car).unwrap(), CarVersion::V1, &[root_cid])
+            .unwrap();
+        writer.write_block(&root_cid, &root_block).unwrap();
+        writer.finish().unwrap();
+        let error = import_car(memory_store(), &registry, car.clone(), root, "photos", None, "test")
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("doesn't hold everything"), "{}", error);
+        std::fs::remove_file(car).unwrap();
+
+        assert!(registry.get_root("photos").await.unwrap().is_none());
+
+        // a section claiming to be huge is refused before anything is allocated for it
+        let huge = [0xff, 0xff, 0xff, 0xff, 0xff, 0x7f];
+        assert!(read_section(&mut &huge[..]).is_err());
+    }
+}
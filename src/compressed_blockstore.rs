@@ -0,0 +1,159 @@
+use std::{
+    borrow::Cow,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use futures::FutureExt;
+use libipld::{
+    cid::Version,
+    multihash::{Code, MultihashDigest},
+    Cid, IpldCodec,
+};
+use wnfs::BlockStore;
+
+use crate::{
+    blockstores::{AnyBlockStore, ListedBlock},
+    fs_blockstore::verify_block,
+};
+
+/// what compressed blocks start with, before the zstd frame of the block. the last byte is the format's version.
+const HEADER: [u8; 4] = *b"BZS\x01";
+/// blocks smaller than this aren't worth compressing
+const MIN_COMPRESSIBLE_SIZE: usize = 256;
+/// how many puts go by between logging how well compression is doing
+const LOG_STATS_EVERY: u64 = 10_000;
+
+/// how well compression is doing
+#[derive(Debug, Default)]
+pub struct CompressionMetrics {
+    pub blocks_compressed: AtomicU64,
+    /// blocks stored as they are, because compressing them didn't help
+    pub blocks_uncompressed: AtomicU64,
+    /// the size of every block put, before compression
+    pub bytes_in: AtomicU64,
+    /// how much of that was actually stored
+    pub bytes_out: AtomicU64,
+}
+
+impl CompressionMetrics {
+    /// how many times smaller blocks are in the store than out of it
+    pub fn ratio(&self) -> f64 {
+        let bytes_out = self.bytes_out.load(Ordering::Relaxed);
+        if bytes_out == 0 {
+            return 1.0;
+        }
+        self.bytes_in.load(Ordering::Relaxed) as f64 / bytes_out as f64
+    }
+}
+
+/// a wrapper compressing blocks with zstd before they go into another blockstore, and decompressing them on the way out.
+///
+/// blocks are still stored under the cid of their uncompressed bytes, so nothing outside of us can tell.
+/// compressed blocks start with a header; blocks that don't compress are stored as they are, without one,
+/// which is also how blocks written before compression was turned on look. so the two can live side by side.
+/// wnfs encrypts private file content, which doesn't compress at all; the gains are in the plaintext blocks.
+#[derive(Debug, Clone)]
+pub struct CompressedBlockStore {
+    inner: Box<AnyBlockStore>,
+    level: i32,
+    metrics: Arc<CompressionMetrics>,
+}
+
+impl CompressedBlockStore {
+    /// only stores that keep whatever bytes they're given under whatever cid they're given can be compressed in front of
+    pub fn new(inner: AnyBlockStore, level: i32) -> Result<Self> {
+        if !matches!(
+            inner,
+            AnyBlockStore::Memory(_) | AnyBlockStore::Fs(_) | AnyBlockStore::Gcs(_) | AnyBlockStore::S3(_)
+        ) {
+            return Err(anyhow!("blocks can't be compressed in this kind of blockstore"));
+        }
+        Ok(Self {
+            inner: Box::new(inner),
+            level,
+            metrics: Default::default(),
+        })
+    }
+
+    pub async fn list_blocks(&self) -> Result<Vec<ListedBlock>> {
+        // boxed, because the inner store's futures could contain ours
+        self.inner.list_blocks().boxed().await
+    }
+
+    pub async fn delete_block(&self, cid: &Cid) -> Result<()> {
+        self.inner.delete_block(cid).boxed().await
+    }
+
+    /// compresses a block, unless that wouldn't save at least a sixteenth of it
+    fn compress(&self, bytes: Vec<u8>) -> Result<Vec<u8>> {
+        let size = bytes.len() as u64;
+        let stored = if bytes.len() < MIN_COMPRESSIBLE_SIZE {
+            None
+        } else {
+            let mut compressed = HEADER.to_vec();
+            compressed.extend(zstd::bulk::compress(&bytes, self.level)?);
+            (compressed.len() < bytes.len() - bytes.len() / 16).then_some(compressed)
+        };
+        let stored = match stored {
+            Some(compressed) => {
+                self.metrics.blocks_compressed.fetch_add(1, Ordering::Relaxed);
+                compressed
+            }
+            None => {
+                self.metrics.blocks_uncompressed.fetch_add(1, Ordering::Relaxed);
+                bytes
+            }
+        };
+        self.metrics.bytes_in.fetch_add(size, Ordering::Relaxed);
+        self.metrics.bytes_out.fetch_add(stored.len() as u64, Ordering::Relaxed);
+
+        let puts = self.metrics.blocks_compressed.load(Ordering::Relaxed)
+            + self.metrics.blocks_uncompressed.load(Ordering::Relaxed);
+        if puts.is_multiple_of(LOG_STATS_EVERY) {
+            log::debug!(
+                "compressed blockstore: {} blocks compressed, {} not, {:.2}x smaller overall",
+                self.metrics.blocks_compressed.load(Ordering::Relaxed),
+                self.metrics.blocks_uncompressed.load(Ordering::Relaxed),
+                self.metrics.ratio()
+            );
+        }
+        Ok(stored)
+    }
+}
+
+/// turns what was stored under `cid` back into the block, checking it on the way
+fn decompress(cid: &Cid, stored: Vec<u8>) -> Result<Vec<u8>> {
+    if let Some(compressed) = stored.strip_prefix(&HEADER) {
+        if let Ok(bytes) = zstd::decode_all(compressed) {
+            if verify_block(cid, &bytes).is_ok() {
+                return Ok(bytes);
+            }
+        }
+    }
+    // an uncompressed block that happens to start like a compressed one ends up here too
+    verify_block(cid, &stored)?;
+    Ok(stored)
+}
+
+#[async_trait(?Send)]
+impl BlockStore for CompressedBlockStore {
+    async fn put_block(&mut self, bytes: Vec<u8>, codec: IpldCodec) -> Result<Cid> {
+        let hash = Code::Sha2_256.digest(&bytes);
+        let cid = Cid::new(Version::V1, codec.into(), hash)?;
+
+        let stored = self.compress(bytes)?;
+        self.inner.put_stored(&cid, stored).await?;
+
+        Ok(cid)
+    }
+
+    async fn get_block<'a>(&'a self, cid: &Cid) -> Result<Cow<'a, Vec<u8>>> {
+        let stored = self.inner.get_stored(cid).await?;
+        Ok(Cow::Owned(decompress(cid, stored)?))
+    }
+}
@@ -0,0 +1,236 @@
+use std::{borrow::Cow, path::PathBuf, str::FromStr};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use libipld::{
+    cid::Version,
+    multihash::{Code, MultihashDigest},
+    Cid, IpldCodec,
+};
+use tokio::io::AsyncWriteExt;
+use wnfs::BlockStore;
+
+use crate::blockstores::{BlockError, ListedBlock};
+
+/// a block store keeping each block in a file named after its cid, under `root`.
+///
+/// blocks are sharded into directories by the next-to-last two characters of the cid, like IPFS's flatfs,
+/// so no directory gets too big. the trailing character is skipped because it carries hardly any entropy.
+#[derive(Debug, Clone)]
+pub struct FsBlockStore {
+    root: PathBuf,
+}
+
+impl FsBlockStore {
+    pub async fn new(root: PathBuf) -> Result<Self> {
+        tokio::fs::create_dir_all(&root).await?;
+        Ok(Self { root })
+    }
+
+    pub(crate) fn path_for(&self, cid: &Cid) -> PathBuf {
+        let name = cid.to_string();
+        let shard = &name[name.len() - 3..name.len() - 1];
+        self.root.join(shard).join(name)
+    }
+
+    /// lists every block in the store, with when it was written
+    pub async fn list_blocks(&self) -> Result<Vec<ListedBlock>> {
+        let mut blocks = Vec::new();
+        let mut shards = tokio::fs::read_dir(&self.root).await?;
+        while let Some(shard) = shards.next_entry().await? {
+            if !shard.file_type().await?.is_dir() {
+                continue;
+            }
+            let mut entries = tokio::fs::read_dir(shard.path()).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                // half-written blocks
+                if name.starts_with('.') {
+                    continue;
+                }
+                let Ok(cid) = Cid::from_str(&name) else {
+                    log::warn!(
+                        "fs blockstore: found a file that isn't a block at {:?}",
+                        entry.path()
+                    );
+                    continue;
+                };
+                let modified = entry
+                    .metadata()
+                    .await?
+                    .modified()
+                    .ok()
+                    .map(DateTime::<Utc>::from);
+                blocks.push(ListedBlock { cid, modified });
+            }
+        }
+        Ok(blocks)
+    }
+
+    /// writes `bytes` under `cid`, unchecked, to a temp file and renames it into place, so nobody ever sees half a block.
+    /// a block that's already there is written again: that's what tells gc it's just been used, and it replaces a corrupt copy.
+    pub async fn put_stored(&self, cid: &Cid, bytes: &[u8]) -> Result<()> {
+        let path = self.path_for(cid);
+        let parent = path.parent().expect("block paths always have a shard directory");
+        tokio::fs::create_dir_all(parent).await?;
+        let temp = parent.join(format!(".{}.partial", uuid::Uuid::new_v4()));
+        let write = async {
+            let mut file = tokio::fs::File::create(&temp).await?;
+            file.write_all(bytes).await?;
+            file.sync_all().await?;
+            tokio::fs::rename(&temp, &path).await
+        };
+        if let Err(e) = write.await {
+            let _ = tokio::fs::remove_file(&temp).await;
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    /// reads back whatever is stored under `cid`, unchecked
+    pub async fn get_stored(&self, cid: &Cid) -> Result<Vec<u8>> {
+        match tokio::fs::read(self.path_for(cid)).await {
+            Ok(bytes) => Ok(bytes),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(BlockError::NotFound(*cid).into()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// removes a block from the store. removing a block that isn't there is fine.
+    pub async fn delete_block(&self, cid: &Cid) -> Result<()> {
+        match tokio::fs::remove_file(self.path_for(cid)).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// checks that `bytes` are what `cid` says they are
+pub(crate) fn verify_block(cid: &Cid, bytes: &[u8]) -> Result<()> {
+    let code = Code::try_from(cid.hash().code())?;
+    if code.digest(bytes) != *cid.hash() {
+        return Err(BlockError::Corrupt(*cid).into());
+    }
+    Ok(())
+}
+
+#[async_trait(?Send)]
+impl BlockStore for FsBlockStore {
+    async fn put_block(&mut self, bytes: Vec<u8>, codec: IpldCodec) -> Result<Cid> {
+        let hash = Code::Sha2_256.digest(&bytes);
+        let cid = Cid::new(Version::V1, codec.into(), hash)?;
+
+        self.put_stored(&cid, &bytes).await?;
+
+        Ok(cid)
+    }
+
+    /// reads a block back, and refuses to hand it over if it's been corrupted on disk
+    async fn get_block<'a>(&'a self, cid: &Cid) -> Result<Cow<'a, Vec<u8>>> {
+        let bytes = self.get_stored(cid).await?;
+        verify_block(cid, &bytes)?;
+
+        Ok(Cow::Owned(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    struct TempStore {
+        root: PathBuf,
+        store: FsBlockStore,
+    }
+
+    impl Drop for TempStore {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.root);
+        }
+    }
+
+    async fn temp_store() -> TempStore {
+        let root = std::env::temp_dir().join(format!("fs-blockstore-{}", uuid::Uuid::new_v4()));
+        let store = FsBlockStore::new(root.clone()).await.unwrap();
+        TempStore { root, store }
+    }
+
+    fn block_error(error: anyhow::Error) -> BlockError {
+        error.downcast::<BlockError>().unwrap()
+    }
+
+    #[tokio::test]
+    async fn reads_check_the_hash_and_tell_corrupt_from_missing() {
+        let TempStore { store, .. } = &temp_store().await;
+        let cid = store.clone().put_block(b"hello".to_vec(), IpldCodec::Raw).await.unwrap();
+        assert_eq!(store.get_block(&cid).await.unwrap().as_slice(), b"hello");
+
+        std::fs::write(store.path_for(&cid), b"jello").unwrap();
+        let error = block_error(store.get_block(&cid).await.unwrap_err());
+        assert!(matches!(error, BlockError::Corrupt(c) if c == cid));
+        // get_stored doesn't check, which is what lets a scrub see what's actually there
+        assert_eq!(store.get_stored(&cid).await.unwrap(), b"jello");
+
+        store.delete_block(&cid).await.unwrap();
+        let error = block_error(store.get_block(&cid).await.unwrap_err());
+        assert!(matches!(error, BlockError::NotFound(c) if c == cid));
+        store.delete_block(&cid).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn puts_go_through_a_temp_file_and_replace_whatever_was_there() {
+        let TempStore { store, .. } = &temp_store().await;
+        let cid = store.clone().put_block(b"hello".to_vec(), IpldCodec::Raw).await.unwrap();
+        let path = store.path_for(&cid);
+        std::fs::write(&path, b"jello").unwrap();
+        // what a put that died halfway leaves behind
+        let shard = path.parent().unwrap();
+        std::fs::write(shard.join(".half-written.partial"), b"hel").unwrap();
+
+        store.clone().put_block(b"hello".to_vec(), IpldCodec::Raw).await.unwrap();
+        assert_eq!(store.get_block(&cid).await.unwrap().as_slice(), b"hello");
+        let mut names: Vec<_> = std::fs::read_dir(shard)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        // the put's own temp file was renamed into place, so only the dead one is left
+        assert_eq!(names, vec![".half-written.partial".to_string(), cid.to_string()]);
+        let listed: Vec<_> = store.list_blocks().await.unwrap().into_iter().map(|block| block.cid).collect();
+        assert_eq!(listed, vec![cid]);
+    }
+
+    #[tokio::test]
+    async fn blocks_are_sharded_and_listed_from_every_shard() {
+        let TempStore { root, store } = &temp_store().await;
+        let mut cids = HashSet::new();
+        for i in 0..64 {
+            let cid = store
+                .clone()
+                .put_block(format!("block {}", i).into_bytes(), IpldCodec::Raw)
+                .await
+                .unwrap();
+            let name = cid.to_string();
+            assert_eq!(
+                store.path_for(&cid),
+                root.join(&name[name.len() - 3..name.len() - 1]).join(&name)
+            );
+            cids.insert(cid);
+        }
+        let shards = std::fs::read_dir(root).unwrap().count();
+        assert!(shards > 1 && shards < cids.len());
+        // neither of these is a block
+        std::fs::write(root.join("README"), b"hi").unwrap();
+        let some_shard = store.path_for(cids.iter().next().unwrap());
+        std::fs::write(some_shard.parent().unwrap().join("not-a-cid"), b"hi").unwrap();
+
+        let listed = store.list_blocks().await.unwrap();
+        assert_eq!(listed.len(), cids.len());
+        assert_eq!(listed.iter().map(|block| block.cid).collect::<HashSet<_>>(), cids);
+        assert!(listed.iter().all(|block| block.modified.is_some()));
+    }
+}
@@ -0,0 +1,493 @@
+use std::{borrow::Cow, future::Future, str::FromStr, sync::Arc, time::Duration};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{TimeZone, Utc};
+use google_cloud_default::WithAuthExt;
+use google_cloud_token::{TokenSource, TokenSourceProvider};
+use google_cloud_storage::{
+    client::{Client, ClientConfig},
+    http::{
+        error::ErrorResponse,
+        objects::{
+            delete::DeleteObjectRequest,
+            download::Range,
+            get::GetObjectRequest,
+            list::ListObjectsRequest,
+            patch::PatchObjectRequest,
+            upload::{Media, UploadObjectRequest, UploadType},
+            Object,
+        },
+        Error as GcsError,
+    },
+};
+use libipld::{
+    cid::Version,
+    multihash::{Code, MultihashDigest},
+    Cid, IpldCodec,
+};
+use wnfs::BlockStore;
+
+use crate::{
+    blockstores::{BlockError, ListedBlock},
+    fs_blockstore::verify_block,
+};
+
+/// how many times we try a request before giving up on it
+const MAX_ATTEMPTS: u32 = 5;
+/// how long we wait before the first retry. it doubles every retry after that.
+const FIRST_BACKOFF: Duration = Duration::from_millis(100);
+
+/// hands out an empty token, for talking to endpoints that don't check them, like a fake-gcs-server.
+/// the client's default provider panics when asked for a token source.
+#[derive(Debug)]
+struct AnonymousTokenSource;
+
+#[async_trait]
+impl TokenSource for AnonymousTokenSource {
+    async fn token(&self) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(String::new())
+    }
+}
+
+impl TokenSourceProvider for AnonymousTokenSource {
+    fn token_source(&self) -> Arc<dyn TokenSource> {
+        Arc::new(AnonymousTokenSource)
+    }
+}
+
+/// a block store keeping each block in a google cloud storage object named after its cid.
+/// clones share one client, and with it one connection pool.
+#[derive(Clone)]
+pub struct GcsBlockStore {
+    client: Arc<Client>,
+    bucket: String,
+}
+
+impl GcsBlockStore {
+    /// connects to gcs with the default credentials.
+    /// if `endpoint` is set, connects anonymously to that instead, e.g. a fake-gcs-server for local testing.
+    pub async fn new(bucket: String, endpoint: Option<String>) -> Result<Self> {
+        let config = match endpoint {
+            Some(storage_endpoint) => ClientConfig {
+                storage_endpoint,
+                token_source_provider: Box::new(AnonymousTokenSource),
+                ..Default::default()
+            },
+            None => ClientConfig::default().with_auth().await?,
+        };
+        let client = Arc::new(Client::new(config));
+
+        Ok(Self { client, bucket })
+    }
+
+    /// lists every block in the bucket, with when it was written
+    pub async fn list_blocks(&self) -> Result<Vec<ListedBlock>> {
+        let mut blocks = Vec::new();
+        let mut page_token = None;
+        loop {
+            let list_object_req = ListObjectsRequest {
+                bucket: self.bucket.clone(),
+                page_token,
+                ..Default::default()
+            };
+            let list_object_resp = with_retries("listing blocks", || {
+                self.client.list_objects(&list_object_req)
+            })
+            .await?;
+            for object in list_object_resp.items.unwrap_or_default() {
+                let Ok(cid) = Cid::from_str(&object.name) else {
+                    log::warn!(
+                        "gcs blockstore: found an object that isn't a block: {}",
+                        object.name
+                    );
+                    continue;
+                };
+                let modified = object
+                    .updated
+                    .and_then(|updated| Utc.timestamp_opt(updated.unix_timestamp(), 0).single());
+                blocks.push(ListedBlock { cid, modified });
+            }
+            page_token = list_object_resp.next_page_token;
+            if page_token.is_none() {
+                break;
+            }
+        }
+        Ok(blocks)
+    }
+
+    /// uploads `bytes` as the object for `cid`, unchecked, unless there's already an object for it.
+    /// a block that's already there isn't uploaded again, just touched, so gc sees it's just been used.
+    /// that means a corrupt copy has to be deleted before it can be replaced.
+    pub async fn put_stored(&self, cid: &Cid, bytes: Vec<u8>) -> Result<()> {
+        let name = cid.to_string();
+        let upload_request = UploadObjectRequest {
+            bucket: self.bucket.clone(),
+            if_generation_match: Some(0),
+            ..Default::default()
+        };
+        let result = with_retries(&format!("putting block {}", name), || {
+            let upload_type = UploadType::Simple(Media::new(name.clone()));
+            let bytes = bytes.clone();
+            let upload_request = &upload_request;
+            async move {
+                self.client
+                    .upload_object(upload_request, bytes, &upload_type)
+                    .await
+            }
+        })
+        .await;
+        match result {
+            Ok(_) => Ok(()),
+            Err(GcsError::Response(ErrorResponse { code: 412, .. })) => self.touch(cid).await,
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// bumps the `updated` time of the object for `cid` with a metadata patch, which is much cheaper than uploading it again
+    async fn touch(&self, cid: &Cid) -> Result<()> {
+        let patch_request = PatchObjectRequest {
+            bucket: self.bucket.clone(),
+            object: cid.to_string(),
+            metadata: Some(Object {
+                metadata: Some([("used".to_string(), Utc::now().to_rfc3339())].into()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        with_retries(&format!("touching block {}", cid), || {
+            self.client.patch_object(&patch_request)
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// downloads whatever is stored as the object for `cid`, unchecked
+    pub async fn get_stored(&self, cid: &Cid) -> Result<Vec<u8>> {
+        let get_object_req = GetObjectRequest {
+            bucket: self.bucket.clone(),
+            object: cid.to_string(),
+            ..Default::default()
+        };
+        let range = Range::default();
+        let result = with_retries(&format!("getting block {}", cid), || {
+            self.client.download_object(&get_object_req, &range)
+        })
+        .await;
+        match result {
+            Ok(bytes) => Ok(bytes),
+            Err(GcsError::Response(ErrorResponse { code: 404, .. })) => {
+                Err(BlockError::NotFound(*cid).into())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// removes a block from the bucket. removing a block that isn't there is fine.
+    pub async fn delete_block(&self, cid: &Cid) -> Result<()> {
+        let delete_request = DeleteObjectRequest {
+            bucket: self.bucket.clone(),
+            object: cid.to_string(),
+            ..Default::default()
+        };
+        match with_retries(&format!("deleting block {}", cid), || {
+            self.client.delete_object(&delete_request)
+        })
+        .await
+        {
+            Ok(()) => Ok(()),
+            Err(GcsError::Response(ErrorResponse { code: 404, .. })) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl std::fmt::Debug for GcsBlockStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GcsBlockStore")
+            .field("bucket", &self.bucket)
+            .finish()
+    }
+}
+
+/// whether a failed request is worth trying again
+fn is_retryable(e: &GcsError) -> bool {
+    match e {
+        GcsError::Response(ErrorResponse { code, .. }) => *code == 429 || *code >= 500,
+        GcsError::HttpClient(_) => true,
+        _ => false,
+    }
+}
+
+/// runs a request until it works, it fails in a way that won't get better, or we run out of attempts
+async fn with_retries<T, F, Fut>(what: &str, mut request: F) -> Result<T, GcsError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, GcsError>>,
+{
+    let mut backoff = FIRST_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        match request().await {
+            Err(e) if attempt < MAX_ATTEMPTS && is_retryable(&e) => {
+                log::warn!(
+                    "gcs blockstore: {} failed (attempt {} of {}). retrying in {:?}. error was {}",
+                    what,
+                    attempt,
+                    MAX_ATTEMPTS,
+                    backoff,
+                    e
+                );
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl BlockStore for GcsBlockStore {
+    async fn put_block(&mut self, bytes: Vec<u8>, codec: IpldCodec) -> Result<Cid> {
+        let hash = Code::Sha2_256.digest(&bytes);
+        let cid = Cid::new(Version::V1, codec.into(), hash)?;
+
+        self.put_stored(&cid, bytes).await?;
+
+        Ok(cid)
+    }
+
+    /// downloads a block, and refuses to hand it over if it's not the block we asked for
+    async fn get_block<'a>(&'a self, cid: &Cid) -> Result<Cow<'a, Vec<u8>>> {
+        let bytes = self.get_stored(cid).await?;
+        verify_block(cid, &bytes)?;
+
+        Ok(Cow::Owned(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        convert::Infallible,
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicU32, Ordering},
+            Mutex,
+        },
+    };
+
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Body, Method, Request, Response, Server, StatusCode,
+    };
+    use serde_json::json;
+
+    use super::*;
+
+    const BUCKET: &str = "blocks";
+
+    /// just enough of the GCS JSON API for the blockstore, over a map of object names to contents and generations
+    #[derive(Default)]
+    struct FakeGcs {
+        objects: Mutex<BTreeMap<String, (Vec<u8>, i64)>>,
+        /// how many of the next requests get a 503 instead of an answer
+        failures: AtomicU32,
+        requests: AtomicU32,
+        uploads: AtomicU32,
+        patches: AtomicU32,
+    }
+
+    fn error(status: StatusCode) -> Response<Body> {
+        let body = json!({
+            "error": {
+                "code": status.as_u16(),
+                "errors": [{"domain": "global", "message": status.to_string(), "reason": "fake"}],
+                "message": status.to_string(),
+            }
+        });
+        Response::builder()
+            .status(status)
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    fn object(name: &str, content: &[u8], generation: i64) -> serde_json::Value {
+        json!({
+            "selfLink": "",
+            "mediaLink": "",
+            "metageneration": "1",
+            "size": content.len().to_string(),
+            "crc32c": "",
+            "etag": "",
+            "name": name,
+            "id": format!("{}/{}/{}", BUCKET, name, generation),
+            "bucket": BUCKET,
+            "generation": generation.to_string(),
+            "updated": "2023-06-01T12:00:00Z",
+        })
+    }
+
+    fn ok_json(body: serde_json::Value) -> Response<Body> {
+        Response::builder()
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    impl FakeGcs {
+        async fn handle(&self, req: Request<Body>) -> Response<Body> {
+            self.requests.fetch_add(1, Ordering::SeqCst);
+            if self
+                .failures
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok()
+            {
+                return error(StatusCode::SERVICE_UNAVAILABLE);
+            }
+            let url = reqwest::Url::parse(&format!("http://fake{}", req.uri())).unwrap();
+            let query: BTreeMap<String, String> = url.query_pairs().into_owned().collect();
+            let path: Vec<String> = url.path_segments().unwrap().map(String::from).collect();
+            let path: Vec<&str> = path.iter().map(String::as_str).collect();
+            match (req.method().clone(), path.as_slice()) {
+                (Method::POST, ["upload", "storage", "v1", "b", BUCKET, "o"]) => {
+                    self.uploads.fetch_add(1, Ordering::SeqCst);
+                    let name = query["name"].clone();
+                    let content = hyper::body::to_bytes(req.into_body()).await.unwrap().to_vec();
+                    let mut objects = self.objects.lock().unwrap();
+                    let generation = objects.get(&name).map_or(0, |(_, generation)| *generation);
+                    if let Some(wanted) = query.get("ifGenerationMatch") {
+                        if wanted.parse::<i64>().unwrap() != generation {
+                            return error(StatusCode::PRECONDITION_FAILED);
+                        }
+                    }
+                    let body = object(&name, &content, generation + 1);
+                    objects.insert(name, (content, generation + 1));
+                    ok_json(body)
+                }
+                (Method::GET, ["storage", "v1", "b", BUCKET, "o"]) => {
+                    // two to a page, so listing has to follow page tokens
+                    let objects = self.objects.lock().unwrap();
+                    let start: usize = query.get("pageToken").map_or(0, |token| token.parse().unwrap());
+                    let items: Vec<_> = objects
+                        .iter()
+                        .skip(start)
+                        .take(2)
+                        .map(|(name, (content, generation))| object(name, content, *generation))
+                        .collect();
+                    let mut body = json!({ "items": items });
+                    if start + 2 < objects.len() {
+                        body["nextPageToken"] = json!((start + 2).to_string());
+                    }
+                    ok_json(body)
+                }
+                (Method::GET, ["storage", "v1", "b", BUCKET, "o", name]) => {
+                    match self.objects.lock().unwrap().get(*name) {
+                        Some((content, _)) => Response::new(Body::from(content.clone())),
+                        None => error(StatusCode::NOT_FOUND),
+                    }
+                }
+                (Method::PATCH, ["storage", "v1", "b", BUCKET, "o", name]) => {
+                    self.patches.fetch_add(1, Ordering::SeqCst);
+                    match self.objects.lock().unwrap().get(*name) {
+                        Some((content, generation)) => ok_json(object(name, content, *generation)),
+                        None => error(StatusCode::NOT_FOUND),
+                    }
+                }
+                (Method::DELETE, ["storage", "v1", "b", BUCKET, "o", name]) => {
+                    match self.objects.lock().unwrap().remove(*name) {
+                        Some(_) => Response::builder()
+                            .status(StatusCode::NO_CONTENT)
+                            .body(Body::empty())
+                            .unwrap(),
+                        None => error(StatusCode::NOT_FOUND),
+                    }
+                }
+                _ => error(StatusCode::BAD_REQUEST),
+            }
+        }
+    }
+
+    /// serves a fake on a port of its own, and connects a blockstore to it
+    async fn fake_store() -> (Arc<FakeGcs>, GcsBlockStore) {
+        let fake = Arc::new(FakeGcs::default());
+        let served = fake.clone();
+        let make_service = make_service_fn(move |_| {
+            let fake = served.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let fake = fake.clone();
+                    async move { Ok::<_, Infallible>(fake.handle(req).await) }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let endpoint = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        let store = GcsBlockStore::new(BUCKET.to_string(), Some(endpoint))
+            .await
+            .unwrap();
+        (fake, store)
+    }
+
+    #[tokio::test]
+    async fn blocks_round_trip() {
+        let (fake, mut store) = fake_store().await;
+        let cid = store.put_block(b"hello".to_vec(), IpldCodec::Raw).await.unwrap();
+        assert_eq!(*store.get_block(&cid).await.unwrap(), b"hello".to_vec());
+        // putting it again only touches it, so gc sees it as new without it being uploaded twice
+        assert_eq!(store.put_block(b"hello".to_vec(), IpldCodec::Raw).await.unwrap(), cid);
+        assert_eq!(fake.uploads.load(Ordering::SeqCst), 2);
+        assert_eq!(fake.patches.load(Ordering::SeqCst), 1);
+        assert_eq!(fake.objects.lock().unwrap()[&cid.to_string()].1, 1);
+    }
+
+    #[tokio::test]
+    async fn missing_and_corrupt_blocks_are_told_apart() {
+        let (fake, mut store) = fake_store().await;
+        let cid = store.put_block(b"hello".to_vec(), IpldCodec::Raw).await.unwrap();
+        fake.objects.lock().unwrap().get_mut(&cid.to_string()).unwrap().0 = b"jello".to_vec();
+        let corrupt = store.get_block(&cid).await.unwrap_err();
+        assert!(matches!(corrupt.downcast_ref(), Some(BlockError::Corrupt(c)) if *c == cid));
+
+        store.delete_block(&cid).await.unwrap();
+        let missing = store.get_block(&cid).await.unwrap_err();
+        assert!(matches!(missing.downcast_ref(), Some(BlockError::NotFound(c)) if *c == cid));
+        // deleting what isn't there is fine too
+        store.delete_block(&cid).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn listing_follows_pages() {
+        let (_fake, mut store) = fake_store().await;
+        let mut cids = Vec::new();
+        for i in 0..5u8 {
+            cids.push(store.put_block(vec![i], IpldCodec::Raw).await.unwrap());
+        }
+        let mut listed: Vec<Cid> = store
+            .list_blocks()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|block| {
+                assert!(block.modified.is_some());
+                block.cid
+            })
+            .collect();
+        listed.sort();
+        cids.sort();
+        assert_eq!(listed, cids);
+    }
+
+    #[tokio::test]
+    async fn unavailable_requests_are_retried() {
+        let (fake, mut store) = fake_store().await;
+        fake.failures.store(2, Ordering::SeqCst);
+        let cid = store.put_block(b"hello".to_vec(), IpldCodec::Raw).await.unwrap();
+        assert_eq!(fake.requests.load(Ordering::SeqCst), 3);
+        assert_eq!(*store.get_block(&cid).await.unwrap(), b"hello".to_vec());
+    }
+}
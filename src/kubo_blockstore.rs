@@ -0,0 +1,355 @@
+use std::{borrow::Cow, collections::HashMap, str::FromStr};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use libipld::{
+    cid::Version,
+    multihash::{Code, MultihashDigest},
+    Cid, IpldCodec,
+};
+use reqwest::{multipart, Url};
+use serde::Deserialize;
+use wnfs::BlockStore;
+
+use crate::{blockstores::ListedBlock, fs_blockstore::verify_block};
+
+/// a block store keeping blocks in an IPFS node, through kubo's RPC API.
+/// blocks are put with the codec wnfs asked for, so their cids are the same inside and outside of wnfs,
+/// and anything stored through S3 can be fetched from any IPFS gateway that can reach the node.
+#[derive(Debug, Clone)]
+pub struct KuboBlockStore {
+    client: reqwest::Client,
+    /// the root of the RPC API, e.g. http://127.0.0.1:5001
+    endpoint: Url,
+    /// whether to pin blocks once they're put, so the node's gc leaves them alone
+    pin: bool,
+}
+
+/// what block/put tells us about the block it stored
+#[derive(Debug, Deserialize)]
+struct BlockPutResponse {
+    #[serde(rename = "Key")]
+    key: String,
+}
+
+/// what pin/ls tells us about the pins on the node
+#[derive(Debug, Deserialize)]
+struct PinLsResponse {
+    #[serde(rename = "Keys")]
+    keys: HashMap<String, serde_json::Value>,
+}
+
+/// kubo's names for the codecs wnfs uses
+fn codec_name(codec: IpldCodec) -> &'static str {
+    match codec {
+        IpldCodec::Raw => "raw",
+        IpldCodec::DagCbor => "dag-cbor",
+        IpldCodec::DagJson => "dag-json",
+        IpldCodec::DagPb => "dag-pb",
+    }
+}
+
+impl KuboBlockStore {
+    pub fn new(endpoint: Url, pin: bool) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            endpoint,
+            pin,
+        }
+    }
+
+    /// calls an RPC method. kubo wants everything POSTed, and answers errors with a 500 and a message.
+    async fn call(
+        &self,
+        method: &str,
+        query: &[(&str, &str)],
+        form: Option<multipart::Form>,
+    ) -> Result<reqwest::Response> {
+        let mut request = self
+            .client
+            .post(self.endpoint.join(&format!("api/v0/{}", method))?)
+            .query(query);
+        if let Some(form) = form {
+            request = request.multipart(form);
+        }
+        let response = request.send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(anyhow!(
+                "kubo blockstore: {} failed with {}: {}",
+                method,
+                status,
+                response.text().await.unwrap_or_default()
+            ));
+        }
+        Ok(response)
+    }
+
+    /// lists the blocks we've pinned, since the node's other content isn't ours to list.
+    /// with pinning off there's no telling our blocks apart, so this refuses rather than have gc miss them all;
+    /// kubo's own gc looks after those. kubo doesn't say when a block was put, so there's no modified time.
+    pub async fn list_blocks(&self) -> Result<Vec<ListedBlock>> {
+        if !self.pin {
+            return Err(anyhow!(
+                "kubo blockstore: blocks aren't pinned, so they can't be listed; leave collecting them to kubo's gc"
+            ));
+        }
+        let response: PinLsResponse = self
+            .call("pin/ls", &[("type", "direct")], None)
+            .await?
+            .json()
+            .await?;
+        response
+            .keys
+            .keys()
+            .map(|key| {
+                Ok(ListedBlock {
+                    cid: Cid::from_str(key)?,
+                    modified: None,
+                })
+            })
+            .collect()
+    }
+
+    /// unpins a block, if we pin them, and removes it from the node
+    pub async fn delete_block(&self, cid: &Cid) -> Result<()> {
+        let cid_string = cid.to_string();
+        if self.pin {
+            match self.call("pin/rm", &[("arg", cid_string.as_str())], None).await {
+                Ok(_) => {}
+                // e.g. a block put while pinning was off. there's nothing to undo, so it can go
+                Err(e) if e.to_string().contains("not pinned") => {}
+                Err(e) => return Err(e),
+            }
+        }
+        self.call("block/rm", &[("arg", cid_string.as_str())], None)
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl BlockStore for KuboBlockStore {
+    /// puts a block into the node, checks the node came up with the same cid, and pins it
+    async fn put_block(&mut self, bytes: Vec<u8>, codec: IpldCodec) -> Result<Cid> {
+        let hash = Code::Sha2_256.digest(&bytes);
+        let cid = Cid::new(Version::V1, codec.into(), hash)?;
+
+        let form = multipart::Form::new().part("file", multipart::Part::bytes(bytes));
+        let response: BlockPutResponse = self
+            .call(
+                "block/put",
+                &[("cid-codec", codec_name(codec)), ("mhtype", "sha2-256")],
+                Some(form),
+            )
+            .await?
+            .json()
+            .await?;
+        if Cid::from_str(&response.key)? != cid {
+            return Err(anyhow!(
+                "kubo blockstore: node stored block {} as {}",
+                cid,
+                response.key
+            ));
+        }
+        if self.pin {
+            self.call("pin/add", &[("arg", response.key.as_str())], None)
+                .await?;
+        }
+
+        Ok(cid)
+    }
+
+    /// gets a block from the node, and refuses to hand it over if it's not the block we asked for.
+    /// the node is asked not to go looking on the network, so a block we never put fails instead of hanging.
+    async fn get_block<'a>(&'a self, cid: &Cid) -> Result<Cow<'a, Vec<u8>>> {
+        let cid_string = cid.to_string();
+        let bytes = self
+            .call(
+                "block/get",
+                &[("arg", cid_string.as_str()), ("offline", "true")],
+                None,
+            )
+            .await?
+            .bytes()
+            .await?
+            .to_vec();
+        verify_block(cid, &bytes)?;
+
+        Ok(Cow::Owned(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashSet,
+        convert::Infallible,
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+    };
+
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Body, Request, Response, Server,
+    };
+
+    use super::*;
+    use crate::blockstores::BlockError;
+
+    /// what the fake kubo holds, and which methods it was called with
+    #[derive(Default)]
+    struct Node {
+        calls: Vec<String>,
+        blocks: HashMap<String, Vec<u8>>,
+        pins: HashSet<String>,
+    }
+
+    struct FakeKubo {
+        endpoint: Url,
+        node: Arc<Mutex<Node>>,
+    }
+
+    fn error(message: &str) -> Response<Body> {
+        let body = serde_json::json!({"Message": message, "Code": 0, "Type": "error"});
+        Response::builder().status(500).body(Body::from(body.to_string())).unwrap()
+    }
+
+    fn json(body: serde_json::Value) -> Response<Body> {
+        Response::new(Body::from(body.to_string()))
+    }
+
+    /// the first file in a multipart form, which is all block/put is sent
+    fn form_file(body: &[u8]) -> Vec<u8> {
+        let start = body.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        let end = body.windows(4).rposition(|w| w == b"\r\n--").unwrap();
+        body[start..end].to_vec()
+    }
+
+    /// the RPC methods the blockstore uses, answered the way kubo would
+    fn answer(node: &mut Node, method: &str, query: &HashMap<String, String>, body: &[u8]) -> Response<Body> {
+        node.calls.push(method.to_string());
+        let arg = query.get("arg").cloned().unwrap_or_default();
+        match method {
+            "block/put" => {
+                let codec = [IpldCodec::Raw, IpldCodec::DagCbor, IpldCodec::DagJson, IpldCodec::DagPb]
+                    .into_iter()
+                    .find(|codec| Some(codec_name(*codec)) == query.get("cid-codec").map(String::as_str))
+                    .unwrap();
+                let bytes = form_file(body);
+                let cid = Cid::new_v1(codec.into(), Code::Sha2_256.digest(&bytes)).to_string();
+                node.blocks.insert(cid.clone(), bytes);
+                json(serde_json::json!({"Key": cid, "Size": 0}))
+            }
+            "block/get" => match node.blocks.get(&arg) {
+                Some(bytes) if query.get("offline").map(String::as_str) == Some("true") => {
+                    Response::new(Body::from(bytes.clone()))
+                }
+                Some(_) => panic!("the blockstore should never let kubo go looking on the network"),
+                None => error("block was not found locally (offline): ipld: could not find node"),
+            },
+            "block/rm" => {
+                node.blocks.remove(&arg);
+                json(serde_json::json!({"Hash": arg}))
+            }
+            "pin/add" if node.blocks.contains_key(&arg) => {
+                node.pins.insert(arg.clone());
+                json(serde_json::json!({"Pins": [arg]}))
+            }
+            "pin/rm" if node.pins.remove(&arg) => json(serde_json::json!({"Pins": [arg]})),
+            "pin/rm" => error("not pinned or pinned indirectly"),
+            "pin/ls" => {
+                let keys: serde_json::Map<_, _> = node
+                    .pins
+                    .iter()
+                    .map(|pin| (pin.clone(), serde_json::json!({"Type": "direct"})))
+                    .collect();
+                json(serde_json::json!({ "Keys": keys }))
+            }
+            _ => error(&format!("unknown method {}", method)),
+        }
+    }
+
+    fn fake_kubo() -> FakeKubo {
+        let node = Arc::new(Mutex::new(Node::default()));
+        let shared = node.clone();
+        let make_service = make_service_fn(move |_| {
+            let node = shared.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let node = node.clone();
+                    async move {
+                        let method = request.uri().path().trim_start_matches("/api/v0/").to_string();
+                        let query = Url::parse(&format!("http://kubo{}", request.uri()))
+                            .unwrap()
+                            .query_pairs()
+                            .into_owned()
+                            .collect();
+                        let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                        Ok::<_, Infallible>(answer(&mut node.lock().unwrap(), &method, &query, &body))
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let endpoint = Url::parse(&format!("http://{}", server.local_addr())).unwrap();
+        tokio::spawn(server);
+        FakeKubo { endpoint, node }
+    }
+
+    #[tokio::test]
+    async fn puts_keep_wnfs_cids_and_pin_them() {
+        let kubo = fake_kubo();
+        let mut store = KuboBlockStore::new(kubo.endpoint.clone(), true);
+        let cid = store.put_block(b"\xa0".to_vec(), IpldCodec::DagCbor).await.unwrap();
+        assert_eq!(cid.codec(), u64::from(IpldCodec::DagCbor));
+        assert_eq!(store.get_block(&cid).await.unwrap().as_slice(), b"\xa0");
+        {
+            let node = kubo.node.lock().unwrap();
+            assert_eq!(node.calls, vec!["block/put", "pin/add", "block/get"]);
+            assert!(node.pins.contains(&cid.to_string()));
+        }
+        let listed: Vec<_> = store.list_blocks().await.unwrap().into_iter().map(|block| block.cid).collect();
+        assert_eq!(listed, vec![cid]);
+    }
+
+    #[tokio::test]
+    async fn without_pins_gc_is_refused() {
+        let kubo = fake_kubo();
+        let mut store = KuboBlockStore::new(kubo.endpoint.clone(), false);
+        let cid = store.put_block(b"block".to_vec(), IpldCodec::Raw).await.unwrap();
+        assert_eq!(store.get_block(&cid).await.unwrap().as_slice(), b"block");
+        assert!(kubo.node.lock().unwrap().pins.is_empty());
+
+        let error = store.list_blocks().await.unwrap_err();
+        assert!(error.to_string().contains("pinned"), "{}", error);
+        assert!(!kubo.node.lock().unwrap().calls.contains(&"pin/ls".to_string()));
+    }
+
+    #[tokio::test]
+    async fn gets_refuse_blocks_that_are_wrong_or_missing() {
+        let kubo = fake_kubo();
+        let mut store = KuboBlockStore::new(kubo.endpoint.clone(), true);
+        let cid = store.put_block(b"block".to_vec(), IpldCodec::Raw).await.unwrap();
+        kubo.node.lock().unwrap().blocks.insert(cid.to_string(), b"blick".to_vec());
+        let error = store.get_block(&cid).await.unwrap_err();
+        assert!(matches!(error.downcast::<BlockError>().unwrap(), BlockError::Corrupt(c) if c == cid));
+
+        let missing = Cid::new_v1(IpldCodec::Raw.into(), Code::Sha2_256.digest(b"never put"));
+        let error = store.get_block(&missing).await.unwrap_err();
+        assert!(error.to_string().contains("not found"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn deletes_blocks_it_never_pinned() {
+        let cid = Cid::new_v1(IpldCodec::Raw.into(), Code::Sha2_256.digest(b"block"));
+
+        let kubo = fake_kubo();
+        KuboBlockStore::new(kubo.endpoint, false).delete_block(&cid).await.unwrap();
+        assert_eq!(kubo.node.lock().unwrap().calls, vec!["block/rm"]);
+
+        let kubo = fake_kubo();
+        KuboBlockStore::new(kubo.endpoint, true).delete_block(&cid).await.unwrap();
+        assert_eq!(kubo.node.lock().unwrap().calls, vec!["pin/rm", "block/rm"]);
+    }
+}
@@ -1,13 +1,32 @@
-use hyper::Server;
+use hyper::{service::Service, Server};
 use s3s::service::S3ServiceBuilder;
-use std::{net::SocketAddr, sync::Arc};
+use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 
-use clap::Parser;
+use blockstores::{BlockstoreArgs, BlockstoreSpec};
+use clap::{Parser, Subcommand};
+use multipart_staging::StagingBackend;
+use multipart_uploads::MultipartMode;
 
 mod banyan_s3_auth;
+mod blockstore_gc;
+mod blockstores;
+mod bucket_registry;
+mod car;
+mod compressed_blockstore;
+mod fs_blockstore;
+mod gcs_blockstore;
+mod kubo_blockstore;
+mod multipart_staging;
+mod multipart_sweeper;
 #[macro_use]
 mod multipart_uploads;
-//mod mutex_memory_blockstore;
+mod mutex_memory_blockstore;
+mod replicated_blockstore;
+mod s3_blockstore;
+mod scrub;
+mod tiered_blockstore;
+mod unixfs;
+mod wnfs_buckets;
 mod wnfs_s3_service;
 
 /// start banyan s3 service
@@ -21,33 +40,511 @@ struct Args {
     /// Key endpoint for WNFS decryption keys
     #[arg(long)]
     key_endpoint: String,
+
+    /// Registry endpoint for the WNFS roots of buckets, or `memory` to keep them in memory, where they are lost on restart
+    #[arg(long)]
+    registry_endpoint: String,
+
+    #[command(flatten)]
+    blockstore: BlockstoreArgs,
+
+    /// Whether the parts of multipart uploads are staged, or go straight into WNFS
+    #[arg(long, value_enum, default_value_t = MultipartMode::Staged)]
+    multipart_mode: MultipartMode,
+
+    /// Where to stage the parts of multipart uploads until they're completed
+    #[arg(long, value_enum, default_value_t = StagingBackend::Gcs)]
+    multipart_staging: StagingBackend,
+
+    /// Cloud storage bucket for multipart staging, when staging in gcs
+    #[arg(long, default_value = "multipart_uploads")]
+    multipart_staging_bucket: String,
+
+    /// Directory for multipart staging, when staging locally
+    #[arg(long)]
+    multipart_staging_dir: Option<PathBuf>,
+
+    /// How often to sweep abandoned multipart uploads, in seconds
+    #[arg(long, default_value_t = 60 * 60)]
+    multipart_sweep_interval_seconds: u64,
+
+    /// How long a multipart upload can go uncompleted before it's abandoned, in seconds,
+    /// unless the bucket's lifecycle configuration says otherwise
+    #[arg(long, default_value_t = 60 * 60 * 24 * 7)]
+    multipart_expiry_seconds: i64,
+
+    /// Largest part a multipart upload can have, in bytes
+    #[arg(long, default_value_t = 5 * 1024 * 1024 * 1024)]
+    multipart_max_part_size: u64,
+
+    /// Largest object a multipart upload can make, in bytes
+    #[arg(long, default_value_t = 5 * 1024 * 1024 * 1024 * 1024)]
+    multipart_max_object_size: u64,
+
+    /// How often to garbage collect unreferenced blocks, in seconds. Unset means never.
+    /// Every replica can set this; only the one holding the gc lease collects.
+    #[arg(long)]
+    gc_interval_seconds: Option<u64>,
+
+    /// How long old bucket roots are kept alive by garbage collection, in seconds
+    #[arg(long, default_value_t = 60 * 60 * 24 * 7)]
+    gc_retention_seconds: i64,
+
+    /// How old an unreferenced block has to be before garbage collection deletes it, in seconds.
+    /// Blockstores that can't say when blocks were written, like memory and kubo, are only collected with 0
+    #[arg(long, default_value_t = 60 * 60 * 24)]
+    gc_grace_seconds: i64,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Garbage collect unreferenced blocks once, print a report and exit
+    Gc {
+        /// Report what would be deleted without deleting it
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Read every object through, checking each block against its CID, print a report and exit.
+    /// Exits with an error if any bad blocks were left unrepaired.
+    Scrub {
+        /// Only check this bucket
+        #[arg(long)]
+        bucket: Option<String>,
+
+        /// Another blockstore to copy good blocks from, as <kind>:<where>, e.g. fs:/mnt/backup/blocks.
+        /// Settings that don't fit in that, like credentials, are taken from the blockstore's arguments.
+        #[arg(long)]
+        repair_from: Option<BlockstoreSpec>,
+    },
+    /// Write every block of a bucket, or of the objects under a prefix, to a CAR file and exit
+    Export {
+        /// The bucket to export
+        #[arg(long)]
+        bucket: String,
+
+        /// Only export the objects under this directory, with it taken off their keys.
+        /// From a private bucket, they're copied into a tree of their own first, with keys of its own.
+        /// From a public one, the directory is exported as it is.
+        #[arg(long)]
+        prefix: Option<String>,
+
+        /// Where to write the CAR
+        #[arg(long)]
+        output: PathBuf,
+
+        /// Where to write the root of what was exported, as JSON.
+        /// For a private bucket it holds the key to everything in the CAR, so keep it somewhere safe.
+        #[arg(long)]
+        root_output: PathBuf,
+
+        /// Which version of the CAR format to write
+        #[arg(long, value_enum, default_value_t = car::CarVersion::V1)]
+        car_version: car::CarVersion,
+    },
+    /// Load every block of a CAR file into the blockstore, checking each one, and make a bucket out of it
+    Import {
+        /// The CAR to import, v1 or v2
+        #[arg(long)]
+        input: PathBuf,
+
+        /// The root of the tree in the CAR, as JSON, like export writes
+        #[arg(long)]
+        root_input: PathBuf,
+
+        /// The bucket to make. With a prefix, an existing bucket to graft the CAR's objects into.
+        #[arg(long)]
+        bucket: String,
+
+        /// Copy the CAR's objects into the bucket under this directory, instead of making a new bucket
+        #[arg(long)]
+        prefix: Option<String>,
+
+        /// Who to record the change under in the bucket's commit log
+        #[arg(long, default_value = "import")]
+        actor: String,
+    },
+    /// Print every root a bucket has had, oldest first, with when and by whom, and exit
+    History {
+        /// The bucket to look at
+        #[arg(long)]
+        bucket: String,
+    },
+    /// Point a bucket back at the root it had at a point in time, or fork that root into a new bucket, and exit
+    Restore {
+        /// The bucket to restore
+        #[arg(long)]
+        bucket: String,
+
+        /// The time to go back to, e.g. 2023-05-01T12:00:00Z. The bucket gets the last root it had at or before then.
+        #[arg(long)]
+        at: chrono::DateTime<chrono::Utc>,
+
+        /// Make a new bucket out of the old root instead, leaving the bucket as it is
+        #[arg(long)]
+        fork_into: Option<String>,
+
+        /// Who to record the change under in the bucket's commit log
+        #[arg(long, default_value = "restore")]
+        actor: String,
+    },
+    /// Pin a bucket's root under a name, so it can be read through S3 as the bucket <bucket>--snap--<name>, and exit
+    CreateSnapshot {
+        /// The bucket to snapshot
+        #[arg(long)]
+        bucket: String,
+
+        /// What to call the snapshot
+        #[arg(long)]
+        name: String,
+
+        /// Snapshot the root the bucket had at this time, e.g. 2023-05-01T12:00:00Z, instead of the one it has now
+        #[arg(long)]
+        at: Option<chrono::DateTime<chrono::Utc>>,
+
+        /// Who to record as having made the snapshot
+        #[arg(long, default_value = "snapshot")]
+        actor: String,
+    },
+    /// Print a bucket's snapshots, or every bucket's, oldest first, and exit
+    ListSnapshots {
+        /// Only list this bucket's snapshots
+        #[arg(long)]
+        bucket: Option<String>,
+    },
+    /// Unpin a snapshot, so gc can clean up whatever only it was keeping, and exit
+    DeleteSnapshot {
+        /// The bucket the snapshot is of
+        #[arg(long)]
+        bucket: String,
+
+        /// The snapshot to delete
+        #[arg(long)]
+        name: String,
+    },
+}
+
+/// connects to the bucket registry, or exits
+async fn connect_registry(args: &Args) -> Arc<bucket_registry::BucketRegistry> {
+    Arc::new(bucket_registry::BucketRegistry::new(args.registry_endpoint.clone())
+        .await
+        .map_err(|e| anyhow::anyhow!("couldn't connect to bucket registry: {}", e))
+        .unwrap())
+}
+
+/// opens the blockstore, or exits
+async fn open_blockstore(args: &Args) -> blockstores::AnyBlockStore {
+    blockstores::blockstore_from_config(&args.blockstore)
+        .await
+        .map_err(|e| anyhow::anyhow!("couldn't set up blockstore: {}", e))
+        .unwrap()
+}
+
+/// sets up multipart uploads over their staging store, or exits
+async fn set_up_multipart(
+    args: &Args,
+    wnfs_buckets: Arc<wnfs_buckets::WnfsBuckets>,
+) -> Arc<multipart_uploads::CloudStorageForMultipartConstruction> {
+    let staging_store = multipart_staging::staging_store_from_config(
+        args.multipart_staging,
+        args.multipart_staging_bucket.clone(),
+        args.multipart_staging_dir.clone(),
+    )
+    .await
+    .map_err(|e| anyhow::anyhow!("couldn't set up multipart staging: {}", e))
+    .unwrap();
+    Arc::new(
+        multipart_uploads::CloudStorageForMultipartConstruction::new(
+            staging_store,
+            wnfs_buckets,
+            args.multipart_mode,
+            multipart_uploads::MultipartLimits {
+                max_part_size: args.multipart_max_part_size,
+                max_object_size: args.multipart_max_object_size,
+            },
+        ),
+    )
+}
+
+fn gc_config(args: &Args) -> blockstore_gc::GcConfig {
+    blockstore_gc::GcConfig {
+        retention: chrono::Duration::seconds(args.gc_retention_seconds),
+        grace: chrono::Duration::seconds(args.gc_grace_seconds),
+        dry_run: false,
+    }
+}
+
+/// runs one of the admin commands. each only connects to what it needs, so e.g. an export doesn't need the auth database.
+async fn run_command(command: Command, args: &Args) {
+    match command {
+        Command::Gc { dry_run } => {
+            let bucket_registry = connect_registry(args).await;
+            let blockstore = open_blockstore(args).await;
+            let wnfs_buckets = Arc::new(wnfs_buckets::WnfsBuckets::new(
+                bucket_registry.clone(),
+                blockstore.clone(),
+            ));
+            // live uploads' segments are only known to the staging store, and gc mustn't take them
+            let multipart_cloud_storage = set_up_multipart(args, wnfs_buckets).await;
+            let report = blockstore_gc::run_gc(
+                blockstore,
+                &bucket_registry,
+                &multipart_cloud_storage,
+                &blockstore_gc::GcConfig {
+                    dry_run,
+                    ..gc_config(args)
+                },
+            )
+            .await
+            .map_err(|e| anyhow::anyhow!("gc failed: {}", e))
+            .unwrap();
+            println!("{}", report);
+        }
+        Command::Scrub {
+            bucket,
+            repair_from,
+        } => {
+            let secondary = match repair_from {
+                Some(spec) => Some(
+                    spec.open(&args.blockstore)
+                        .await
+                        .map_err(|e| anyhow::anyhow!("couldn't set up blockstore to repair from: {}", e))
+                        .unwrap(),
+                ),
+                None => None,
+            };
+            let report = scrub::run_scrub(
+                open_blockstore(args).await,
+                secondary,
+                &*connect_registry(args).await,
+                bucket,
+            )
+            .await
+            .map_err(|e| anyhow::anyhow!("scrub failed: {}", e))
+            .unwrap();
+            print!("{}", report);
+            if report.unrepaired() > 0 {
+                std::process::exit(1);
+            }
+        }
+        Command::Export {
+            bucket,
+            prefix,
+            output,
+            root_output,
+            car_version,
+        } => {
+            let report = car::export_bucket(
+                open_blockstore(args).await,
+                &*connect_registry(args).await,
+                &bucket,
+                prefix,
+                output,
+                car_version,
+            )
+            .await
+            .map_err(|e| anyhow::anyhow!("export failed: {}", e))
+            .unwrap();
+            tokio::fs::write(&root_output, serde_json::to_vec_pretty(&report.root).unwrap())
+                .await
+                .map_err(|e| anyhow::anyhow!("couldn't write the exported root: {}", e))
+                .unwrap();
+            println!("{}", report);
+        }
+        Command::Import {
+            input,
+            root_input,
+            bucket,
+            prefix,
+            actor,
+        } => {
+            let root = tokio::fs::read(&root_input)
+                .await
+                .map_err(anyhow::Error::from)
+                .and_then(|root| Ok(serde_json::from_slice(&root)?))
+                .map_err(|e| anyhow::anyhow!("couldn't read the root to import: {}", e))
+                .unwrap();
+            let report = car::import_car(
+                open_blockstore(args).await,
+                &*connect_registry(args).await,
+                input,
+                root,
+                &bucket,
+                prefix,
+                &actor,
+            )
+            .await
+            .map_err(|e| anyhow::anyhow!("import failed: {}", e))
+            .unwrap();
+            println!("{}", report);
+        }
+        Command::History { bucket } => {
+            let commits = connect_registry(args)
+                .await
+                .list_bucket_commits(&bucket)
+                .await
+                .map_err(|e| anyhow::anyhow!("couldn't list commits of {}: {:?}", bucket, e))
+                .unwrap();
+            for commit in commits {
+                println!(
+                    "{} {} {}",
+                    commit.committed_at.to_rfc3339(),
+                    commit.root.root_cid(),
+                    commit.actor
+                );
+            }
+        }
+        Command::Restore {
+            bucket,
+            at,
+            fork_into,
+            actor,
+        } => {
+            let wnfs_buckets =
+                wnfs_buckets::WnfsBuckets::new(connect_registry(args).await, open_blockstore(args).await);
+            let commit = wnfs_buckets
+                .restore_bucket(&bucket, at, fork_into.as_deref(), &actor)
+                .await
+                .map_err(|e| anyhow::anyhow!("restore failed: {:?}", e))
+                .unwrap();
+            println!(
+                "{} is now at the root {} had at {}: {}",
+                fork_into.as_deref().unwrap_or(&bucket),
+                bucket,
+                commit.committed_at.to_rfc3339(),
+                commit.root.root_cid()
+            );
+        }
+        Command::CreateSnapshot {
+            bucket,
+            name,
+            at,
+            actor,
+        } => {
+            let wnfs_buckets =
+                wnfs_buckets::WnfsBuckets::new(connect_registry(args).await, open_blockstore(args).await);
+            let snapshot = wnfs_buckets
+                .create_snapshot(&bucket, &name, at, &actor)
+                .await
+                .map_err(|e| anyhow::anyhow!("couldn't create snapshot: {:?}", e))
+                .unwrap();
+            println!(
+                "{} is {}",
+                bucket_registry::snapshot_id(&snapshot.bucket, &snapshot.name),
+                snapshot.root.root_cid()
+            );
+        }
+        Command::ListSnapshots { bucket } => {
+            let snapshots = connect_registry(args)
+                .await
+                .list_snapshots(bucket.as_deref())
+                .await
+                .map_err(|e| anyhow::anyhow!("couldn't list snapshots: {:?}", e))
+                .unwrap();
+            for snapshot in snapshots {
+                println!(
+                    "{} {} {} {}",
+                    bucket_registry::snapshot_id(&snapshot.bucket, &snapshot.name),
+                    snapshot.created_at.to_rfc3339(),
+                    snapshot.root.root_cid(),
+                    snapshot.actor
+                );
+            }
+        }
+        Command::DeleteSnapshot { bucket, name } => {
+            let deleted = connect_registry(args)
+                .await
+                .delete_snapshot(&bucket, &name)
+                .await
+                .map_err(|e| anyhow::anyhow!("couldn't delete snapshot: {:?}", e))
+                .unwrap();
+            if !deleted {
+                eprintln!("{} has no snapshot called {}", bucket, name);
+                std::process::exit(1);
+            }
+        }
+    }
 }
 
 // TODO add logging
 #[tokio::main]
 async fn main() {
-    let args = Args::parse();
+    let mut args = Args::parse();
+    if let Some(command) = args.command.take() {
+        run_command(command, &args).await;
+        return;
+    }
 
     // Construct our SocketAddr to listen on...
     let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
 
     let s3_service = {
         let banyan_s3_auth =
-            Arc::new(banyan_s3_auth::BanyanS3Auth::new(args.auth_endpoint, args.key_endpoint)
+            Arc::new(banyan_s3_auth::BanyanS3Auth::new(args.auth_endpoint.clone(), args.key_endpoint.clone())
                 .await
                 .map_err(|e| anyhow::anyhow!("couldn't connect to auth database: {}", e))
                 .unwrap());
 
-        let wnfs_s3_service = wnfs_s3_service::WnfsS3Service::new(banyan_s3_auth.clone()).await;
+        let bucket_registry = connect_registry(&args).await;
+        let wnfs_buckets = Arc::new(wnfs_buckets::WnfsBuckets::new(
+            bucket_registry.clone(),
+            open_blockstore(&args).await,
+        ));
+        let multipart_cloud_storage = set_up_multipart(&args, wnfs_buckets.clone()).await;
+
+        if let Some(gc_interval_seconds) = args.gc_interval_seconds {
+            blockstore_gc::spawn_gc(
+                wnfs_buckets.blockstore(),
+                bucket_registry.clone(),
+                multipart_cloud_storage.clone(),
+                Duration::from_secs(gc_interval_seconds),
+                gc_config(&args),
+            );
+        }
+
+        multipart_sweeper::spawn_sweeper(
+            multipart_cloud_storage.clone(),
+            bucket_registry.clone(),
+            multipart_sweeper::SweepConfig {
+                interval: Duration::from_secs(args.multipart_sweep_interval_seconds),
+                default_expiry: chrono::Duration::seconds(args.multipart_expiry_seconds),
+            },
+        );
+
+        let wnfs_s3_service = wnfs_s3_service::WnfsS3Service::new(
+            banyan_s3_auth.clone(),
+            bucket_registry,
+            wnfs_buckets,
+            multipart_cloud_storage,
+        );
 
         let mut service_builder = S3ServiceBuilder::new(wnfs_s3_service);
-        service_builder.set_auth(banyan_s3_auth.as_ref());
+        // the server runs until the process exits, so the auth it's handed can live that long too
+        let static_auth: &'static banyan_s3_auth::BanyanS3Auth = Box::leak(Box::new(banyan_s3_auth));
+        service_builder.set_auth(static_auth);
         // service_builder.set_base_domain("localhost:3000"); ???
         service_builder.build()
     };
 
     // Then bind and serve...
-    let server = Server::bind(&addr).serve(s3_service.into_shared().into_make_service());
+    let s3_service = s3_service.into_shared();
+    let make_service = hyper::service::make_service_fn(move |_| {
+        let s3_service = s3_service.clone();
+        async move {
+            Ok::<_, std::convert::Infallible>(hyper::service::service_fn(move |request| {
+                let mut s3_service = s3_service.clone();
+                async move {
+                    s3_service
+                        .call(request)
+                        .await
+                        .map(wnfs_s3_service::partial_content)
+                }
+            }))
+        }
+    });
+    let server = Server::bind(&addr).serve(make_service);
 
     // And run forever...
     if let Err(e) = server.await {
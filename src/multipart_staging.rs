@@ -0,0 +1,698 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Mutex,
+    },
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{stream::BoxStream, Stream, StreamExt, TryStreamExt};
+use google_cloud_default::WithAuthExt;
+use google_cloud_storage::{
+    client::{Client, ClientConfig},
+    http::{
+        error::ErrorResponse,
+        objects::{
+            delete::DeleteObjectRequest,
+            get::GetObjectRequest,
+            list::ListObjectsRequest,
+            upload::{Media, UploadObjectRequest, UploadType},
+        },
+        Error as GcsError,
+    },
+};
+use tokio::io::AsyncWriteExt;
+
+/// the bytes of something being put into staging
+pub type StagingBody = Pin<Box<dyn Stream<Item = std::io::Result<Bytes>> + Send + Sync>>;
+
+/// one page of a listing. works like a cloud storage listing with "/" as the delimiter:
+/// `items` are the objects directly under the prefix, `prefixes` are the "folders" under it (ending in "/").
+#[derive(Debug, Default)]
+pub struct StagingListing {
+    pub items: Vec<String>,
+    pub prefixes: Vec<String>,
+    pub next_page_token: Option<String>,
+}
+
+/// somewhere to keep the parts of multipart uploads until they're completed.
+/// paths are "/"-separated, and a "folder" exists exactly as long as something is in it.
+#[async_trait]
+pub trait StagingStore: Send + Sync {
+    /// writes an object, replacing whatever was there
+    async fn put(&self, path: &str, body: StagingBody) -> Result<()>;
+
+    /// reads a whole object. returns Ok(None) if it isn't there.
+    async fn get(&self, path: &str) -> Result<Option<Vec<u8>>>;
+
+    /// streams an object
+    async fn get_stream(&self, path: &str) -> Result<BoxStream<'static, Result<Bytes>>>;
+
+    /// lists one page of what's directly under `prefix`
+    async fn list(&self, prefix: &str, page_token: Option<String>) -> Result<StagingListing>;
+
+    /// deletes an object
+    async fn delete(&self, path: &str) -> Result<()>;
+
+    /// reads a whole object along with its generation. returns Ok(None) if it isn't there.
+    /// the generation changes every time the object is written.
+    async fn get_with_generation(&self, path: &str) -> Result<Option<(Vec<u8>, i64)>>;
+
+    /// writes an object, but only if it's still at `generation` (0 meaning it doesn't exist yet).
+    /// returns Ok(false) without writing anything if somebody else wrote it first.
+    async fn put_if_generation(&self, path: &str, bytes: Vec<u8>, generation: i64)
+        -> Result<bool>;
+
+    /// cleans up after writes that died halfway, if they leave anything behind, once it's older than `max_age`.
+    /// most stores write in one go, so there's nothing to do.
+    async fn sweep_leftovers(&self, _max_age: chrono::Duration) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// turns some bytes we already have into a body
+pub fn body_from_bytes(bytes: impl Into<Bytes>) -> StagingBody {
+    let bytes = bytes.into();
+    Box::pin(futures::stream::once(async move { Ok(bytes) }))
+}
+
+/// which kind of staging store to run with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum StagingBackend {
+    /// a google cloud storage bucket
+    Gcs,
+    /// a directory on local disk
+    Local,
+    /// memory. everything is lost on restart!
+    Memory,
+}
+
+pub async fn staging_store_from_config(
+    backend: StagingBackend,
+    gcs_bucket: String,
+    local_dir: Option<PathBuf>,
+) -> Result<Box<dyn StagingStore>> {
+    Ok(match backend {
+        StagingBackend::Gcs => Box::new(GcsStagingStore::new(gcs_bucket).await?),
+        StagingBackend::Local => Box::new(LocalStagingStore::new(local_dir.ok_or_else(
+            || anyhow::anyhow!("local multipart staging needs a staging directory"),
+        )?)?),
+        StagingBackend::Memory => Box::new(MemoryStagingStore::default()),
+    })
+}
+
+/// staging in a google cloud storage bucket
+pub struct GcsStagingStore {
+    client: Client,
+    bucket: String,
+}
+
+impl GcsStagingStore {
+    pub async fn new(bucket: String) -> Result<Self> {
+        let config = ClientConfig::default().with_auth().await?;
+        let client = Client::new(config);
+
+        Ok(Self { client, bucket })
+    }
+}
+
+#[async_trait]
+impl StagingStore for GcsStagingStore {
+    async fn put(&self, path: &str, body: StagingBody) -> Result<()> {
+        let upload_type = UploadType::Simple(Media::new(path.to_string()));
+        self.client
+            .upload_object(
+                &UploadObjectRequest {
+                    bucket: self.bucket.clone(),
+                    ..Default::default()
+                },
+                reqwest::Body::wrap_stream(body),
+                &upload_type,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn get(&self, path: &str) -> Result<Option<Vec<u8>>> {
+        let get_object_req = GetObjectRequest {
+            bucket: self.bucket.clone(),
+            object: path.to_string(),
+            ..Default::default()
+        };
+        match self
+            .client
+            .download_object(&get_object_req, &Default::default())
+            .await
+        {
+            Ok(body) => Ok(Some(body)),
+            Err(GcsError::Response(ErrorResponse { code: 404, .. })) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn get_stream(&self, path: &str) -> Result<BoxStream<'static, Result<Bytes>>> {
+        let get_object_req = GetObjectRequest {
+            bucket: self.bucket.clone(),
+            object: path.to_string(),
+            ..Default::default()
+        };
+        let stream = self
+            .client
+            .download_streamed_object(&get_object_req, &Default::default())
+            .await?;
+        Ok(stream.map_err(|e| anyhow::anyhow!(e)).boxed())
+    }
+
+    async fn list(&self, prefix: &str, page_token: Option<String>) -> Result<StagingListing> {
+        let list_object_req = ListObjectsRequest {
+            bucket: self.bucket.clone(),
+            delimiter: Some("/".to_string()),
+            prefix: Some(prefix.to_string()),
+            page_token,
+            ..Default::default()
+        };
+        let list_object_resp = self.client.list_objects(&list_object_req).await?;
+        Ok(StagingListing {
+            items: list_object_resp
+                .items
+                .unwrap_or_default()
+                .into_iter()
+                .map(|item| item.name)
+                .collect(),
+            prefixes: list_object_resp.prefixes.unwrap_or_default(),
+            next_page_token: list_object_resp.next_page_token,
+        })
+    }
+
+    async fn delete(&self, path: &str) -> Result<()> {
+        let delete_request = DeleteObjectRequest {
+            bucket: self.bucket.clone(),
+            object: path.to_string(),
+            ..Default::default()
+        };
+        self.client.delete_object(&delete_request).await?;
+        Ok(())
+    }
+
+    async fn get_with_generation(&self, path: &str) -> Result<Option<(Vec<u8>, i64)>> {
+        let get_object_req = GetObjectRequest {
+            bucket: self.bucket.clone(),
+            object: path.to_string(),
+            ..Default::default()
+        };
+        let object = match self.client.get_object(&get_object_req).await {
+            Ok(object) => object,
+            Err(GcsError::Response(ErrorResponse { code: 404, .. })) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        // pin the download to the generation we just saw, so the bytes and generation agree
+        let get_object_req = GetObjectRequest {
+            generation: Some(object.generation),
+            ..get_object_req
+        };
+        match self
+            .client
+            .download_object(&get_object_req, &Default::default())
+            .await
+        {
+            Ok(body) => Ok(Some((body, object.generation))),
+            Err(GcsError::Response(ErrorResponse { code: 404, .. })) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn put_if_generation(&self, path: &str, bytes: Vec<u8>, generation: i64) -> Result<bool> {
+        let upload_type = UploadType::Simple(Media::new(path.to_string()));
+        match self
+            .client
+            .upload_object(
+                &UploadObjectRequest {
+                    bucket: self.bucket.clone(),
+                    if_generation_match: Some(generation),
+                    ..Default::default()
+                },
+                bytes,
+                &upload_type,
+            )
+            .await
+        {
+            Ok(_) => Ok(true),
+            // precondition failed: somebody else wrote it
+            Err(GcsError::Response(ErrorResponse { code: 412, .. })) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// staging in a directory on local disk. meant for laptops and CI.
+/// files are written to a dotfile next to their destination and renamed into place,
+/// so a reader never sees half a part. dotfiles are never listed, and ones left behind by a write that died are swept.
+/// every file's generation is kept in a dotfile of its own, since mtimes can be too coarse to tell two writes apart.
+/// conditional writes are only atomic within this process, so don't point two servers at the same directory.
+pub struct LocalStagingStore {
+    root: PathBuf,
+    /// held while a file and its generation are changed, so they're only ever seen together
+    conditional_write_lock: tokio::sync::Mutex<()>,
+    /// starts at the time we started, in nanoseconds, so a file deleted and written again,
+    /// even by an earlier run, never gets a generation it had before
+    last_generation: AtomicI64,
+    /// how many entries a page of a listing has at most
+    page_size: usize,
+}
+
+impl LocalStagingStore {
+    pub fn new(root: PathBuf) -> Result<Self> {
+        std::fs::create_dir_all(&root)?;
+        let started = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?;
+        Ok(Self {
+            root,
+            conditional_write_lock: Default::default(),
+            last_generation: AtomicI64::new(started.as_nanos() as i64),
+            // the same as GCS's
+            page_size: 1000,
+        })
+    }
+
+    /// the dotfile next to a file that has its generation in it
+    fn generation_path(path: &Path) -> PathBuf {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        path.with_file_name(format!(".{}.generation", name))
+    }
+
+    /// the generation of a file on disk, or None if there's no file
+    async fn generation_of(path: &Path) -> Result<Option<i64>> {
+        match tokio::fs::metadata(path).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let generation = tokio::fs::read_to_string(Self::generation_path(path)).await?;
+        Ok(Some(generation.trim().parse()?))
+    }
+
+    /// writes a body to a dotfile next to where it's going, and returns the dotfile's path
+    async fn write_temp(&self, destination: &Path, mut body: StagingBody) -> Result<PathBuf> {
+        let parent = destination
+            .parent()
+            .ok_or_else(|| anyhow::anyhow!("can't put to the staging root"))?;
+        tokio::fs::create_dir_all(parent).await?;
+        let temp = parent.join(format!(".{}.partial", uuid::Uuid::new_v4()));
+        let mut file = tokio::fs::File::create(&temp).await?;
+        let written: Result<()> = async {
+            while let Some(chunk) = body.next().await {
+                file.write_all(&chunk?).await?;
+            }
+            file.sync_all().await?;
+            Ok(())
+        }
+        .await;
+        if let Err(e) = written {
+            let _ = tokio::fs::remove_file(&temp).await;
+            return Err(e);
+        }
+        Ok(temp)
+    }
+
+    /// moves `from` to `destination` under a new generation. the caller holds the conditional write lock.
+    /// the generation goes first: if we die in between, the old file has a generation nobody saw it at,
+    /// so a conditional write against it fails, which is the safe way round.
+    async fn move_into_place(&self, from: &Path, destination: &Path) -> Result<()> {
+        let generation = self.last_generation.fetch_add(1, Ordering::SeqCst) + 1;
+        tokio::fs::write(Self::generation_path(destination), generation.to_string()).await?;
+        tokio::fs::rename(from, destination).await?;
+        Ok(())
+    }
+
+    fn full_path(&self, path: &str) -> PathBuf {
+        self.root.join(path)
+    }
+
+    /// removes empty directories from `dir` up to (not including) the root,
+    /// so that emptied "folders" stop showing up in listings
+    async fn prune_empty_dirs(&self, mut dir: &Path) {
+        while dir != self.root && dir.starts_with(&self.root) {
+            // fails if the directory isn't empty, which is where we stop
+            if tokio::fs::remove_dir(dir).await.is_err() {
+                break;
+            }
+            match dir.parent() {
+                Some(parent) => dir = parent,
+                None => break,
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl StagingStore for LocalStagingStore {
+    async fn put(&self, path: &str, body: StagingBody) -> Result<()> {
+        let destination = self.full_path(path);
+        let temp = self.write_temp(&destination, body).await?;
+        let _guard = self.conditional_write_lock.lock().await;
+        self.move_into_place(&temp, &destination).await
+    }
+
+    async fn get(&self, path: &str) -> Result<Option<Vec<u8>>> {
+        match tokio::fs::read(self.full_path(path)).await {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn get_stream(&self, path: &str) -> Result<BoxStream<'static, Result<Bytes>>> {
+        let file = tokio::fs::File::open(self.full_path(path)).await?;
+        Ok(tokio_util::io::ReaderStream::new(file)
+            .map_err(|e| anyhow::anyhow!(e))
+            .boxed())
+    }
+
+    /// the page token is the last name on the previous page, since the directory is read in name order
+    async fn list(&self, prefix: &str, page_token: Option<String>) -> Result<StagingListing> {
+        // the prefix is a directory plus the start of a name in it
+        let (dir, name_prefix) = match prefix.rsplit_once('/') {
+            Some((dir, name_prefix)) => (format!("{}/", dir), name_prefix),
+            None => (String::new(), prefix),
+        };
+        let mut listing = StagingListing::default();
+        let mut entries = match tokio::fs::read_dir(self.full_path(&dir)).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(listing),
+            Err(e) => return Err(e.into()),
+        };
+        let mut names = BTreeMap::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with('.') || !name.starts_with(name_prefix) {
+                continue;
+            }
+            if page_token.as_ref().is_some_and(|last| name <= *last) {
+                continue;
+            }
+            let is_dir = entry.file_type().await?.is_dir();
+            names.insert(name, is_dir);
+        }
+        if names.len() > self.page_size {
+            listing.next_page_token = names.keys().nth(self.page_size - 1).cloned();
+        }
+        for (name, is_dir) in names.into_iter().take(self.page_size) {
+            if is_dir {
+                listing.prefixes.push(format!("{}{}/", dir, name));
+            } else {
+                listing.items.push(format!("{}{}", dir, name));
+            }
+        }
+        Ok(listing)
+    }
+
+    async fn delete(&self, path: &str) -> Result<()> {
+        let full_path = self.full_path(path);
+        {
+            let _guard = self.conditional_write_lock.lock().await;
+            for file in [full_path.clone(), Self::generation_path(&full_path)] {
+                match tokio::fs::remove_file(&file).await {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+        if let Some(parent) = full_path.parent() {
+            self.prune_empty_dirs(parent).await;
+        }
+        Ok(())
+    }
+
+    async fn get_with_generation(&self, path: &str) -> Result<Option<(Vec<u8>, i64)>> {
+        let _guard = self.conditional_write_lock.lock().await;
+        let Some(generation) = Self::generation_of(&self.full_path(path)).await? else {
+            return Ok(None);
+        };
+        Ok(self.get(path).await?.map(|bytes| (bytes, generation)))
+    }
+
+    async fn put_if_generation(&self, path: &str, bytes: Vec<u8>, generation: i64) -> Result<bool> {
+        let destination = self.full_path(path);
+        let temp = self.write_temp(&destination, body_from_bytes(bytes)).await?;
+        let _guard = self.conditional_write_lock.lock().await;
+        let current = Self::generation_of(&destination).await?.unwrap_or(0);
+        if current != generation {
+            let _ = tokio::fs::remove_file(&temp).await;
+            return Ok(false);
+        }
+        self.move_into_place(&temp, &destination).await?;
+        Ok(true)
+    }
+
+    /// removes temp files, and generations whose file never made it into place, once they're older than `max_age`.
+    /// left alone, they'd also keep their directories from ever being pruned.
+    async fn sweep_leftovers(&self, max_age: chrono::Duration) -> Result<()> {
+        let cutoff = std::time::SystemTime::now() - max_age.to_std()?;
+        let mut dirs = vec![self.root.clone()];
+        while let Some(dir) = dirs.pop() {
+            let mut entries = match tokio::fs::read_dir(&dir).await {
+                Ok(entries) => entries,
+                // pruned while we were looking at another directory
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            let mut swept = false;
+            while let Some(entry) = entries.next_entry().await? {
+                let metadata = entry.metadata().await?;
+                if metadata.is_dir() {
+                    dirs.push(entry.path());
+                    continue;
+                }
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let leftover = match name.strip_suffix(".generation") {
+                    Some(generation) => {
+                        let file = dir.join(generation.trim_start_matches('.'));
+                        name.starts_with('.') && !tokio::fs::try_exists(file).await?
+                    }
+                    None => name.starts_with('.') && name.ends_with(".partial"),
+                };
+                if !leftover || metadata.modified()? > cutoff {
+                    continue;
+                }
+                log::info!("local staging: removing {:?}, left behind by a write that didn't finish", entry.path());
+                match tokio::fs::remove_file(entry.path()).await {
+                    Ok(()) => swept = true,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            if swept {
+                self.prune_empty_dirs(&dir).await;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// staging in memory. meant for tests.
+/// every write gets the next generation number.
+#[derive(Default)]
+pub struct MemoryStagingStore {
+    objects: Mutex<BTreeMap<String, (Bytes, i64)>>,
+    last_generation: AtomicI64,
+}
+
+impl MemoryStagingStore {
+    fn next_generation(&self) -> i64 {
+        self.last_generation.fetch_add(1, Ordering::SeqCst) + 1
+    }
+}
+
+#[async_trait]
+impl StagingStore for MemoryStagingStore {
+    async fn put(&self, path: &str, body: StagingBody) -> Result<()> {
+        let chunks: Vec<Bytes> = body.try_collect().await?;
+        let generation = self.next_generation();
+        self.objects
+            .lock()
+            .unwrap()
+            .insert(path.to_string(), (chunks.concat().into(), generation));
+        Ok(())
+    }
+
+    async fn get(&self, path: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self
+            .objects
+            .lock()
+            .unwrap()
+            .get(path)
+            .map(|(bytes, _)| bytes.to_vec()))
+    }
+
+    async fn get_stream(&self, path: &str) -> Result<BoxStream<'static, Result<Bytes>>> {
+        let bytes = self
+            .objects
+            .lock()
+            .unwrap()
+            .get(path)
+            .map(|(bytes, _)| bytes.clone())
+            .ok_or_else(|| anyhow::anyhow!("{} not found in staging", path))?;
+        Ok(futures::stream::once(async move { Ok(bytes) }).boxed())
+    }
+
+    async fn list(&self, prefix: &str, _page_token: Option<String>) -> Result<StagingListing> {
+        let objects = self.objects.lock().unwrap();
+        let mut listing = StagingListing::default();
+        for name in objects.range(prefix.to_string()..).map(|(name, _)| name) {
+            let Some(rest) = name.strip_prefix(prefix) else {
+                break;
+            };
+            match rest.find('/') {
+                Some(i) => {
+                    let folder = format!("{}{}", prefix, &rest[..=i]);
+                    // everything in a folder is adjacent, so only the last one can be a repeat
+                    if listing.prefixes.last() != Some(&folder) {
+                        listing.prefixes.push(folder);
+                    }
+                }
+                None => listing.items.push(name.clone()),
+            }
+        }
+        Ok(listing)
+    }
+
+    async fn delete(&self, path: &str) -> Result<()> {
+        self.objects.lock().unwrap().remove(path);
+        Ok(())
+    }
+
+    async fn get_with_generation(&self, path: &str) -> Result<Option<(Vec<u8>, i64)>> {
+        Ok(self
+            .objects
+            .lock()
+            .unwrap()
+            .get(path)
+            .map(|(bytes, generation)| (bytes.to_vec(), *generation)))
+    }
+
+    async fn put_if_generation(&self, path: &str, bytes: Vec<u8>, generation: i64) -> Result<bool> {
+        let mut objects = self.objects.lock().unwrap();
+        let current = objects.get(path).map_or(0, |(_, generation)| *generation);
+        if current != generation {
+            return Ok(false);
+        }
+        let next_generation = self.next_generation();
+        objects.insert(path.to_string(), (bytes.into(), next_generation));
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn local_generations_change_on_every_write() {
+        let root = std::env::temp_dir().join(format!("staging-{}", uuid::Uuid::new_v4()));
+        let store = LocalStagingStore::new(root.clone()).unwrap();
+
+        assert!(store.put_if_generation("a/manifest", b"1".to_vec(), 0).await.unwrap());
+        let (_, first) = store.get_with_generation("a/manifest").await.unwrap().unwrap();
+        // two writes in the same instant would have had the same mtime
+        assert!(store.put_if_generation("a/manifest", b"2".to_vec(), first).await.unwrap());
+        assert!(!store.put_if_generation("a/manifest", b"3".to_vec(), first).await.unwrap());
+        let (bytes, second) = store.get_with_generation("a/manifest").await.unwrap().unwrap();
+        assert_eq!(bytes, b"2");
+        assert_ne!(first, second);
+
+        // coming back after a delete doesn't bring an old generation back with it
+        store.delete("a/manifest").await.unwrap();
+        assert!(store.get_with_generation("a/manifest").await.unwrap().is_none());
+        store.put("a/manifest", body_from_bytes(&b"4"[..])).await.unwrap();
+        let (_, third) = store.get_with_generation("a/manifest").await.unwrap().unwrap();
+        assert!(third != first && third != second);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    fn age(path: &Path, age: std::time::Duration) {
+        std::fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(std::time::SystemTime::now() - age)
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn local_listings_come_in_pages() {
+        let root = std::env::temp_dir().join(format!("staging-{}", uuid::Uuid::new_v4()));
+        let mut store = LocalStagingStore::new(root.clone()).unwrap();
+        store.page_size = 2;
+        for path in ["up/a", "up/b", "up/c/part", "up/d", "up/e/part", "other"] {
+            store.put(path, body_from_bytes(&b"x"[..])).await.unwrap();
+        }
+
+        let mut pages = Vec::new();
+        let mut page_token = None;
+        loop {
+            let listing = store.list("up/", page_token).await.unwrap();
+            pages.push((listing.items, listing.prefixes));
+            page_token = listing.next_page_token;
+            if page_token.is_none() {
+                break;
+            }
+        }
+        let strings = |names: &[&str]| names.iter().map(|name| name.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            pages,
+            vec![
+                (strings(&["up/a", "up/b"]), vec![]),
+                (strings(&["up/d"]), strings(&["up/c/"])),
+                (vec![], strings(&["up/e/"])),
+            ]
+        );
+        // a prefix that's the start of a name still pages through only what it matches
+        let listing = store.list("up/", None).await.unwrap();
+        assert_eq!(listing.next_page_token.as_deref(), Some("b"));
+        let listing = store.list("up/c", None).await.unwrap();
+        assert_eq!((listing.prefixes, listing.next_page_token), (strings(&["up/c/"]), None));
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn local_leftovers_are_swept_once_theyre_old() {
+        let root = std::env::temp_dir().join(format!("staging-{}", uuid::Uuid::new_v4()));
+        let store = LocalStagingStore::new(root.clone()).unwrap();
+        store.put("up/part", body_from_bytes(&b"x"[..])).await.unwrap();
+        let dir = root.join("up");
+        // what writes that died leave behind: a temp file, and a generation for a file that never arrived
+        let old_temp = dir.join(".dead.partial");
+        let old_generation = dir.join(".gone.generation");
+        let young_temp = dir.join(".writing.partial");
+        for path in [&old_temp, &old_generation, &young_temp] {
+            std::fs::write(path, b"x").unwrap();
+        }
+        for path in [&old_temp, &old_generation, &dir.join("part"), &dir.join(".part.generation")] {
+            age(path, std::time::Duration::from_secs(60 * 60 * 2));
+        }
+
+        store.sweep_leftovers(chrono::Duration::hours(1)).await.unwrap();
+        assert!(!old_temp.exists() && !old_generation.exists());
+        assert!(young_temp.exists());
+        // finished files and their generations stay, however old
+        assert_eq!(store.get("up/part").await.unwrap().unwrap(), b"x");
+        assert!(store.get_with_generation("up/part").await.unwrap().is_some());
+
+        // leftovers keep an emptied folder around until they're swept
+        store.delete("up/part").await.unwrap();
+        assert_eq!(store.list("", None).await.unwrap().prefixes, vec!["up/".to_string()]);
+        age(&young_temp, std::time::Duration::from_secs(60 * 60 * 2));
+        store.sweep_leftovers(chrono::Duration::hours(1)).await.unwrap();
+        assert!(!dir.exists());
+        assert!(store.list("", None).await.unwrap().prefixes.is_empty());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,57 @@
+use std::{sync::Arc, time::Duration};
+
+use tokio::task::JoinHandle;
+
+use crate::{
+    bucket_registry::BucketRegistry,
+    multipart_uploads::{CloudStorageForMultipartConstruction, MULTIPART_SWEEP_LEASE},
+};
+
+/// how often to sweep, and how old an upload has to be before it's abandoned
+#[derive(Debug, Clone)]
+pub struct SweepConfig {
+    pub interval: Duration,
+    /// used for uploads to buckets without an AbortIncompleteMultipartUpload lifecycle rule
+    pub default_expiry: chrono::Duration,
+}
+
+/// spawns a task that runs the multipart cleanup sweep every `config.interval`.
+/// every replica runs one of these, but only the one holding the sweep lease actually sweeps.
+pub fn spawn_sweeper(
+    multipart_cloud_storage: Arc<CloudStorageForMultipartConstruction>,
+    registry: Arc<BucketRegistry>,
+    config: SweepConfig,
+) -> JoinHandle<()> {
+    // identifies this replica to the lease
+    let holder = uuid::Uuid::new_v4().to_string();
+    // hold the lease a little past the next tick, so the holder gets to renew it before anyone else can grab it
+    let lease_ttl = chrono::Duration::from_std(config.interval * 2)
+        .unwrap_or_else(|_| chrono::Duration::hours(1));
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(config.interval);
+        loop {
+            interval.tick().await;
+            match multipart_cloud_storage
+                .try_acquire_sweep_lease(MULTIPART_SWEEP_LEASE, &holder, lease_ttl)
+                .await
+            {
+                Ok(true) => {}
+                Ok(false) => {
+                    log::debug!("multipart sweeper: another replica holds the sweep lease. skipping.");
+                    continue;
+                }
+                Err(e) => {
+                    log::error!("multipart sweeper: couldn't acquire sweep lease. skipping. error was {}", e);
+                    continue;
+                }
+            }
+            log::info!("multipart sweeper: starting cleanup sweep");
+            if let Err(e) = multipart_cloud_storage
+                .run_cleanup_sweep(config.default_expiry, &registry)
+                .await
+            {
+                log::error!("multipart sweeper: cleanup sweep failed. error was {}", e);
+            }
+        }
+    })
+}
@@ -1,48 +1,120 @@
-use bitmaps::Bitmap;
-use chrono::{DateTime, FixedOffset};
-use futures::{stream::BoxStream, FutureExt, StreamExt, AsyncRead};
-use google_cloud_default::WithAuthExt;
-use google_cloud_storage::{
-    client::{Client, ClientConfig},
-    http::{
-        error::ErrorResponse,
-        objects::{
-            delete::DeleteObjectRequest,
-            get::GetObjectRequest,
-            list::ListObjectsRequest,
-            upload::{Media, UploadObjectRequest, UploadType},
-        },
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
     },
 };
-use s3s::{dto::StreamingBlob, s3_error, S3Result};
+
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use futures::{stream::BoxStream, Stream, StreamExt};
+use md5::{Digest, Md5};
+use s3s::{s3_error, S3Result};
+use serde::{Deserialize, Serialize};
 
 use anyhow::Result;
 
-// TODO put them in cli parameters or a config file
-const BUCKET_NAME: &str = "multipart_uploads";
-const EXPIRY_TIME_SECONDS: u64 = 60 * 60 * 24 * 7; // 7 days
+use crate::{
+    bucket_registry::{BucketLifecycle, BucketRegistry},
+    multipart_staging::{body_from_bytes, StagingStore},
+    wnfs_buckets::{CompositeObject, Segment, WnfsBuckets},
+};
+
+/// the folder the sweep lease lives in. it has no dashes, so it can't be an upload.
+const SWEEP_LEASE_FOLDER: &str = "sweep/";
+/// the lease on the multipart cleanup sweep, under SWEEP_LEASE_FOLDER
+pub const MULTIPART_SWEEP_LEASE: &str = "lease";
+/// the lease on blockstore gc, which lives with the sweep's so every replica can find it
+pub const GC_LEASE: &str = "gc-lease";
+
+/// how many times we'll retry a conditional write to a marker before giving up
+const MAX_MARKER_WRITE_ATTEMPTS: usize = 8;
+/// s3's minimum size for every part of an upload but the last
+const MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
+/// s3's part numbers go from 1 to this
+const MAX_PART_NUMBER: u32 = 10000;
+/// how many staged objects we delete at once when cleaning up
+const DELETE_CONCURRENCY: usize = 16;
+/// how long a completion gets before the upload is given up on as aborted, in case whoever was completing it died.
+/// generous, since a staged completion copies the whole object into wnfs.
+const COMPLETION_DEADLINE_HOURS: i64 = 12;
+
+/// where the bytes of parts go while an upload is in progress
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum MultipartMode {
+    /// parts go to the staging store, and get copied into wnfs when the upload is completed
+    Staged,
+    /// parts are encrypted straight into wnfs segments, and completing the upload just links them together.
+    /// only the markers and part records go to the staging store.
+    Direct,
+}
+
+/// how big parts and the objects made out of them are allowed to be
+#[derive(Debug, Clone)]
+pub struct MultipartLimits {
+    pub max_part_size: u64,
+    pub max_object_size: u64,
+}
+
+fn no_such_upload() -> s3s::S3Error {
+    s3_error!(
+        NoSuchUpload,
+        "The specified multipart upload does not exist. The upload ID might be invalid, or the multipart upload might have been aborted or completed."
+    )
+}
 
-fn transmute_result_for_s3error<T>(
-    res: Result<T, google_cloud_storage::http::Error>,
-) -> S3Result<T> {
+fn transmute_result_for_s3error<T>(res: Result<T>) -> S3Result<T> {
     res.map_err(|e| {
-        log::error!("google cloud storage error: {:?}", e);
+        log::error!("multipart staging error: {:?}", e);
         s3_error!(InternalError, "internal error")
     })
 }
 
 /// a safe string is a string that is safe to use in our little macros below
-/// ie, no slashes!
-#[derive(Debug, Clone)]
+/// ie, no slashes, and no dashes since those separate the components of an upload location.
+/// the escaping is reversible, so a staging location can always be mapped back to its bucket, key and upload id.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub(crate) struct SafeString {
     inner: String,
+    raw: String,
 }
 
 impl SafeString {
     pub(crate) fn new(ini: String) -> Self {
+        // escape the escape character first so decoding is unambiguous
         Self {
-            inner: ini.replace("/", "%2F"),
+            inner: ini
+                .replace('%', "%25")
+                .replace('/', "%2F")
+                .replace('-', "%2D"),
+            raw: ini,
+        }
+    }
+
+    /// reverses the escaping done in `SafeString::new`.
+    /// returns None if the string contains an escape sequence we never produce.
+    pub(crate) fn decode(escaped: &str) -> Option<String> {
+        let mut out = String::with_capacity(escaped.len());
+        let mut rest = escaped;
+        while let Some(i) = rest.find('%') {
+            out.push_str(&rest[..i]);
+            let code = rest.get(i + 1..i + 3)?;
+            out.push(match code {
+                "25" => '%',
+                "2F" => '/',
+                "2D" => '-',
+                _ => return None,
+            });
+            rest = &rest[i + 3..];
         }
+        out.push_str(rest);
+        Some(out)
+    }
+
+    /// the string as the client gave it to us
+    pub(crate) fn raw(&self) -> &str {
+        &self.raw
     }
 }
 
@@ -59,15 +131,14 @@ impl From<String> for SafeString {
 }
 
 /// a macro to stringbuild the directory where parts will live
-/// the directory is the bucket name, the object name, and the upload id
+/// the directory is the bucket name, the object name, and the upload id, each escaped with SafeString
+/// so that the dashes between them are the only dashes in the name.
 macro_rules! multipart_loc {
     ($bucket_name:expr, $object_name:expr, $upload_id:expr) => {
         format!("{}-{}-{}", $bucket_name, $object_name, $upload_id)
     };
 }
 
-pub(crate) use multipart_loc;
-
 macro_rules! multipart_loc_with_part {
     ($bucket_name:expr, $object_name:expr, $upload_id:expr, $part_number:expr) => {
         format!(
@@ -78,8 +149,6 @@ macro_rules! multipart_loc_with_part {
     };
 }
 
-pub(crate) use multipart_loc_with_part;
-
 macro_rules! multipart_loc_with_marker {
     ($bucket_name:expr, $object_name:expr, $upload_id:expr) => {
         format!(
@@ -89,488 +158,1311 @@ macro_rules! multipart_loc_with_marker {
     };
 }
 
-pub(crate) use multipart_loc_with_marker;
+macro_rules! multipart_loc_with_part_record {
+    ($bucket_name:expr, $object_name:expr, $upload_id:expr, $part_number:expr) => {
+        format!(
+            "{}/records/{}",
+            multipart_loc!($bucket_name, $object_name, $upload_id),
+            $part_number
+        )
+    };
+}
+
+/// splits a location built by `multipart_loc!` back into (bucket, key, upload id).
+/// accepts the location with or without a trailing slash, since that's how listings hand back prefixes.
+/// returns None if it isn't a location we could have produced.
+pub(crate) fn parse_multipart_loc(loc: &str) -> Option<(String, String, String)> {
+    let loc = loc.strip_suffix('/').unwrap_or(loc);
+    let mut components = loc.split('-');
+    let bucket_name = SafeString::decode(components.next()?)?;
+    let object_name = SafeString::decode(components.next()?)?;
+    let upload_id = SafeString::decode(components.next()?)?;
+    if components.next().is_some() {
+        return None;
+    }
+    Some((bucket_name, object_name, upload_id))
+}
+
+/// where an upload is in its life.
+/// Active -> Completing -> Completed, or Active -> Aborted. a failed completion goes back to Active,
+/// and one that's still Completing past its deadline counts as Aborted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum UploadState {
+    Active,
+    Completing,
+    Completed,
+    Aborted,
+}
+
+/// the contents of the marker file of an upload.
+/// records what the upload is for, so we never have to guess from the folder name,
+/// and where it is in its life. it's only ever rewritten with a conditional write.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct UploadManifest {
+    pub bucket: String,
+    pub key: String,
+    pub upload_id: String,
+    pub initiated: DateTime<Utc>,
+    pub state: UploadState,
+    /// the etag of the finished object, once the upload is Completed
+    pub etag: Option<String>,
+    /// when a Completing upload counts as Aborted instead
+    pub completing_until: Option<DateTime<Utc>>,
+}
+
+impl UploadManifest {
+    /// the state the upload is really in, with a completion that ran out of time counted as Aborted
+    pub fn effective_state(&self) -> UploadState {
+        match (self.state, self.completing_until) {
+            (UploadState::Completing, Some(until)) if until < Utc::now() => UploadState::Aborted,
+            (state, _) => state,
+        }
+    }
+
+    /// true if the manifest describes the upload living at `loc`
+    fn matches_loc(&self, loc: &str) -> bool {
+        parse_multipart_loc(loc).is_some_and(|(bucket, key, upload_id)| {
+            bucket == self.bucket && key == self.key && upload_id == self.upload_id
+        })
+    }
+}
+
+/// the keys we couldn't delete while cleaning up under `root`
+#[derive(Debug)]
+pub struct CleanupError {
+    pub root: String,
+    pub deleted: usize,
+    pub failed: Vec<(String, anyhow::Error)>,
+}
+
+impl std::fmt::Display for CleanupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "couldn't delete {} of {} objects under {:?}",
+            self.failed.len(),
+            self.failed.len() + self.deleted,
+            self.root
+        )?;
+        if let Some((key, error)) = self.failed.first() {
+            write!(f, ". first failure was {}: {}", key, error)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for CleanupError {}
+
+/// what we remember about a part, stored next to it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PartRecord {
+    pub etag: String,
+    pub size: u64,
+    /// where the part's bytes went, when it was uploaded in direct mode
+    pub segments: Vec<Segment>,
+    /// where the part's bytes are in staging, when it was uploaded in staged mode.
+    /// every upload of a part gets a path of its own, so bytes a record points at never change under it.
+    pub staged_path: Option<String>,
+}
+
+/// who's allowed to run the cleanup sweep, and until when
+#[derive(Debug, Serialize, Deserialize)]
+struct SweepLease {
+    holder: String,
+    expires: DateTime<Utc>,
+}
 
 /// fast and memory-efficient tracker for which parts we have when we're wrapping up an upload.
+/// part numbers start at 1, so part n is bit n - 1.
 pub struct PartTracker {
     inner: [bitmaps::Bitmap<1000>; 10],
-    largest: u8,
 }
 
 impl PartTracker {
     fn new() -> Self {
         Self {
             inner: Default::default(),
-            largest: 0,
         }
     }
 
-    fn add_part(&mut self, part_number: u8) -> Result<()> {
-        if part_number >= 10000 {
-            return Err(anyhow::anyhow!("part number too large"));
-        }
-        self.inner[part_number as usize / 1000].set(part_number as usize % 1000, true);
-        if part_number > self.largest {
-            self.largest = part_number;
+    fn add_part(&mut self, part_number: u32) -> Result<()> {
+        if !(1..=MAX_PART_NUMBER).contains(&part_number) {
+            return Err(anyhow::anyhow!("part number {} out of range", part_number));
         }
+        let i = part_number as usize - 1;
+        self.inner[i / 1000].set(i % 1000, true);
         Ok(())
     }
-    fn has_part(&self, part_number: u8) -> Result<bool> {
-        if part_number >= 10000 {
-            return Err(anyhow::anyhow!("part number too large"));
-        }
-        if part_number > self.largest {
-            return Ok(false);
-        }
-        Ok(self.inner[part_number as usize / 1000].get(part_number as usize % 1000))
-    }
-
-    /// returns true if we're filled up through the largest part number we've seen
-    /// TODO test me this is some real leetcode moment
-    fn is_complete(&self) -> bool {
-        let last_full = self.largest / 1000;
-        let scan_fulls = self.inner.iter().take(last_full.into());
-        for i in scan_fulls {
-            if !i.is_full() {
-                return false;
-            }
-        }
-        // get the last bitmap that we think has indices, and check up through the largest
-        let last: Bitmap<1000> = self.inner[(last_full + 1) as usize];
-        let should_be_next_false = (self.largest as usize) % 1000;
-        let next_false_on_last = last.next_false_index(0);
-        next_false_on_last.map_or(true, |i| i > should_be_next_false)
-    }
-}
-
-/// a reader that can read parts from a (complete) multipart upload
-struct PartsReader<'a> {
-    num_parts: u32,
-    // use flatten to map the inner stream of parts to a stream of bytes
-    // use next to map part numbers to
-    inner_stream: BoxStream<'a, Result<bytes::Bytes>>,
-}
 
-impl<'a> PartsReader<'a> {
-    async fn new(
-        num_parts: u32,
-        client: &Client,
-        bucket_name: SafeString,
-        object_name: SafeString,
-        upload_id: SafeString,
-    ) -> crate::multipart_uploads::PartsReader<'a> {
-        let inner_stream = futures::stream::iter(1..=num_parts)
-            .then(|part_number| {
-                let req = GetObjectRequest {
-                    bucket: BUCKET_NAME.to_string(),
-                    object: multipart_loc_with_part!(
-                        bucket_name,
-                        object_name,
-                        upload_id,
-                        part_number
-                    ),
-                    ..Default::default()
-                };
-                client
-                    .download_streamed_object(&req, &Default::default())
-                    .map(|res| match res {
-                        Ok(stream) => stream,
-                        Err(e) => futures::stream::once(async { Err(e) }),
-                    })
-            })
-            .flat_map(|stream| stream.map_err(|e| anyhow::anyhow!(e)));
-        Self {
-            num_parts,
-            inner_stream: Box::new(inner_stream),
+    fn has_part(&self, part_number: u32) -> bool {
+        if !(1..=MAX_PART_NUMBER).contains(&part_number) {
+            return false;
         }
+        let i = part_number as usize - 1;
+        self.inner[i / 1000].get(i % 1000)
     }
 }
 
-impl AsyncRead for PartsReader<'_> {
-    fn poll_read(
-            self: std::pin::Pin<&mut Self>,
-            cx: &mut std::task::Context<'_>,
-            buf: &mut [u8],
-        ) -> std::task::Poll<std::io::Result<usize>> {
-        todo!()
-    }
+/// the bytes of the listed parts of a (complete) multipart upload, one after the other
+fn read_parts(paths: Vec<String>, store: &dyn StagingStore) -> BoxStream<'_, Result<bytes::Bytes>> {
+    // use flatten to map the stream of parts to a stream of bytes
+    futures::stream::iter(paths)
+        .then(move |path| async move {
+            match store.get_stream(&path).await {
+                Ok(stream) => stream,
+                Err(e) => futures::stream::once(async { Err(e) }).boxed(),
+            }
+        })
+        .flatten()
+        .boxed()
 }
 
 pub struct CloudStorageForMultipartConstruction {
-    client: Client,
+    store: Box<dyn StagingStore>,
+    buckets: Arc<WnfsBuckets>,
+    mode: MultipartMode,
+    limits: MultipartLimits,
 }
 
 impl CloudStorageForMultipartConstruction {
-    pub async fn new() -> Self {
-        let config = ClientConfig::default().with_auth().await.unwrap();
-        let client = Client::new(config);
-
-        Self { client }
+    pub fn new(
+        store: Box<dyn StagingStore>,
+        buckets: Arc<WnfsBuckets>,
+        mode: MultipartMode,
+        limits: MultipartLimits,
+    ) -> Self {
+        Self {
+            store,
+            buckets,
+            mode,
+            limits,
+        }
     }
 
-    /// creates a spot to put parts of a multipart upload in a cloud storage bucket
-    /// marks the existence of the bucket with a creation time. eventually we'll use to clean up partial uploads.
+    /// creates a spot to put parts of a multipart upload in the staging store
+    /// marks the existence of the bucket with a manifest recording the bucket, key, upload id and creation time.
+    /// the creation time is what we use to clean up partial uploads.
     pub async fn create_multipart_upload_folder(
         &self,
         client_bucket_name: SafeString,
         client_object_name: SafeString,
         upload_id: SafeString,
     ) -> S3Result<()> {
-        // create a folder in the cloud storage bucket for this multipart upload
-        let upload_type = UploadType::Simple(Media::new(multipart_loc_with_marker!(
-            client_bucket_name,
-            client_object_name,
-            upload_id
-        )));
-        let datetime = chrono::Utc::now().to_rfc3339();
-        let obj = transmute_result_for_s3error(
-            self.client
-                .upload_object(
-                    &UploadObjectRequest {
-                        bucket: BUCKET_NAME.to_string(),
-                        ..Default::default()
-                    },
-                    datetime.to_string(),
-                    &upload_type,
-                )
-                .await,
-        )?;
+        // create a folder in the staging store for this multipart upload
+        let marker_path =
+            multipart_loc_with_marker!(client_bucket_name, client_object_name, upload_id);
+        let manifest = UploadManifest {
+            bucket: client_bucket_name.raw().to_string(),
+            key: client_object_name.raw().to_string(),
+            upload_id: upload_id.raw().to_string(),
+            initiated: Utc::now(),
+            state: UploadState::Active,
+            etag: None,
+            completing_until: None,
+        };
+        let manifest = serde_json::to_vec(&manifest).map_err(|e| {
+            log::error!("cloudstorage multipart: couldn't serialize manifest: {}", e);
+            s3_error!(InternalError, "internal error")
+        })?;
+        // upload ids are fresh uuids, so this only fails if something is very wrong
+        if !transmute_result_for_s3error(self.store.put_if_generation(&marker_path, manifest, 0).await)? {
+            log::error!("cloudstorage multipart: marker already exists at {}", marker_path);
+            return Err(s3_error!(InternalError, "internal error"));
+        }
         Ok(())
     }
 
-    /// checks that the upload exists in the cloud storage bucket
+    /// moves an upload to a new state with a conditional write on its marker, retrying if somebody else wrote it first.
+    /// `decide` looks at the current manifest, with its effective state, and returns the state to move to,
+    /// Ok(None) to leave it where it is, or an error to refuse.
+    /// returns the manifest as it is afterwards.
+    pub(crate) async fn transition_upload(
+        &self,
+        client_bucket_name: &SafeString,
+        client_object_name: &SafeString,
+        upload_id: &SafeString,
+        decide: impl Fn(&UploadManifest) -> S3Result<Option<UploadState>>,
+        etag: Option<String>,
+    ) -> S3Result<UploadManifest> {
+        let marker_path =
+            multipart_loc_with_marker!(client_bucket_name, client_object_name, upload_id);
+        for _ in 0..MAX_MARKER_WRITE_ATTEMPTS {
+            let Some((body, generation)) =
+                transmute_result_for_s3error(self.store.get_with_generation(&marker_path).await)?
+            else {
+                return Err(no_such_upload());
+            };
+            let mut manifest: UploadManifest = serde_json::from_slice(&body).map_err(|e| {
+                log::error!("cloudstorage multipart: corrupted marker at {}: {}", marker_path, e);
+                no_such_upload()
+            })?;
+            manifest.state = manifest.effective_state();
+            let Some(state) = decide(&manifest)? else {
+                return Ok(manifest);
+            };
+            manifest.state = state;
+            manifest.completing_until = (state == UploadState::Completing)
+                .then(|| Utc::now() + chrono::Duration::hours(COMPLETION_DEADLINE_HOURS));
+            if state == UploadState::Completed {
+                manifest.etag = etag.clone();
+            }
+            let body = transmute_result_for_s3error(
+                serde_json::to_vec(&manifest).map_err(anyhow::Error::from),
+            )?;
+            if transmute_result_for_s3error(
+                self.store
+                    .put_if_generation(&marker_path, body, generation)
+                    .await,
+            )? {
+                return Ok(manifest);
+            }
+            log::debug!("cloudstorage multipart: lost a race writing {}. retrying.", marker_path);
+        }
+        Err(s3_error!(
+            OperationAborted,
+            "A conflicting conditional operation is currently in progress against this resource. Please try again."
+        ))
+    }
+
+    /// reads the manifest of an upload, if it exists
+    async fn get_manifest(
+        &self,
+        client_bucket_name: &SafeString,
+        client_object_name: &SafeString,
+        upload_id: &SafeString,
+    ) -> S3Result<Option<UploadManifest>> {
+        transmute_result_for_s3error(
+            self.get_marker_contents(multipart_loc!(
+                client_bucket_name,
+                client_object_name,
+                upload_id
+            ))
+            .await,
+        )
+    }
+
+    /// the effective state of an upload and the generation of its marker, or None if there's no such upload
+    async fn upload_state(
+        &self,
+        client_bucket_name: &SafeString,
+        client_object_name: &SafeString,
+        upload_id: &SafeString,
+    ) -> S3Result<Option<(UploadState, i64)>> {
+        let marker_path =
+            multipart_loc_with_marker!(client_bucket_name, client_object_name, upload_id);
+        let Some((body, generation)) =
+            transmute_result_for_s3error(self.store.get_with_generation(&marker_path).await)?
+        else {
+            return Ok(None);
+        };
+        let manifest: UploadManifest = serde_json::from_slice(&body).map_err(|e| {
+            log::error!("cloudstorage multipart: corrupted marker at {}: {}", marker_path, e);
+            no_such_upload()
+        })?;
+        Ok(Some((manifest.effective_state(), generation)))
+    }
+
+    /// checks that the upload exists in the staging store and is still taking parts
     pub async fn check_upload_exists(
         &self,
         client_bucket_name: SafeString,
         client_object_name: SafeString,
         upload_id: SafeString,
     ) -> S3Result<bool> {
-        // check that the part_id exists in the cloud storage bucket- it should be at
-        let list_object_req = ListObjectsRequest {
-            bucket: BUCKET_NAME.to_string(),
-            delimiter: Some("/".to_string()),
-            prefix: Some(multipart_loc!(
-                client_bucket_name,
-                client_object_name,
-                upload_id
-            )),
-            ..Default::default()
-        };
-        let list_object_resp =
-            transmute_result_for_s3error(self.client.list_objects(&list_object_req).await)?;
-        if let Some(items) = list_object_resp.items {
-            Ok(!items.is_empty())
-        } else {
-            Ok(false)
-        }
+        let manifest = self
+            .get_manifest(&client_bucket_name, &client_object_name, &upload_id)
+            .await?;
+        Ok(manifest.is_some_and(|manifest| manifest.effective_state() == UploadState::Active))
     }
 
-    /// puts a part into the initiated spot for the upload
-    pub async fn put_upload_part(
+    /// aborts an upload and throws away its parts.
+    /// aborting an aborted upload is fine; aborting one that's completing or completed isn't.
+    pub async fn abort_upload(
         &self,
         client_bucket_name: SafeString,
         client_object_name: SafeString,
         upload_id: SafeString,
-        part_no: u32,
-        body: StreamingBlob,
     ) -> S3Result<()> {
-        // put the part in the cloud storage bucket
-        // TODO ought we check it doesn't exist yet?
-        let upload_type = UploadType::Simple(Media::new(multipart_loc_with_part!(
-            client_bucket_name,
-            client_object_name,
-            upload_id,
-            part_no
-        )));
-        let data = reqwest::Body::wrap_stream(body);
-        let _ = transmute_result_for_s3error(
-            self.client
-                .upload_object(
-                    &UploadObjectRequest {
-                        bucket: BUCKET_NAME.to_string(),
-                        ..Default::default()
-                    },
-                    data,
-                    &upload_type,
-                )
-                .await,
-        )?;
-        Ok(())
+        self.transition_upload(
+            &client_bucket_name,
+            &client_object_name,
+            &upload_id,
+            |manifest| match manifest.state {
+                UploadState::Active => Ok(Some(UploadState::Aborted)),
+                UploadState::Aborted => Ok(None),
+                UploadState::Completing => Err(s3_error!(
+                    OperationAborted,
+                    "The multipart upload is being completed"
+                )),
+                UploadState::Completed => Err(no_such_upload()),
+            },
+            None,
+        )
+        .await?;
+        self.cleanup_upload(client_bucket_name, client_object_name, upload_id)
+            .await
     }
 
-    /// deletes all the parts and the upload folder
+    /// deletes all the parts of an upload, and their records.
+    /// the marker stays until the sweep expires it, so it can keep answering for the upload id.
     pub async fn cleanup_upload(
         &self,
         client_bucket_name: SafeString,
         client_object_name: SafeString,
         upload_id: SafeString,
     ) -> S3Result<()> {
-        // list the objects in the right folder
-        let list_object_req = ListObjectsRequest {
-            bucket: BUCKET_NAME.to_string(),
-            delimiter: Some("/".to_string()),
-            prefix: Some(multipart_loc!(
-                client_bucket_name,
-                client_object_name,
-                upload_id
-            )),
-            ..Default::default()
-        };
-        let mut list_object_resp =
-            transmute_result_for_s3error(self.client.list_objects(&list_object_req).await)?;
-        // TODO test this pagination behavior as you delete stuff.
-        loop {
-            let items = list_object_resp.items.unwrap();
-            for item in items {
-                let delete_request = DeleteObjectRequest {
-                    bucket: BUCKET_NAME.to_string(),
-                    object: item.name,
-                    ..Default::default()
-                };
-                self.client.delete_object(&delete_request).await;
-            }
-            if list_object_resp.next_page_token.is_some() {
-                let list_object_req = ListObjectsRequest {
-                    bucket: BUCKET_NAME.to_string(),
-                    page_token: list_object_resp.next_page_token,
-                    ..Default::default()
-                };
-                list_object_resp =
-                    transmute_result_for_s3error(self.client.list_objects(&list_object_req).await)?;
-            } else {
-                break;
+        let root = format!(
+            "{}/",
+            multipart_loc!(client_bucket_name, client_object_name, upload_id)
+        );
+        let keys = transmute_result_for_s3error(self.list_all(&root).await)?
+            .into_iter()
+            .filter(|key| !key.ends_with("/marker"))
+            .collect();
+        self.delete_all(&root, keys).await.map_err(|e| {
+            for (key, error) in &e.failed {
+                log::error!("cloudstorage multipart: couldn't delete {}. error was {}", key, error);
             }
-        }
+            log::error!("cloudstorage multipart: {}", e);
+            s3_error!(InternalError, "internal error")
+        })?;
         Ok(())
     }
 
-    /// removes a key and all its sub-keys from the bucket
-    pub async fn rm_rf(&self, root: String) -> S3Result<()> {
-        log::info!("cloudstorage multipart: rm_rf {}", root);
-        let mut prefix_queue = vec![root];
-        while !prefix_queue.is_empty() {
-            let prefix = prefix_queue.pop().unwrap();
-            let list_object_req = ListObjectsRequest {
-                bucket: BUCKET_NAME.to_string(),
-                delimiter: Some("/".to_string()),
-                prefix: Some(prefix),
-                ..Default::default()
-            };
-            let mut list_object_resp;
-            while {
-                list_object_resp =
-                    transmute_result_for_s3error(self.client.list_objects(&list_object_req).await)?;
-
-                if let Some(prefs) = list_object_resp.prefixes {
-                    for pref in prefs {
-                        prefix_queue.push(pref);
-                    }
+    /// lists every key under `root`, however deep.
+    /// everything is listed before anything is deleted, so deletes can't shift pages out from under us.
+    async fn list_all(&self, root: &str) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        let mut prefix_queue = vec![root.to_string()];
+        while let Some(prefix) = prefix_queue.pop() {
+            let mut page_token = None;
+            loop {
+                let listing = self.store.list(&prefix, page_token).await?;
+                prefix_queue.extend(listing.prefixes);
+                keys.extend(listing.items);
+                page_token = listing.next_page_token;
+                if page_token.is_none() {
+                    break;
                 }
-                if let Some(items) = list_object_resp.items {
-                    for item in items {
-                        let delete_request = DeleteObjectRequest {
-                            bucket: BUCKET_NAME.to_string(),
-                            object: item.name,
-                            ..Default::default()
-                        };
-                        transmute_result_for_s3error(
-                            self.client.delete_object(&delete_request).await,
-                        )?;
-                    }
+            }
+        }
+        Ok(keys)
+    }
+
+    /// deletes `keys`, a few at a time. keeps going past failures, and reports all of them at the end.
+    /// returns how many keys were deleted.
+    async fn delete_all(&self, root: &str, keys: Vec<String>) -> Result<usize, CleanupError> {
+        let total = keys.len();
+        let failed: Vec<(String, anyhow::Error)> = futures::stream::iter(keys)
+            .map(|key| async move {
+                match self.store.delete(&key).await {
+                    Ok(()) => None,
+                    Err(e) => Some((key, e)),
                 }
-                list_object_resp.next_page_token.is_some()
-            } {}
+            })
+            .buffer_unordered(DELETE_CONCURRENCY)
+            .filter_map(|failure| async move { failure })
+            .collect()
+            .await;
+        if failed.is_empty() {
+            Ok(total)
+        } else {
+            Err(CleanupError {
+                root: root.to_string(),
+                deleted: total - failed.len(),
+                failed,
+            })
         }
+    }
+
+    /// removes a key and all its sub-keys from the staging store
+    pub async fn rm_rf(&self, root: String) -> Result<()> {
+        log::info!("cloudstorage multipart: rm_rf {}", root);
+        let keys = self.list_all(&root).await?;
+        self.delete_all(&root, keys).await?;
         Ok(())
     }
 
-    /// returns Ok(Some(timestamp)) if the marker is in that path and everything looks good
+    /// returns Ok(Some(manifest)) if the marker is in that path and everything looks good
     /// returns Ok(None) if the marker is not in that path
-    /// returns Ok(None) if the marker is in that path but the timestamp is not parseable
+    /// returns Ok(None) if the marker is in that path but the manifest is not parseable or doesn't match the path
     /// returns Err(blabla) if there was an error in accessing the marker
-    async fn get_marker_contents(
-        &self,
-        path_root: String,
-    ) -> Result<Option<DateTime<FixedOffset>>> {
+    async fn get_marker_contents(&self, path_root: String) -> Result<Option<UploadManifest>> {
+        let path_root = path_root.strip_suffix('/').unwrap_or(&path_root);
         let marker_path = format!("{}/{}", path_root, "marker");
-        let get_object_req = GetObjectRequest {
-            bucket: BUCKET_NAME.to_string(),
-            object: marker_path.clone(),
-            ..Default::default()
+        let Some(body) = self.store.get(&marker_path).await? else {
+            return Ok(None);
         };
-        let get_object_resp = self
-            .client
-            .download_object(&get_object_req, &Default::default())
-            .await;
-        match get_object_resp {
-            Ok(body) => {
-                if let Ok(ts) =
-                    chrono::DateTime::parse_from_rfc3339(&String::from_utf8(body.clone())?)
-                {
-                    Ok(Some(ts))
-                } else {
-                    log::warn!("found corrupted marker file at {}. it's going to be deleted. contents were {:?}.", marker_path, body);
-                    Ok(None)
-                }
+        match serde_json::from_slice::<UploadManifest>(&body) {
+            Ok(manifest) if manifest.matches_loc(path_root) => Ok(Some(manifest)),
+            Ok(manifest) => {
+                log::warn!("found marker file at {} describing a different upload ({:?}). it's going to be deleted.", marker_path, manifest);
+                Ok(None)
             }
-            Err(e) => {
-                if let google_cloud_storage::http::Error::Response(ErrorResponse { code, .. }) = e {
-                    if code == 404 {
-                        Ok(None)
-                    } else {
-                        Err(anyhow::anyhow!(e))
+            Err(_) => {
+                log::warn!("found corrupted marker file at {}. it's going to be deleted. contents were {:?}.", marker_path, body);
+                Ok(None)
+            }
+        }
+    }
+
+    /// lists the manifests of all the in-progress uploads for a bucket.
+    /// folders without a readable manifest are skipped; the cleanup sweep takes care of them.
+    pub async fn list_uploads(&self, client_bucket_name: SafeString) -> S3Result<Vec<UploadManifest>> {
+        // the bucket is escaped, so the dash after it can't be part of another bucket's name
+        let prefix = format!("{}-", client_bucket_name);
+        let mut page_token = None;
+        let mut uploads = Vec::new();
+        loop {
+            let listing = transmute_result_for_s3error(self.store.list(&prefix, page_token).await)?;
+            for prefix in listing.prefixes {
+                match self.get_marker_contents(prefix.clone()).await {
+                    Ok(Some(manifest))
+                        if matches!(
+                            manifest.effective_state(),
+                            UploadState::Active | UploadState::Completing
+                        ) =>
+                    {
+                        uploads.push(manifest)
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        log::error!("cloudstorage multipart: error accessing marker for {}. error was {}", prefix, e);
+                        return Err(s3_error!(InternalError, "internal error"));
                     }
-                } else {
-                    unreachable!()
                 }
             }
+            page_token = listing.next_page_token;
+            if page_token.is_none() {
+                break;
+            }
         }
+        Ok(uploads)
     }
 
-    pub async fn run_cleanup_sweep(&self) -> Result<()> {
-        // list all folders in the bucket. if they don't have a marker, delete them. if the marker is over EXPIRY_TIME_SECONDS, delete them.
-        let list_object_req = ListObjectsRequest {
-            bucket: BUCKET_NAME.to_string(),
-            delimiter: Some("/".to_string()),
-            ..Default::default()
+    /// lists the segments of every part uploaded in direct mode that hasn't been linked into an object yet.
+    /// nothing in wnfs points at them, so the blockstore's gc needs to be told they're alive.
+    /// any error is returned rather than skipped: gc must not sweep without the full list.
+    pub async fn list_live_segments(&self) -> Result<Vec<Segment>> {
+        let mut segments = Vec::new();
+        let mut page_token = None;
+        loop {
+            let listing = self.store.list("", page_token).await?;
+            for prefix in listing.prefixes {
+                if prefix == SWEEP_LEASE_FOLDER {
+                    continue;
+                }
+                // parts of aborted uploads, and of folders without a marker, are garbage
+                match self.get_marker_contents(prefix.clone()).await? {
+                    Some(manifest) if manifest.effective_state() != UploadState::Aborted => {}
+                    _ => continue,
+                }
+                let records_prefix = format!("{}/records/", prefix.trim_end_matches('/'));
+                for record_path in self.list_all(&records_prefix).await? {
+                    let Some(record) = self.store.get(&record_path).await? else {
+                        continue;
+                    };
+                    let record: PartRecord = serde_json::from_slice(&record)?;
+                    segments.extend(record.segments);
+                }
+            }
+            page_token = listing.next_page_token;
+            if page_token.is_none() {
+                break;
+            }
+        }
+        Ok(segments)
+    }
+
+    /// tries to take (or renew) the lease called `lease_name` that lets `holder` run a sweep, like the multipart cleanup or gc,
+    /// for the next `ttl`. returns Ok(false) if another replica holds it.
+    pub async fn try_acquire_sweep_lease(
+        &self,
+        lease_name: &str,
+        holder: &str,
+        ttl: chrono::Duration,
+    ) -> Result<bool> {
+        let path = format!("{}{}", SWEEP_LEASE_FOLDER, lease_name);
+        let generation = match self.store.get_with_generation(&path).await? {
+            Some((body, generation)) => {
+                match serde_json::from_slice::<SweepLease>(&body) {
+                    Ok(lease) if lease.holder != holder && lease.expires > Utc::now() => {
+                        return Ok(false)
+                    }
+                    Ok(_) => {}
+                    Err(e) => log::warn!("cloudstorage multipart: found corrupted sweep lease, taking it over. error was {}", e),
+                }
+                generation
+            }
+            None => 0,
         };
-        let mut list_object_resp = self.client.list_objects(&list_object_req).await?;
+        let lease = SweepLease {
+            holder: holder.to_string(),
+            expires: Utc::now() + ttl,
+        };
+        self.store
+            .put_if_generation(&path, serde_json::to_vec(&lease)?, generation)
+            .await
+    }
+
+    /// deletes uploads that have been around longer than their bucket's AbortIncompleteMultipartUpload
+    /// lifecycle rules allow, or longer than `default_expiry` if no rule covers them.
+    pub async fn run_cleanup_sweep(
+        &self,
+        default_expiry: chrono::Duration,
+        registry: &BucketRegistry,
+    ) -> Result<()> {
+        // lifecycles of the buckets we've seen this sweep
+        let mut lifecycles: HashMap<String, BucketLifecycle> = HashMap::new();
+        // folders we couldn't get rid of. the sweep carries on past them, and they get another go next time.
+        let mut failures = 0;
+        if let Err(e) = self.store.sweep_leftovers(default_expiry).await {
+            log::error!("cloudstorage multipart: couldn't sweep what unfinished writes left behind: {}", e);
+            failures += 1;
+        }
+        // list all folders in the store. if they don't have a marker, delete them. if the marker is past its expiry, delete them.
+        let mut listing = self.store.list("", None).await?;
         loop {
             // items should have nothing in it.
-            if let Some(items) = list_object_resp.items {
-                log::warn!("cloudstorage multipart: found {} loose items in the root outside directories. this looks like a bug. deleting them.", items.len());
-                for item in items {
-                    let delete_request = DeleteObjectRequest {
-                        bucket: BUCKET_NAME.to_string(),
-                        object: item.name,
-                        ..Default::default()
-                    };
-                    self.client.delete_object(&delete_request).await?;
+            if !listing.items.is_empty() {
+                log::warn!("cloudstorage multipart: found {} loose items in the root outside directories. this looks like a bug. deleting them.", listing.items.len());
+                if let Err(e) = self.delete_all("", listing.items).await {
+                    log::error!("cloudstorage multipart: {}", e);
+                    failures += 1;
                 }
             }
-            if let Some(prefixes) = list_object_resp.prefixes {
-                for prefix in prefixes {
-                    let started = self.get_marker_contents(prefix.clone()).await;
-                    match started {
-                        Ok(Some(ts)) => {
-                            if ts
-                                < chrono::Utc::now()
-                                    - chrono::Duration::seconds(
-                                        EXPIRY_TIME_SECONDS.try_into().unwrap(),
-                                    )
-                            {
-                                log::info!(
-                                    "cloudstorage multipart: deleting {} because it's too old",
-                                    prefix
-                                );
-                                self.rm_rf(prefix).await.unwrap();
-                            }
+            for prefix in listing.prefixes {
+                if prefix == SWEEP_LEASE_FOLDER {
+                    continue;
+                }
+                let started = self.get_marker_contents(prefix.clone()).await;
+                match started {
+                    Ok(Some(manifest)) => {
+                        if !lifecycles.contains_key(&manifest.bucket) {
+                            // a bucket we can't look up doesn't stop the rest of the sweep. its uploads get another go next time.
+                            let lifecycle = match registry.get_lifecycle(&manifest.bucket).await {
+                                Ok(lifecycle) => lifecycle.unwrap_or_default(),
+                                Err(e) => {
+                                    log::error!("cloudstorage multipart: couldn't get the lifecycle of {}, skipping {}. error was {:?}", manifest.bucket, prefix, e);
+                                    failures += 1;
+                                    continue;
+                                }
+                            };
+                            lifecycles.insert(manifest.bucket.clone(), lifecycle);
                         }
-                        Ok(None) => {
-                            log::info!("cloudstorage multipart: deleting {} because it's missing a marker or has a malformatted marker", prefix);
-                            self.rm_rf(prefix).await.unwrap();
+                        let expiry = lifecycles[&manifest.bucket]
+                            .abort_after(&manifest.key)
+                            .unwrap_or(default_expiry);
+                        if manifest.initiated < Utc::now() - expiry {
+                            // aborting it first means nobody can add parts to it or complete it while it goes.
+                            // one that's being completed is left to finish, or to run out of time.
+                            let aborted = self
+                                .transition_upload(
+                                    &SafeString::new(manifest.bucket.clone()),
+                                    &SafeString::new(manifest.key.clone()),
+                                    &SafeString::new(manifest.upload_id.clone()),
+                                    |manifest| match manifest.state {
+                                        UploadState::Active => Ok(Some(UploadState::Aborted)),
+                                        UploadState::Aborted | UploadState::Completed => Ok(None),
+                                        UploadState::Completing => Err(s3_error!(
+                                            OperationAborted,
+                                            "The multipart upload is being completed"
+                                        )),
+                                    },
+                                    None,
+                                )
+                                .await;
+                            if let Err(e) = aborted {
+                                log::warn!("cloudstorage multipart: couldn't abort upload {} of {}/{}, skipping it. error was {:?}", manifest.upload_id, manifest.bucket, manifest.key, e);
+                                continue;
+                            }
+                            log::info!(
+                                "cloudstorage multipart: deleting upload {} of {}/{} because it's too old",
+                                manifest.upload_id,
+                                manifest.bucket,
+                                manifest.key
+                            );
+                            if let Err(e) = self.rm_rf(prefix).await {
+                                log::error!("cloudstorage multipart: {}", e);
+                                failures += 1;
+                            }
                         }
-                        Err(e) => {
-                            log::error!("cloudstorage multipart: error accessing marker for {}. skipping. error was {}", prefix, e);
+                    }
+                    Ok(None) => {
+                        log::info!("cloudstorage multipart: deleting {} because it's missing a marker or has a malformatted marker", prefix);
+                        if let Err(e) = self.rm_rf(prefix).await {
+                            log::error!("cloudstorage multipart: {}", e);
+                            failures += 1;
                         }
                     }
+                    Err(e) => {
+                        log::error!("cloudstorage multipart: error accessing marker for {}. skipping. error was {}", prefix, e);
+                        failures += 1;
+                    }
                 }
             }
-            if let Some(next_page_token) = list_object_resp.next_page_token {
-                list_object_resp = self
-                    .client
-                    .list_objects(&ListObjectsRequest {
-                        bucket: BUCKET_NAME.to_string(),
-                        delimiter: Some("/".to_string()),
-                        page_token: Some(next_page_token),
-                        ..Default::default()
-                    })
-                    .await?;
+            if let Some(next_page_token) = listing.next_page_token {
+                listing = self.store.list("", Some(next_page_token)).await?;
+            } else if failures > 0 {
+                break Err(anyhow::anyhow!(
+                    "{} folders couldn't be cleaned up this sweep",
+                    failures
+                ));
             } else {
                 break Ok(());
             }
         }
     }
 
-    pub async fn upload_part(
+    /// streams a part into the upload's folder (or into wnfs, in direct mode), hashing and counting it on the way through.
+    /// staged parts land under a name of their own, and only become the part once their record is written,
+    /// so a bad or late retry never clobbers a good part, and a completion never reads bytes other than the ones it checked.
+    /// the record is only written while the upload is taking parts. if the upload was aborted or completed while it was
+    /// being written, it's taken back out again.
+    /// if the client sent a Content-MD5, the part is checked against it and thrown away on a mismatch.
+    /// a part over the maximum part size is cut off as soon as it goes over.
+    /// the part's etag and size are stored next to it so completion can check what the client thinks it uploaded.
+    /// returns the etag.
+    pub async fn upload_part<S, E>(
         &self,
         client_bucket_name: SafeString,
         client_object_name: SafeString,
         upload_id: SafeString,
         part_number: u32,
-        body: StreamingBlob,
-    ) -> S3Result<()> {
+        content_md5: Option<String>,
+        body: S,
+    ) -> S3Result<String>
+    where
+        S: Stream<Item = Result<bytes::Bytes, E>> + Send + Sync + 'static,
+        E: Into<Box<dyn std::error::Error + Send + Sync>> + 'static,
+    {
+        if !(1..=MAX_PART_NUMBER).contains(&part_number) {
+            return Err(s3_error!(
+                InvalidArgument,
+                "Part number must be an integer between 1 and 10000, inclusive"
+            ));
+        }
         let part_path = multipart_loc_with_part!(
             client_bucket_name,
             client_object_name,
             upload_id,
             part_number
         );
-        let up_object_req: UploadObjectRequest = UploadObjectRequest {
-            bucket: BUCKET_NAME.to_string(),
-            ..Default::default()
+        let staged_path = format!("{}.{}", part_path, uuid::Uuid::new_v4());
+        // hash and count the bytes as they go by on their way to staging
+        let hasher = Arc::new(std::sync::Mutex::new(Md5::new()));
+        let size = Arc::new(AtomicU64::new(0));
+        let body = {
+            let hasher = hasher.clone();
+            let size = size.clone();
+            let max_part_size = self.limits.max_part_size;
+            body.map(move |chunk| {
+                let chunk = chunk.map_err(|e| std::io::Error::other(e.into()))?;
+                hasher.lock().unwrap().update(&chunk);
+                // failing the stream makes the store throw away what it has so far
+                if size.fetch_add(chunk.len() as u64, Ordering::SeqCst) + chunk.len() as u64
+                    > max_part_size
+                {
+                    return Err(std::io::Error::other("part is over the maximum part size"));
+                }
+                Ok(chunk)
+            })
+        };
+        let put = match self.mode {
+            MultipartMode::Staged => self
+                .store
+                .put(&staged_path, Box::pin(body))
+                .await
+                .map(|_| Vec::new()),
+            // segments that don't make it into a record are garbage, and left for the blockstore's gc
+            MultipartMode::Direct => self
+                .buckets
+                .write_segments(body.map(|chunk| chunk.map_err(anyhow::Error::from)))
+                .await
+                .map_err(|e| anyhow::anyhow!("{:?}", e)),
+        };
+        let size = size.load(Ordering::SeqCst);
+        if size > self.limits.max_part_size {
+            return Err(s3_error!(
+                EntityTooLarge,
+                "Your proposed upload exceeds the maximum allowed part size of {} bytes.",
+                self.limits.max_part_size
+            ));
+        }
+        let segments = transmute_result_for_s3error(put)?;
+        let digest = hasher.lock().unwrap().finalize_reset();
+
+        let staged_path = (self.mode == MultipartMode::Staged).then_some(staged_path);
+
+        if let Some(content_md5) = content_md5 {
+            if content_md5 != base64::engine::general_purpose::STANDARD.encode(digest) {
+                log::info!(
+                    "cloudstorage multipart: part {} failed Content-MD5 check. deleting it.",
+                    part_path
+                );
+                if let Some(staged_path) = &staged_path {
+                    transmute_result_for_s3error(self.store.delete(staged_path).await)?;
+                }
+                return Err(s3_error!(
+                    BadDigest,
+                    "The Content-MD5 you specified did not match what we received."
+                ));
+            }
+        }
+
+        // s3 etags for non-multipart objects are the quoted hex md5
+        let etag = format!("\"{}\"", hex::encode(digest));
+        let record_path = multipart_loc_with_part_record!(
+            client_bucket_name,
+            client_object_name,
+            upload_id,
+            part_number
+        );
+        let record = transmute_result_for_s3error(
+            serde_json::to_vec(&PartRecord {
+                etag: etag.clone(),
+                size,
+                segments,
+                staged_path: staged_path.clone(),
+            })
+            .map_err(anyhow::Error::from),
+        )?;
+
+        // if the upload was completed or aborted while we were writing, the part is too late
+        let Some((UploadState::Active, generation)) = self
+            .upload_state(&client_bucket_name, &client_object_name, &upload_id)
+            .await?
+        else {
+            self.withdraw_part(None, staged_path.as_deref()).await;
+            return Err(no_such_upload());
         };
-        let upload_type = UploadType::Simple(Media::new(part_path));
         transmute_result_for_s3error(
-            self.client
-                .upload_object(
-                    &up_object_req,
-                    reqwest::Body::wrap_stream(body),
-                    &upload_type,
-                )
+            self.store
+                .put(&record_path, body_from_bytes(record))
                 .await,
         )?;
-        Ok(())
+        // and if it happened while the record was being written, an abort or the cleanup after a completion
+        // can have been and gone without seeing it. a completion that's still going, or gave up, is fine:
+        // it read one record of the part or the other, and the bytes of both stay put.
+        match self
+            .upload_state(&client_bucket_name, &client_object_name, &upload_id)
+            .await?
+        {
+            Some((_, current)) if current == generation => Ok(etag),
+            Some((UploadState::Active | UploadState::Completing, _)) => Ok(etag),
+            _ => {
+                self.withdraw_part(Some(&record_path), staged_path.as_deref()).await;
+                Err(no_such_upload())
+            }
+        }
+    }
+
+    /// takes a part that was too late for its upload back out of staging. whatever's left is swept up with the upload.
+    async fn withdraw_part(&self, record_path: Option<&str>, staged_path: Option<&str>) {
+        for path in record_path.into_iter().chain(staged_path) {
+            if let Err(e) = self.store.delete(path).await {
+                log::warn!("cloudstorage multipart: couldn't delete {} of a finished upload: {}", path, e);
+            }
+        }
+    }
+
+    /// returns the record stored alongside a part, or None if the part (or its record) isn't there
+    pub async fn get_part_record(
+        &self,
+        client_bucket_name: SafeString,
+        client_object_name: SafeString,
+        upload_id: SafeString,
+        part_number: u32,
+    ) -> S3Result<Option<PartRecord>> {
+        let record_path = multipart_loc_with_part_record!(
+            client_bucket_name,
+            client_object_name,
+            upload_id,
+            part_number
+        );
+        let record = transmute_result_for_s3error(self.store.get(&record_path).await)?;
+        Ok(record.and_then(|record| serde_json::from_slice(&record).ok()))
     }
 
+    /// completes an upload, returning the etag of the finished object.
+    /// only one completion can run at once; completing an upload that's already completed
+    /// just hands back the etag from the first time.
+    /// `expected_etags` are the (part number, etag) pairs the client listed in its completion request.
+    /// every one of them has to match the etag we stored when the part was uploaded.
     pub async fn finish_upload(
         &self,
         client_bucket_name: SafeString,
         client_object_name: SafeString,
         upload_id: SafeString,
-    ) -> S3Result<()> {
+        expected_etags: Vec<(u32, String)>,
+        actor: &str,
+    ) -> S3Result<String> {
+        let manifest = self
+            .transition_upload(
+                &client_bucket_name,
+                &client_object_name,
+                &upload_id,
+                |manifest| match manifest.state {
+                    UploadState::Active => Ok(Some(UploadState::Completing)),
+                    UploadState::Completed => Ok(None),
+                    UploadState::Completing => Err(s3_error!(
+                        OperationAborted,
+                        "The multipart upload is already being completed"
+                    )),
+                    UploadState::Aborted => Err(no_such_upload()),
+                },
+                None,
+            )
+            .await?;
+        if manifest.state == UploadState::Completed {
+            return Ok(manifest.etag.unwrap_or_default());
+        }
+
+        let assembled = self
+            .assemble_upload(
+                client_bucket_name.clone(),
+                client_object_name.clone(),
+                upload_id.clone(),
+                expected_etags,
+                actor,
+            )
+            .await;
+        let etag = match assembled {
+            Ok(etag) => etag,
+            Err(e) => {
+                // let the client fix whatever it was and try again, unless we ran out of time and it's been aborted
+                self.transition_upload(
+                    &client_bucket_name,
+                    &client_object_name,
+                    &upload_id,
+                    |manifest| {
+                        Ok((manifest.state == UploadState::Completing).then_some(UploadState::Active))
+                    },
+                    None,
+                )
+                .await?;
+                return Err(e);
+            }
+        };
+        // the object is written either way, but a completion that ran out of time leaves its upload aborted
+        self.transition_upload(
+            &client_bucket_name,
+            &client_object_name,
+            &upload_id,
+            |manifest| {
+                Ok((manifest.state == UploadState::Completing).then_some(UploadState::Completed))
+            },
+            Some(etag.clone()),
+        )
+        .await?;
+        // the parts aren't needed any more. if they don't all go, the sweep gets the rest;
+        // the object is finished either way, so the client shouldn't hear about it.
+        if let Err(e) = self
+            .cleanup_upload(client_bucket_name, client_object_name, upload_id)
+            .await
+        {
+            log::warn!("cloudstorage multipart: upload completed but its parts weren't all cleaned up: {:?}", e);
+        }
+        Ok(etag)
+    }
+
+    /// checks the parts of an upload and puts them together. returns the etag of the finished object.
+    async fn assemble_upload(
+        &self,
+        client_bucket_name: SafeString,
+        client_object_name: SafeString,
+        upload_id: SafeString,
+        expected_etags: Vec<(u32, String)>,
+        actor: &str,
+    ) -> S3Result<String> {
         let root = multipart_loc!(client_bucket_name, client_object_name, upload_id);
+        // parts have to be listed in ascending order
+        if expected_etags
+            .windows(2)
+            .any(|pair| pair[0].0 >= pair[1].0)
+        {
+            return Err(s3_error!(
+                InvalidPartOrder,
+                "The list of parts was not in ascending order. The parts list must be specified in order by part number."
+            ));
+        }
+        if expected_etags.is_empty() {
+            return Err(s3_error!(
+                MalformedXML,
+                "The XML you provided was not well-formed or did not validate against our published schema."
+            ));
+        }
+        // s3 multipart etags are the md5 of the parts' md5s, followed by the number of parts
+        let part_numbers: Vec<u32> = expected_etags.iter().map(|(part_number, _)| *part_number).collect();
+        let mut etag_hasher = Md5::new();
+        let num_parts = expected_etags.len();
+        let mut object_size = 0;
+        let mut segments = Vec::new();
+        // where each listed part's bytes are, in staged mode
+        let mut staged_paths = Vec::new();
+        for (i, (part_number, expected_etag)) in expected_etags.into_iter().enumerate() {
+            let record = self
+                .get_part_record(
+                    client_bucket_name.clone(),
+                    client_object_name.clone(),
+                    upload_id.clone(),
+                    part_number,
+                )
+                .await?;
+            // some clients send etags without the quotes
+            let Some(record) = record
+                .filter(|record| record.etag.trim_matches('"') == expected_etag.trim_matches('"'))
+            else {
+                return Err(s3_error!(
+                    InvalidPart,
+                    "One or more of the specified parts could not be found or the specified entity tag might not have matched the part's entity tag."
+                ));
+            };
+            let part_digest = hex::decode(expected_etag.trim_matches('"')).map_err(|_| {
+                s3_error!(InvalidPart, "The specified entity tag is not a valid part entity tag.")
+            })?;
+            etag_hasher.update(part_digest);
+
+            let size = record.size;
+            segments.extend(record.segments);
+            staged_paths.extend(record.staged_path.map(|path| (path, part_number)));
+            if size < MIN_PART_SIZE && i + 1 < num_parts {
+                return Err(s3_error!(
+                    EntityTooSmall,
+                    "Your proposed upload is smaller than the minimum allowed object size. Every part but the last must be at least {} bytes.",
+                    MIN_PART_SIZE
+                ));
+            }
+            object_size += size;
+            if object_size > self.limits.max_object_size {
+                return Err(s3_error!(
+                    EntityTooLarge,
+                    "Your proposed upload exceeds the maximum allowed object size of {} bytes.",
+                    self.limits.max_object_size
+                ));
+            }
+        }
+        let etag = format!(
+            "\"{}-{}\"",
+            hex::encode(etag_hasher.finalize()),
+            num_parts
+        );
+        if self.mode == MultipartMode::Direct {
+            // the bytes are already in wnfs. all that's left is to say which segments make up the object.
+            self.buckets
+                .link_composite(
+                    client_bucket_name.raw(),
+                    client_object_name.raw(),
+                    CompositeObject {
+                        segments,
+                        etag: etag.clone(),
+                    },
+                    actor,
+                )
+                .await?;
+            return Ok(etag);
+        }
         // list the objects in the right folder
-        let list_object_req = ListObjectsRequest {
-            bucket: BUCKET_NAME.to_string(),
-            delimiter: Some("/".to_string()),
-            prefix: Some(root.clone()),
-            ..Default::default()
-        };
-        let mut list_object_resp =
-            transmute_result_for_s3error(self.client.list_objects(&list_object_req).await)?;
+        let prefix = format!("{}/", root);
+        let mut listing = transmute_result_for_s3error(self.store.list(&prefix, None).await)?;
+        let listed: HashMap<&str, u32> = staged_paths
+            .iter()
+            .map(|(path, part_number)| (path.as_str(), *part_number))
+            .collect();
         let mut parts = PartTracker::new();
         loop {
-            if let Some(items) = list_object_resp.items {
-                for item in items {
-                    let part_number = item.name.split("/").last().unwrap().parse::<u8>().unwrap();
-                    parts.add_part(part_number);
+            for item in listing.items {
+                // only the bytes the records point at count. the rest are earlier uploads of a part, or the marker.
+                if let Some(part_number) = listed.get(item.as_str()) {
+                    transmute_result_for_s3error(parts.add_part(*part_number))?;
                 }
             }
-            if list_object_resp.next_page_token.is_some() {
-                let list_object_req = ListObjectsRequest {
-                    bucket: BUCKET_NAME.to_string(),
-                    page_token: list_object_resp.next_page_token,
-                    ..Default::default()
-                };
-                list_object_resp =
-                    transmute_result_for_s3error(self.client.list_objects(&list_object_req).await)?;
+            if listing.next_page_token.is_some() {
+                listing = transmute_result_for_s3error(
+                    self.store.list(&prefix, listing.next_page_token).await,
+                )?;
             } else {
                 break;
             }
         }
-        if !parts.is_complete() {
-            return Err(s3_error!(InvalidRequest, "invalid parts"));
+        // a part with a record but no bytes can't go into the object
+        if !part_numbers.iter().all(|part_number| parts.has_part(*part_number)) {
+            return Err(s3_error!(
+                InvalidPart,
+                "One or more of the specified parts could not be found or the specified entity tag might not have matched the part's entity tag."
+            ));
         }
 
-        // this thing implements asyncread. go crazy.
-        let reader = PartsReader::new(parts.largest.into(), &self.client,
-            client_bucket_name, client_object_name, upload_id).await;
+        // copy the listed parts, and only those, out of staging and into wnfs
+        let parts = read_parts(
+            staged_paths.into_iter().map(|(path, _)| path).collect(),
+            self.store.as_ref(),
+        );
+        self.buckets
+            .write_object(
+                client_bucket_name.raw(),
+                client_object_name.raw(),
+                parts,
+                &etag,
+                actor,
+            )
+            .await?;
+        Ok(etag)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::TryStreamExt;
 
-        todo!("reconstruct");
-        todo!("put into wnfs");
-        // delete it from cloud storage once we're done...
-        self.rm_rf(root).await?;
-        Ok(())
+    use super::*;
+    use crate::{
+        blockstores::AnyBlockStore,
+        multipart_staging::{MemoryStagingStore, StagingBody, StagingListing},
+        mutex_memory_blockstore::MutexMemoryBlockStore,
+    };
+
+    #[test]
+    fn upload_locations_map_back_to_what_made_them() {
+        for (bucket, key, upload_id) in [
+            ("photos", "cat.jpg", "upload"),
+            ("my-photos", "2023/05/cat-1.jpg", "a-b-c"),
+            ("photos", "100%/%2F-%2D", "%25"),
+            ("photos", "/leading/and/trailing/", "-"),
+        ] {
+            let (safe_bucket, safe_key, safe_upload_id) = (
+                SafeString::new(bucket.to_string()),
+                SafeString::new(key.to_string()),
+                SafeString::new(upload_id.to_string()),
+            );
+            for (escaped, raw) in [(&safe_bucket, bucket), (&safe_key, key), (&safe_upload_id, upload_id)] {
+                assert!(!escaped.to_string().contains(['/', '-']));
+                assert_eq!(SafeString::decode(&escaped.to_string()).as_deref(), Some(raw));
+            }
+            let expected = Some((bucket.to_string(), key.to_string(), upload_id.to_string()));
+            let loc = multipart_loc!(safe_bucket, safe_key, safe_upload_id);
+            assert_eq!(parse_multipart_loc(&loc), expected);
+            assert_eq!(parse_multipart_loc(&format!("{}/", loc)), expected);
+        }
+        assert_eq!(SafeString::decode("100%"), None);
+        assert_eq!(SafeString::decode("%41"), None);
+        assert_eq!(parse_multipart_loc("photos-cat.jpg"), None);
+        assert_eq!(parse_multipart_loc("photos-cat-jpg-upload"), None);
+    }
+
+    #[test]
+    fn dashes_dont_move_between_components() {
+        let loc = |bucket: &str, key: &str| {
+            multipart_loc!(
+                SafeString::new(bucket.to_string()),
+                SafeString::new(key.to_string()),
+                SafeString::new("upload".to_string())
+            )
+        };
+        assert_ne!(loc("a-b", "c"), loc("a", "b-c"));
+        assert_eq!(
+            parse_multipart_loc(&loc("a-b", "c")),
+            Some(("a-b".to_string(), "c".to_string(), "upload".to_string()))
+        );
+        assert_eq!(
+            parse_multipart_loc(&loc("a", "b-c")),
+            Some(("a".to_string(), "b-c".to_string(), "upload".to_string()))
+        );
+    }
+
+    #[test]
+    fn part_tracker_covers_every_part_number() {
+        let mut parts = PartTracker::new();
+        for part_number in [1, 999, 1000, 1001, 9000, 9999, 10000] {
+            parts.add_part(part_number).unwrap();
+            assert!(parts.has_part(part_number));
+        }
+        assert!(!parts.has_part(2));
+        assert!(!parts.has_part(0));
+        assert!(parts.add_part(0).is_err());
+        assert!(parts.add_part(10001).is_err());
+    }
+
+    #[test]
+    fn completions_that_run_out_of_time_count_as_aborted() {
+        let mut manifest = UploadManifest {
+            bucket: "bucket".to_string(),
+            key: "key".to_string(),
+            upload_id: "upload".to_string(),
+            initiated: Utc::now(),
+            state: UploadState::Completing,
+            etag: None,
+            completing_until: Some(Utc::now() + chrono::Duration::hours(1)),
+        };
+        assert_eq!(manifest.effective_state(), UploadState::Completing);
+        manifest.completing_until = Some(Utc::now() - chrono::Duration::hours(1));
+        assert_eq!(manifest.effective_state(), UploadState::Aborted);
+    }
+
+    #[tokio::test]
+    async fn only_the_listed_parts_are_read() {
+        let store = MemoryStagingStore::default();
+        let (bucket, key, upload_id) = (
+            SafeString::new("bucket".to_string()),
+            SafeString::new("key".to_string()),
+            SafeString::new("upload".to_string()),
+        );
+        let mut paths = Vec::new();
+        for (part_number, content) in [(1, "one "), (2, "two "), (3, "three")] {
+            let path = multipart_loc_with_part!(bucket, key, upload_id, part_number);
+            store.put(&path, body_from_bytes(content)).await.unwrap();
+            paths.push(path);
+        }
+        paths.remove(1);
+        let content: Vec<bytes::Bytes> = read_parts(paths, &store).try_collect().await.unwrap();
+        assert_eq!(content.concat(), b"one three");
+    }
+
+    /// a staging store that aborts the upload something is put into, just before a put to a path containing
+    /// `abort_before`, like an abort from another request landing at just the wrong time
+    struct AbortingStore {
+        inner: MemoryStagingStore,
+        abort_before: &'static str,
+    }
+
+    #[async_trait::async_trait]
+    impl StagingStore for AbortingStore {
+        async fn put(&self, path: &str, body: StagingBody) -> Result<()> {
+            if path.contains(self.abort_before) {
+                let marker_path = format!("{}/marker", path.split('/').next().unwrap());
+                let (body, generation) = self.inner.get_with_generation(&marker_path).await?.unwrap();
+                let mut manifest: UploadManifest = serde_json::from_slice(&body)?;
+                manifest.state = UploadState::Aborted;
+                let body = serde_json::to_vec(&manifest)?;
+                assert!(self.inner.put_if_generation(&marker_path, body, generation).await?);
+            }
+            self.inner.put(path, body).await
+        }
+
+        async fn get(&self, path: &str) -> Result<Option<Vec<u8>>> {
+            self.inner.get(path).await
+        }
+
+        async fn get_stream(&self, path: &str) -> Result<BoxStream<'static, Result<bytes::Bytes>>> {
+            self.inner.get_stream(path).await
+        }
+
+        async fn list(&self, prefix: &str, page_token: Option<String>) -> Result<StagingListing> {
+            self.inner.list(prefix, page_token).await
+        }
+
+        async fn delete(&self, path: &str) -> Result<()> {
+            self.inner.delete(path).await
+        }
+
+        async fn get_with_generation(&self, path: &str) -> Result<Option<(Vec<u8>, i64)>> {
+            self.inner.get_with_generation(path).await
+        }
+
+        async fn put_if_generation(&self, path: &str, bytes: Vec<u8>, generation: i64) -> Result<bool> {
+            self.inner.put_if_generation(path, bytes, generation).await
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn parts_too_late_for_an_abort_are_taken_back_out() {
+        // the abort lands while the part's bytes are being written, and while its record is
+        for (mode, abort_before) in [
+            (MultipartMode::Staged, "/1."),
+            (MultipartMode::Staged, "/records/1"),
+            (MultipartMode::Direct, "/records/1"),
+        ] {
+            let buckets = Arc::new(WnfsBuckets::new(
+                Arc::new(BucketRegistry::in_memory()),
+                AnyBlockStore::Memory(MutexMemoryBlockStore::new()),
+            ));
+            let multipart = CloudStorageForMultipartConstruction::new(
+                Box::new(AbortingStore {
+                    inner: MemoryStagingStore::default(),
+                    abort_before,
+                }),
+                buckets,
+                mode,
+                MultipartLimits {
+                    max_part_size: 1 << 20,
+                    max_object_size: 1 << 20,
+                },
+            );
+            let (bucket, key, upload_id) = (
+                SafeString::new("bucket".to_string()),
+                SafeString::new("key".to_string()),
+                SafeString::new("upload".to_string()),
+            );
+            multipart
+                .create_multipart_upload_folder(bucket.clone(), key.clone(), upload_id.clone())
+                .await
+                .unwrap();
+            let body = futures::stream::iter([Ok::<_, std::io::Error>(bytes::Bytes::from("part"))]);
+            let err = multipart
+                .upload_part(bucket.clone(), key.clone(), upload_id.clone(), 1, None, body)
+                .await
+                .unwrap_err();
+            assert_eq!(*err.code(), s3s::S3ErrorCode::NoSuchUpload);
+
+            // nothing but the marker is left for the aborted upload
+            let root = format!("{}/", multipart_loc!(bucket, key, upload_id));
+            let left = multipart.list_all(&root).await.unwrap();
+            assert_eq!(left, [format!("{}marker", root)], "{} {:?}", abort_before, mode);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn completions_read_the_bytes_of_the_part_they_checked() {
+        let buckets = Arc::new(WnfsBuckets::new(
+            Arc::new(BucketRegistry::in_memory()),
+            AnyBlockStore::Memory(MutexMemoryBlockStore::new()),
+        ));
+        buckets.create_bucket("bucket", false, "test").await.unwrap();
+        let multipart = CloudStorageForMultipartConstruction::new(
+            Box::new(MemoryStagingStore::default()),
+            buckets.clone(),
+            MultipartMode::Staged,
+            MultipartLimits {
+                max_part_size: 1 << 20,
+                max_object_size: 1 << 20,
+            },
+        );
+        let (bucket, key, upload_id) = (
+            SafeString::new("bucket".to_string()),
+            SafeString::new("key".to_string()),
+            SafeString::new("upload".to_string()),
+        );
+        multipart
+            .create_multipart_upload_folder(bucket.clone(), key.clone(), upload_id.clone())
+            .await
+            .unwrap();
+        let mut etags = Vec::new();
+        for content in ["old", "new"] {
+            let body = futures::stream::iter([Ok::<_, std::io::Error>(bytes::Bytes::from(content))]);
+            let etag = multipart
+                .upload_part(bucket.clone(), key.clone(), upload_id.clone(), 1, None, body)
+                .await
+                .unwrap();
+            etags.push(etag);
+        }
+        // the first upload of the part is still in staging, but only the second one has a record
+        let err = multipart
+            .finish_upload(bucket.clone(), key.clone(), upload_id.clone(), vec![(1, etags[0].clone())], "test")
+            .await
+            .unwrap_err();
+        assert_eq!(*err.code(), s3s::S3ErrorCode::InvalidPart);
+        multipart
+            .finish_upload(bucket, key, upload_id, vec![(1, etags[1].clone())], "test")
+            .await
+            .unwrap();
+
+        let content: Vec<bytes::Bytes> = buckets
+            .read_object("bucket", "key", None)
+            .await
+            .unwrap()
+            .map(|chunk| chunk.unwrap())
+            .collect()
+            .await;
+        assert_eq!(content.concat(), b"new");
     }
 }
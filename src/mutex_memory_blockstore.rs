@@ -1,35 +1,28 @@
-//! Block store traits.
+//! An in-memory block store whose clones all share the same blocks.
 
-use std::borrow::Cow;
+use std::{
+    borrow::Cow,
+    sync::{Arc, Mutex},
+};
 
 use anyhow::{anyhow, Result};
-// use async_trait::async_trait;
-use hashbrown::HashMap;
-use libipld::{cid::Version, Cid, IpldCodec};
-use multihash::{Code, MultihashDigest};
-
-// //--------------------------------------------------------------------------------------------------
-// // Type Definitions
-// //--------------------------------------------------------------------------------------------------
-
-// /// For types that implement block store operations like adding, getting content from the store.
-// #[async_trait(?Send)]
-// pub trait BlockStore {
-//     async fn get_block<'a>(&'a self, cid: &Cid) -> Result<Cow<'a, Vec<u8>>>;
-//     async fn put_block(&mut self, bytes: Vec<u8>, codec: IpldCodec) -> Result<Cid>;
-// }
-
-use std::sync::Arc;
-
 use async_trait::async_trait;
-use tokio::sync::Mutex;
+use hashbrown::HashMap;
+use libipld::{
+    cid::Version,
+    multihash::{Code, MultihashDigest},
+    Cid, IpldCodec,
+};
 use wnfs::BlockStore;
 
+use crate::blockstores::BlockError;
+
 /// An in-memory block store to simulate IPFS.
 ///
-/// IPFS is basically a glorified HashMap.
-#[derive(Debug, Default)]
-pub struct MutexMemoryBlockStore(Arc<Mutex<HashMap<String, Vec<u8>>>>);
+/// IPFS is basically a glorified HashMap. Unlike wnfs's MemoryBlockStore, clones share their blocks,
+/// so one store can be handed to every task that needs it.
+#[derive(Debug, Default, Clone)]
+pub struct MutexMemoryBlockStore(Arc<Mutex<HashMap<Cid, Vec<u8>>>>);
 
 //--------------------------------------------------------------------------------------------------
 // Implementations
@@ -40,32 +33,86 @@ impl MutexMemoryBlockStore {
     pub fn new() -> Self {
         Self::default()
     }
-}
 
-unsafe impl Send for MutexMemoryBlockStore {}
-unsafe impl Sync for MutexMemoryBlockStore {}
+    /// Lists the CIDs of all the blocks in the store.
+    pub fn list_blocks(&self) -> Result<Vec<Cid>> {
+        Ok(self
+            .0
+            .lock()
+            .map_err(|_| anyhow!("blockstore lock poisoned"))?
+            .keys()
+            .copied()
+            .collect())
+    }
 
-#[async_trait]
+    /// Stores bytes under a CID, without checking they're the block it names.
+    pub fn put_stored(&self, cid: &Cid, bytes: Vec<u8>) -> Result<()> {
+        self.0
+            .lock()
+            .map_err(|_| anyhow!("blockstore lock poisoned"))?
+            .insert(*cid, bytes);
+        Ok(())
+    }
+
+    /// Retrieves whatever bytes are stored under a CID.
+    /// They're copied out, since they can't be borrowed past the lock.
+    pub fn get_stored(&self, cid: &Cid) -> Result<Vec<u8>> {
+        Ok(self
+            .0
+            .lock()
+            .map_err(|_| anyhow!("blockstore lock poisoned"))?
+            .get(cid)
+            .cloned()
+            .ok_or(BlockError::NotFound(*cid))?)
+    }
+
+    /// Removes a block from the store.
+    pub fn delete_block(&self, cid: &Cid) -> Result<()> {
+        self.0
+            .lock()
+            .map_err(|_| anyhow!("blockstore lock poisoned"))?
+            .remove(cid);
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
 impl BlockStore for MutexMemoryBlockStore {
     /// Stores an array of bytes in the block store.
-    async fn put_block(&mut self, bytes: Vec<u8>, codec: wnfs::ipld::IpldCodec) -> Result<Cid> {
+    async fn put_block(&mut self, bytes: Vec<u8>, codec: IpldCodec) -> Result<Cid> {
         let hash = Code::Sha2_256.digest(&bytes);
         let cid = Cid::new(Version::V1, codec.into(), hash)?;
 
-        self.0.insert((&cid).to_string(), bytes);
+        self.put_stored(&cid, bytes)?;
 
         Ok(cid)
     }
 
     /// Retrieves an array of bytes from the block store with given CID.
-    async fn get_block<'a>(&'a self, cid: &Cid<64>) -> Result<Cow<'a, Vec<u8>>> {
-        let bytes = self
-            .0
-            .lock()
-            .await
-            .get(&cid.to_string())
-            .ok_or(Err(anyhow!("CID not found in blockstore")));
+    async fn get_block<'a>(&'a self, cid: &Cid) -> Result<Cow<'a, Vec<u8>>> {
+        Ok(Cow::Owned(self.get_stored(cid)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn clones_share_their_blocks() {
+        let store = MutexMemoryBlockStore::new();
+        let cid = store.clone().put_block(b"hello".to_vec(), IpldCodec::Raw).await.unwrap();
+        assert_eq!(store.get_block(&cid).await.unwrap().as_slice(), b"hello");
+        assert_eq!(store.list_blocks().unwrap(), vec![cid]);
+
+        // put_stored doesn't check what it's given, and neither do reads
+        store.put_stored(&cid, b"jello".to_vec()).unwrap();
+        assert_eq!(store.clone().get_block(&cid).await.unwrap().as_slice(), b"jello");
 
-        Ok(Cow::Borrowed(bytes))
+        store.clone().delete_block(&cid).unwrap();
+        let error = store.get_block(&cid).await.unwrap_err();
+        assert!(matches!(error.downcast::<BlockError>().unwrap(), BlockError::NotFound(c) if c == cid));
+        assert!(store.list_blocks().unwrap().is_empty());
+        store.delete_block(&cid).unwrap();
     }
 }
@@ -0,0 +1,344 @@
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use futures::{future::join_all, stream::FuturesUnordered, StreamExt};
+use libipld::{Cid, IpldCodec};
+use wnfs::BlockStore;
+
+use crate::blockstores::{AnyBlockStore, BlockError, ListedBlock};
+
+/// how many errors in a row before a replica is only read from when nothing else has the block
+const UNHEALTHY_AFTER_FAILURES: u32 = 3;
+
+/// how a replica has been doing lately
+#[derive(Debug, Default)]
+struct ReplicaHealth {
+    consecutive_failures: AtomicU32,
+    /// moving average of how long reads take, in microseconds. 0 until the first read.
+    latency_micros: AtomicU64,
+}
+
+impl ReplicaHealth {
+    fn healthy(&self) -> bool {
+        self.consecutive_failures.load(Ordering::Relaxed) < UNHEALTHY_AFTER_FAILURES
+    }
+
+    fn succeeded(&self, started: Instant) {
+        self.consecutive_failures.store(0, Ordering::Relaxed);
+        let took = started.elapsed().as_micros() as u64;
+        let average = self.latency_micros.load(Ordering::Relaxed);
+        // weighted so one slow read doesn't send a replica to the back of the queue
+        let average = if average == 0 {
+            took
+        } else {
+            (average * 7 + took) / 8
+        };
+        self.latency_micros.store(average, Ordering::Relaxed);
+    }
+
+    fn failed(&self) {
+        self.consecutive_failures.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// a blockstore keeping a copy of every block in each of several others, so losing one of them loses nothing.
+///
+/// a put goes to every replica at once, and returns as soon as `write_quorum` of them took the block.
+/// the rest carry on in the background, so one slow replica doesn't slow down every write.
+/// a get tries healthy replicas fastest first, and copies the block to any replica it found missing it on the way,
+/// which also fills in the copies that puts missed while a replica was down.
+#[derive(Debug, Clone)]
+pub struct ReplicatedBlockStore {
+    replicas: Vec<AnyBlockStore>,
+    health: Arc<Vec<ReplicaHealth>>,
+    write_quorum: usize,
+}
+
+impl ReplicatedBlockStore {
+    pub fn new(replicas: Vec<AnyBlockStore>, write_quorum: usize) -> Result<Self> {
+        if write_quorum == 0 || write_quorum > replicas.len() {
+            return Err(anyhow!(
+                "a write quorum of {} doesn't work with {} replicas",
+                write_quorum,
+                replicas.len()
+            ));
+        }
+        Ok(Self {
+            health: Arc::new(replicas.iter().map(|_| Default::default()).collect()),
+            replicas,
+            write_quorum,
+        })
+    }
+
+    /// replica indices in the order to read from them: healthy before unhealthy, then fastest first
+    fn read_order(&self) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..self.replicas.len()).collect();
+        order.sort_by_key(|&i| {
+            let health = &self.health[i];
+            (
+                !health.healthy(),
+                health.latency_micros.load(Ordering::Relaxed),
+            )
+        });
+        order
+    }
+
+    /// lists every block any replica has. when replicas disagree about when a block was written,
+    /// the latest time wins, so gc's grace period counts from the last copy.
+    pub async fn list_blocks(&self) -> Result<Vec<ListedBlock>> {
+        let mut blocks: HashMap<Cid, ListedBlock> = HashMap::new();
+        let listings = join_all(self.replicas.iter().map(|replica| replica.list_blocks())).await;
+        for listing in listings {
+            for block in listing? {
+                blocks
+                    .entry(block.cid)
+                    .and_modify(|listed| listed.modified = listed.modified.max(block.modified))
+                    .or_insert(block);
+            }
+        }
+        Ok(blocks.into_values().collect())
+    }
+
+    /// removes a block from every replica
+    pub async fn delete_block(&self, cid: &Cid) -> Result<()> {
+        let deletes = join_all(self.replicas.iter().map(|replica| replica.delete_block(cid))).await;
+        let failed = deletes.iter().filter(|delete| delete.is_err()).count();
+        if failed > 0 {
+            return Err(anyhow!(
+                "couldn't delete block {} from {} of {} replicas. first error was {}",
+                cid,
+                failed,
+                self.replicas.len(),
+                deletes.into_iter().find_map(Result::err).unwrap()
+            ));
+        }
+        Ok(())
+    }
+
+    /// copies a block to replicas that didn't have a good copy of it.
+    /// stores that keep a block they already have, like gcs, keep a corrupt copy too. that's for scrub to repair.
+    async fn backfill(&self, cid: &Cid, bytes: &[u8], replicas: Vec<usize>) {
+        let codec = match IpldCodec::try_from(cid.codec()) {
+            Ok(codec) => codec,
+            Err(e) => {
+                log::error!("replicated blockstore: can't backfill block {} with an unknown codec: {}", cid, e);
+                return;
+            }
+        };
+        for i in replicas {
+            // put_block wants &mut, but a clone writes to the same place
+            let mut replica = self.replicas[i].clone();
+            match replica.put_block(bytes.to_vec(), codec).await {
+                Ok(_) => log::info!("replicated blockstore: backfilled block {} to replica {}", cid, i),
+                Err(e) => log::warn!(
+                    "replicated blockstore: couldn't backfill block {} to replica {}: {}",
+                    cid,
+                    i,
+                    e
+                ),
+            }
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl BlockStore for ReplicatedBlockStore {
+    async fn put_block(&mut self, bytes: Vec<u8>, codec: IpldCodec) -> Result<Cid> {
+        // blockstore futures aren't Send, so each put gets a blocking thread to itself, like wnfs does.
+        // that's also what lets the puts still going once there's a quorum carry on after we return.
+        let handle = tokio::runtime::Handle::current();
+        let mut puts: FuturesUnordered<_> = self
+            .replicas
+            .iter()
+            .enumerate()
+            .map(|(i, replica)| {
+                let (mut replica, bytes) = (replica.clone(), bytes.clone());
+                let (health, handle) = (self.health.clone(), handle.clone());
+                tokio::task::spawn_blocking(move || {
+                    let put = handle.block_on(replica.put_block(bytes, codec));
+                    if let Err(e) = &put {
+                        health[i].failed();
+                        log::warn!("replicated blockstore: replica {} couldn't store a block: {}", i, e);
+                    }
+                    put
+                })
+            })
+            .collect();
+        let mut cid = None;
+        let (mut stored, mut failed) = (0, 0);
+        while let Some(put) = puts.next().await {
+            match put {
+                Ok(Ok(put_cid)) => {
+                    if cid.is_some_and(|cid| cid != put_cid) {
+                        return Err(anyhow!(
+                            "replicated blockstore: replicas disagree on the cid of a block: {} and {}",
+                            cid.unwrap(),
+                            put_cid
+                        ));
+                    }
+                    cid = Some(put_cid);
+                    stored += 1;
+                    if stored >= self.write_quorum {
+                        return Ok(put_cid);
+                    }
+                }
+                _ => {
+                    failed += 1;
+                    // no point waiting for the rest if they can't make a quorum anyway
+                    if self.replicas.len() - failed < self.write_quorum {
+                        break;
+                    }
+                }
+            }
+        }
+        Err(anyhow!(
+            "replicated blockstore: only {} of {} replicas stored the block, and {} need to",
+            stored,
+            self.replicas.len(),
+            self.write_quorum
+        ))
+    }
+
+    async fn get_block<'a>(&'a self, cid: &Cid) -> Result<Cow<'a, Vec<u8>>> {
+        // replicas that turned out not to have a good copy
+        let mut lacking = Vec::new();
+        let mut last_error = None;
+        for i in self.read_order() {
+            let started = Instant::now();
+            match self.replicas[i].get_block(cid).await {
+                Ok(bytes) => {
+                    self.health[i].succeeded(started);
+                    let bytes = bytes.into_owned();
+                    if !lacking.is_empty() {
+                        self.backfill(cid, &bytes, lacking).await;
+                    }
+                    return Ok(Cow::Owned(bytes));
+                }
+                Err(e) => {
+                    match e.downcast_ref::<BlockError>() {
+                        // the replica's fine, it just doesn't have the block
+                        Some(BlockError::NotFound(_) | BlockError::Corrupt(_)) => lacking.push(i),
+                        None => self.health[i].failed(),
+                    }
+                    log::debug!("replicated blockstore: replica {} couldn't get block {}: {}", i, cid, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| BlockError::NotFound(*cid).into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{fs_blockstore::FsBlockStore, mutex_memory_blockstore::MutexMemoryBlockStore};
+
+    use super::*;
+
+    /// a replica that fails everything: its directory is a file, at the path handed back
+    async fn broken_replica() -> (AnyBlockStore, std::path::PathBuf) {
+        let root = std::env::temp_dir().join(format!("broken-{}", uuid::Uuid::new_v4()));
+        let store = FsBlockStore::new(root.clone()).await.unwrap();
+        std::fs::remove_dir(&root).unwrap();
+        std::fs::write(&root, b"not a directory").unwrap();
+        (AnyBlockStore::Fs(store), root)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn puts_need_a_quorum() {
+        let memory = MutexMemoryBlockStore::new();
+        let (broken, broken_path) = broken_replica().await;
+        let replicas = vec![AnyBlockStore::Memory(memory.clone()), broken];
+
+        let mut store = ReplicatedBlockStore::new(replicas.clone(), 2).unwrap();
+        let error = store.put_block(b"hello".to_vec(), IpldCodec::Raw).await.unwrap_err();
+        assert!(error.to_string().contains("only 1 of 2"), "{}", error);
+
+        let mut store = ReplicatedBlockStore::new(replicas, 1).unwrap();
+        let cid = store.put_block(b"hello".to_vec(), IpldCodec::Raw).await.unwrap();
+        assert_eq!(memory.get_stored(&cid).unwrap(), b"hello");
+        assert!(ReplicatedBlockStore::new(vec![AnyBlockStore::Memory(memory)], 2).is_err());
+        std::fs::remove_file(broken_path).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn puts_past_the_quorum_finish_in_the_background() {
+        let replicas: Vec<_> = (0..3).map(|_| MutexMemoryBlockStore::new()).collect();
+        let mut store = ReplicatedBlockStore::new(
+            replicas.iter().cloned().map(AnyBlockStore::Memory).collect(),
+            1,
+        )
+        .unwrap();
+        let cid = store.put_block(b"hello".to_vec(), IpldCodec::Raw).await.unwrap();
+        for _ in 0..100 {
+            if replicas.iter().all(|replica| replica.get_stored(&cid).is_ok()) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("the puts past the quorum never finished");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reads_go_to_healthy_replicas_fastest_first() {
+        let (broken, broken_path) = broken_replica().await;
+        let replicas = vec![
+            broken,
+            AnyBlockStore::Memory(MutexMemoryBlockStore::new()),
+            AnyBlockStore::Memory(MutexMemoryBlockStore::new()),
+        ];
+        let mut store = ReplicatedBlockStore::new(replicas, 2).unwrap();
+        store.health[1].latency_micros.store(500, Ordering::Relaxed);
+        store.health[2].latency_micros.store(100, Ordering::Relaxed);
+        assert_eq!(store.read_order(), [0, 2, 1]);
+
+        // the broken replica fails its put, and then enough reads that it goes to the back
+        let cid = store.put_block(b"hello".to_vec(), IpldCodec::Raw).await.unwrap();
+        for _ in 0..UNHEALTHY_AFTER_FAILURES {
+            assert_eq!(*store.get_block(&cid).await.unwrap(), b"hello");
+        }
+        assert!(!store.health[0].healthy());
+        assert_eq!(store.read_order()[2], 0);
+        std::fs::remove_file(broken_path).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reads_backfill_missing_and_corrupt_copies() {
+        let missing = MutexMemoryBlockStore::new();
+        // memory stores don't check what they hand back, so the corrupt copy needs one that does
+        let dir = std::env::temp_dir().join(format!("corrupt-{}", uuid::Uuid::new_v4()));
+        let corrupt = FsBlockStore::new(dir.clone()).await.unwrap();
+        let good = MutexMemoryBlockStore::new();
+        let store = ReplicatedBlockStore::new(
+            vec![
+                AnyBlockStore::Memory(missing.clone()),
+                AnyBlockStore::Fs(corrupt.clone()),
+                AnyBlockStore::Memory(good.clone()),
+            ],
+            1,
+        )
+        .unwrap();
+        let cid = good.clone().put_block(b"hello".to_vec(), IpldCodec::Raw).await.unwrap();
+        corrupt.put_stored(&cid, b"jello").await.unwrap();
+        // the good copy is read last
+        store.health[2].latency_micros.store(1000, Ordering::Relaxed);
+
+        assert_eq!(*store.get_block(&cid).await.unwrap(), b"hello");
+        assert_eq!(missing.get_stored(&cid).unwrap(), b"hello");
+        assert_eq!(*corrupt.get_block(&cid).await.unwrap(), b"hello");
+        // not having a good copy of a block isn't the replica's fault
+        assert!(store.health.iter().all(ReplicaHealth::healthy));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,533 @@
+use std::{borrow::Cow, cell::RefCell, collections::HashSet};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use libipld::{Cid, IpldCodec};
+use wnfs::{private::PrivateNode, public::PublicNode, BlockStore};
+
+use crate::{
+    blockstores::{AnyBlockStore, BlockError},
+    bucket_registry::{BucketRegistry, BucketRoot},
+    fs_blockstore::verify_block,
+    unixfs,
+    wnfs_buckets::{
+        file_content, open_bucket, open_public, read_segment, run_wnfs_anyhow, CompositeObject, COMPOSITE_DIR,
+    },
+};
+
+/// what was wrong with a block
+#[derive(Debug, Clone)]
+pub enum ProblemKind {
+    Missing,
+    Corrupt,
+    /// the store couldn't say either way, e.g. because it was unreachable
+    Unreadable(String),
+}
+
+impl ProblemKind {
+    fn of(error: &anyhow::Error) -> Self {
+        match error.downcast_ref::<BlockError>() {
+            Some(BlockError::NotFound(_)) => ProblemKind::Missing,
+            Some(BlockError::Corrupt(_)) => ProblemKind::Corrupt,
+            None => ProblemKind::Unreadable(error.to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for ProblemKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProblemKind::Missing => write!(f, "missing"),
+            ProblemKind::Corrupt => write!(f, "corrupt"),
+            ProblemKind::Unreadable(e) => write!(f, "unreadable ({})", e),
+        }
+    }
+}
+
+/// a bad block, and the object it was found under.
+/// blocks of a bucket's tree that aren't any one object's, like the forest's, are found under whichever key needed them first.
+#[derive(Debug, Clone)]
+pub struct BlockProblem {
+    pub bucket: String,
+    /// the object key, a directory ending in a slash, or empty for the bucket's root
+    pub key: String,
+    pub cid: Cid,
+    pub kind: ProblemKind,
+    /// whether a good copy was put back from the secondary blockstore
+    pub repaired: bool,
+}
+
+/// what a scrub found
+#[derive(Debug, Default)]
+pub struct ScrubReport {
+    pub buckets: usize,
+    pub objects: usize,
+    pub blocks_checked: usize,
+    pub problems: Vec<BlockProblem>,
+    /// objects and directories that couldn't be read all the way through, even after repairs
+    pub unreadable: usize,
+}
+
+impl ScrubReport {
+    pub fn unrepaired(&self) -> usize {
+        self.problems.iter().filter(|problem| !problem.repaired).count()
+    }
+}
+
+impl std::fmt::Display for ScrubReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{} buckets, {} objects, {} blocks checked, {} bad blocks ({} repaired), {} objects or directories unreadable",
+            self.buckets,
+            self.objects,
+            self.blocks_checked,
+            self.problems.len(),
+            self.problems.len() - self.unrepaired(),
+            self.unreadable
+        )?;
+        for problem in &self.problems {
+            let key = if problem.key.is_empty() {
+                "(bucket root)"
+            } else {
+                &problem.key
+            };
+            writeln!(
+                f,
+                "{}/{}: block {} is {}{}",
+                problem.bucket,
+                key,
+                problem.cid,
+                problem.kind,
+                if problem.repaired { ", repaired" } else { "" }
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// a blockstore that checks every block it hands out, and notes down the bad ones under the object being read.
+/// with a secondary store, bad blocks are fetched from it and put back into the primary.
+struct ScrubStore {
+    primary: AnyBlockStore,
+    secondary: Option<AnyBlockStore>,
+    bucket: RefCell<String>,
+    key: RefCell<String>,
+    checked: RefCell<HashSet<Cid>>,
+    problems: RefCell<Vec<BlockProblem>>,
+}
+
+impl ScrubStore {
+    /// files the blocks read from now on under `key`
+    fn start(&self, bucket: &str, key: &str) {
+        *self.bucket.borrow_mut() = bucket.to_string();
+        *self.key.borrow_mut() = key.to_string();
+    }
+
+    fn record(&self, cid: Cid, kind: ProblemKind, repaired: bool) {
+        let bucket = self.bucket.borrow().clone();
+        let key = self.key.borrow().clone();
+        let mut problems = self.problems.borrow_mut();
+        // wnfs can read the same block more than once for one object
+        if problems
+            .iter()
+            .any(|problem| problem.cid == cid && problem.bucket == bucket && problem.key == key)
+        {
+            return;
+        }
+        log::warn!(
+            "scrub: block {} of {}/{} is {}{}",
+            cid,
+            bucket,
+            key,
+            kind,
+            if repaired { ", repaired" } else { "" }
+        );
+        problems.push(BlockProblem {
+            bucket,
+            key,
+            cid,
+            kind,
+            repaired,
+        });
+    }
+
+    /// gets a copy of a block from `secondary` and checks it's the right one
+    async fn good_copy(secondary: &AnyBlockStore, cid: &Cid) -> Result<Vec<u8>> {
+        let bytes = secondary.get_block(cid).await?.into_owned();
+        verify_block(cid, &bytes)?;
+        Ok(bytes)
+    }
+
+    /// puts a good copy of a block back in the primary store, then reads it back to make sure it took.
+    /// some stores skip puts of blocks they already have, so a corrupt copy is deleted first.
+    async fn repair(&self, cid: &Cid, bytes: &[u8], kind: &ProblemKind) -> Result<()> {
+        if matches!(kind, ProblemKind::Corrupt) {
+            self.primary.delete_block(cid).await?;
+        }
+        let codec = IpldCodec::try_from(cid.codec())?;
+        // put_block wants &mut, but a clone writes to the same place
+        let repaired = self.primary.clone().put_block(bytes.to_vec(), codec).await?;
+        if repaired != *cid {
+            return Err(anyhow!("the blockstore stored {} as {}", cid, repaired));
+        }
+        verify_block(cid, &self.primary.get_block(cid).await?)
+    }
+}
+
+#[async_trait(?Send)]
+impl BlockStore for ScrubStore {
+    async fn put_block(&mut self, bytes: Vec<u8>, codec: IpldCodec) -> Result<Cid> {
+        self.primary.put_block(bytes, codec).await
+    }
+
+    /// rechecks the block's hash even if the primary store already did, since not all of them do
+    async fn get_block<'a>(&'a self, cid: &Cid) -> Result<Cow<'a, Vec<u8>>> {
+        self.checked.borrow_mut().insert(*cid);
+        let error = match self.primary.get_block(cid).await {
+            Ok(bytes) => match verify_block(cid, &bytes) {
+                Ok(()) => return Ok(bytes),
+                Err(e) => e,
+            },
+            Err(e) => e,
+        };
+        let kind = ProblemKind::of(&error);
+        let Some(secondary) = &self.secondary else {
+            self.record(*cid, kind, false);
+            return Err(error);
+        };
+        let bytes = match Self::good_copy(secondary, cid).await {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                log::error!("scrub: couldn't get a good copy of block {}: {}", cid, e);
+                self.record(*cid, kind, false);
+                return Err(error);
+            }
+        };
+        // only put back blocks the primary is known to have lost or mangled; one it just couldn't read may be fine
+        let repaired = match kind {
+            ProblemKind::Missing | ProblemKind::Corrupt => match self.repair(cid, &bytes, &kind).await {
+                Ok(()) => true,
+                Err(e) => {
+                    log::error!("scrub: couldn't repair block {}: {}", cid, e);
+                    false
+                }
+            },
+            ProblemKind::Unreadable(_) => false,
+        };
+        self.record(*cid, kind, repaired);
+        // the good copy lets the scrub carry on through the rest of the object either way
+        Ok(Cow::Owned(bytes))
+    }
+}
+
+/// walks a public bucket's tree, reading every object's UnixFS file all the way through
+async fn scrub_public_bucket(bucket: &str, public_root_cid: &str, store: &ScrubStore, report: &mut ScrubReport) {
+    let root_dir = match open_public(public_root_cid, store).await {
+        Ok(root_dir) => root_dir,
+        Err(e) => {
+            log::error!("scrub: couldn't open bucket {}: {}", bucket, e);
+            report.unreadable += 1;
+            return;
+        }
+    };
+    let mut dirs: Vec<Vec<String>> = vec![vec![]];
+    while let Some(dir) = dirs.pop() {
+        if !dir.is_empty() {
+            store.start(bucket, &format!("{}/", dir.join("/")));
+        }
+        let entries = match root_dir.clone().ls(&dir, store).await {
+            Ok(op) => op.result,
+            Err(e) => {
+                log::error!("scrub: couldn't list {}/{}: {}", bucket, dir.join("/"), e);
+                report.unreadable += 1;
+                continue;
+            }
+        };
+        for (name, _) in entries {
+            let mut path = dir.clone();
+            path.push(name);
+            let key = path.join("/");
+            store.start(bucket, &key);
+            let file = match root_dir.clone().get_node(&path, store).await {
+                Ok(op) => match op.result {
+                    Some(PublicNode::File(file)) => file,
+                    Some(PublicNode::Dir(_)) => {
+                        dirs.push(path);
+                        continue;
+                    }
+                    None => continue,
+                },
+                Err(e) => {
+                    log::error!("scrub: couldn't find {}/{}: {}", bucket, key, e);
+                    report.unreadable += 1;
+                    continue;
+                }
+            };
+            report.objects += 1;
+            if let Err(e) = unixfs::read_file(file.get_content_cid(), store).await {
+                log::error!("scrub: couldn't read {}/{}: {}", bucket, key, e);
+                report.unreadable += 1;
+            }
+        }
+    }
+}
+
+/// walks a bucket's tree, reading every object in it all the way through
+async fn scrub_bucket(bucket: &str, root: &BucketRoot, store: &ScrubStore, report: &mut ScrubReport) {
+    store.start(bucket, "");
+    if let BucketRoot::Public { public_root_cid } = root {
+        scrub_public_bucket(bucket, public_root_cid, store, report).await;
+        return;
+    }
+    let (forest, root_dir) = match open_bucket(root, store).await {
+        Ok(opened) => opened,
+        Err(e) => {
+            log::error!("scrub: couldn't open bucket {}: {}", bucket, e);
+            report.unreadable += 1;
+            return;
+        }
+    };
+    let mut dirs: Vec<Vec<String>> = vec![vec![]];
+    while let Some(dir) = dirs.pop() {
+        if !dir.is_empty() {
+            store.start(bucket, &format!("{}/", dir.join("/")));
+        }
+        let entries = match root_dir.clone().ls(&dir, true, forest.clone(), store).await {
+            Ok(op) => op.result,
+            Err(e) => {
+                log::error!("scrub: couldn't list {}/{}: {}", bucket, dir.join("/"), e);
+                report.unreadable += 1;
+                continue;
+            }
+        };
+        for (name, _) in entries {
+            let mut path = dir.clone();
+            path.push(name);
+            // composite objects are filed under the key they're for, which is their path without the composite directory
+            let composite = path[0] == COMPOSITE_DIR;
+            let key = if composite {
+                path[1..].join("/")
+            } else {
+                path.join("/")
+            };
+            store.start(bucket, &key);
+            let node = match root_dir.clone().get_node(&path, true, forest.clone(), store).await {
+                Ok(op) => op.result,
+                Err(e) => {
+                    log::error!("scrub: couldn't find {}/{}: {}", bucket, key, e);
+                    report.unreadable += 1;
+                    continue;
+                }
+            };
+            let file = match node {
+                Some(PrivateNode::File(file)) => file,
+                Some(PrivateNode::Dir(_)) => {
+                    dirs.push(path);
+                    continue;
+                }
+                None => continue,
+            };
+            report.objects += 1;
+            let read = async {
+                let content = file_content(&file, &forest, store).await?;
+                if composite {
+                    let composite: CompositeObject = serde_json::from_slice(&content)?;
+                    for segment in &composite.segments {
+                        read_segment(segment, store).await?;
+                    }
+                }
+                anyhow::Ok(())
+            };
+            if let Err(e) = read.await {
+                log::error!("scrub: couldn't read {}/{}: {}", bucket, key, e);
+                report.unreadable += 1;
+            }
+        }
+    }
+}
+
+/// reads every object of every bucket (or just `bucket`) through `store`, checking every block on the way.
+/// bad blocks are repaired from `secondary`, if there is one.
+/// only what the buckets point at now is checked; old roots and unfinished multipart uploads aren't.
+pub async fn run_scrub(
+    store: AnyBlockStore,
+    secondary: Option<AnyBlockStore>,
+    registry: &BucketRegistry,
+    bucket: Option<String>,
+) -> Result<ScrubReport> {
+    let buckets = match bucket {
+        Some(name) => {
+            let root = registry
+                .get_root(&name)
+                .await
+                .map_err(|e| anyhow!("couldn't look up bucket {}: {:?}", name, e))?
+                .ok_or_else(|| anyhow!("there's no bucket called {}", name))?;
+            vec![(name, root)]
+        }
+        None => registry
+            .list_buckets()
+            .await
+            .map_err(|e| anyhow!("couldn't list buckets: {:?}", e))?,
+    };
+
+    run_wnfs_anyhow(move || async move {
+        let store = ScrubStore {
+            primary: store,
+            secondary,
+            bucket: Default::default(),
+            key: Default::default(),
+            checked: Default::default(),
+            problems: Default::default(),
+        };
+        let mut report = ScrubReport::default();
+        for (name, root) in &buckets {
+            log::info!("scrub: checking bucket {}", name);
+            report.buckets += 1;
+            scrub_bucket(name, root, &store, &mut report).await;
+        }
+        report.blocks_checked = store.checked.borrow().len();
+        report.problems = store.problems.take();
+        Ok(report)
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, str::FromStr, sync::Arc};
+
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::{fs_blockstore::FsBlockStore, wnfs_buckets::WnfsBuckets};
+
+    struct Fixture {
+        dirs: Vec<PathBuf>,
+        primary: FsBlockStore,
+        secondary: FsBlockStore,
+        registry: Arc<BucketRegistry>,
+        root: Cid,
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            for dir in &self.dirs {
+                let _ = std::fs::remove_dir_all(dir);
+                let _ = std::fs::remove_file(dir);
+            }
+        }
+    }
+
+    /// a bucket holding one object, with a copy of every block in a secondary store
+    async fn fixture() -> Fixture {
+        let dirs: Vec<_> = (0..2)
+            .map(|_| std::env::temp_dir().join(format!("scrub-{}", uuid::Uuid::new_v4())))
+            .collect();
+        let primary = FsBlockStore::new(dirs[0].clone()).await.unwrap();
+        let secondary = FsBlockStore::new(dirs[1].clone()).await.unwrap();
+        let registry = Arc::new(BucketRegistry::in_memory());
+        let buckets = WnfsBuckets::new(registry.clone(), AnyBlockStore::Fs(primary.clone()));
+        buckets.create_bucket("photos", false, "test").await.unwrap();
+        let body = futures::stream::iter([Ok(Bytes::from("a cat"))]);
+        buckets
+            .write_object("photos", "cat.txt", body, "\"test\"", "test")
+            .await
+            .unwrap();
+        for block in primary.list_blocks().await.unwrap() {
+            let bytes = primary.get_stored(&block.cid).await.unwrap();
+            secondary.put_stored(&block.cid, &bytes).await.unwrap();
+        }
+        let root = registry.get_root("photos").await.unwrap().unwrap();
+        Fixture {
+            dirs,
+            primary,
+            secondary,
+            root: Cid::from_str(root.root_cid()).unwrap(),
+            registry,
+        }
+    }
+
+    impl Fixture {
+        async fn scrub(&self, secondary: Option<&FsBlockStore>) -> ScrubReport {
+            run_scrub(
+                AnyBlockStore::Fs(self.primary.clone()),
+                secondary.map(|store| AnyBlockStore::Fs(store.clone())),
+                &self.registry,
+                None,
+            )
+            .await
+            .unwrap()
+        }
+    }
+
+    fn corrupt(store: &FsBlockStore, cid: &Cid) {
+        std::fs::write(store.path_for(cid), b"not what it was").unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn corrupt_and_missing_blocks_are_repaired_from_the_secondary() {
+        let fixture = fixture().await;
+        corrupt(&fixture.primary, &fixture.root);
+        for block in fixture.primary.list_blocks().await.unwrap() {
+            if block.cid != fixture.root {
+                fixture.primary.delete_block(&block.cid).await.unwrap();
+            }
+        }
+
+        let report = fixture.scrub(Some(&fixture.secondary)).await;
+        assert_eq!((report.buckets, report.objects, report.unreadable), (1, 1, 0));
+        assert!(report.problems.len() > 1);
+        assert_eq!(report.unrepaired(), 0);
+        let corrupt: Vec<_> = report
+            .problems
+            .iter()
+            .filter(|problem| matches!(problem.kind, ProblemKind::Corrupt))
+            .collect();
+        assert_eq!(corrupt.len(), 1);
+        assert_eq!((corrupt[0].cid, corrupt[0].key.as_str()), (fixture.root, ""));
+        assert!(report
+            .problems
+            .iter()
+            .all(|problem| problem.cid == fixture.root || matches!(problem.kind, ProblemKind::Missing)));
+
+        // the primary has everything again, without needing the secondary
+        let report = fixture.scrub(None).await;
+        assert!(report.problems.is_empty());
+        assert_eq!((report.objects, report.unreadable), (1, 0));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn blocks_the_primary_cant_read_come_from_the_secondary_but_arent_put_back() {
+        let fixture = fixture().await;
+        // the primary's directory is gone, so it can't say whether it has anything
+        std::fs::remove_dir_all(&fixture.dirs[0]).unwrap();
+        std::fs::write(&fixture.dirs[0], b"not a directory").unwrap();
+
+        let report = fixture.scrub(Some(&fixture.secondary)).await;
+        assert_eq!((report.objects, report.unreadable), (1, 0));
+        assert!(!report.problems.is_empty());
+        assert!(report
+            .problems
+            .iter()
+            .all(|problem| matches!(problem.kind, ProblemKind::Unreadable(_)) && !problem.repaired));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn without_a_good_copy_bad_blocks_are_left_alone() {
+        let fixture = fixture().await;
+        corrupt(&fixture.primary, &fixture.root);
+        corrupt(&fixture.secondary, &fixture.root);
+
+        for secondary in [None, Some(&fixture.secondary)] {
+            let report = fixture.scrub(secondary).await;
+            assert_eq!(report.problems.len(), 1);
+            assert_eq!(report.problems[0].cid, fixture.root);
+            assert!(matches!(report.problems[0].kind, ProblemKind::Corrupt));
+            assert_eq!(report.unrepaired(), 1);
+            // without its root, nothing in the bucket can be read
+            assert_eq!((report.objects, report.unreadable), (0, 1));
+        }
+        assert!(fixture.primary.get_block(&fixture.root).await.is_err());
+    }
+}
@@ -0,0 +1,462 @@
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use libipld::{
+    cid::Version,
+    multihash::{Code, MultihashDigest},
+    Cid, IpldCodec,
+};
+use lru::LruCache;
+use tokio::sync::mpsc;
+use wnfs::BlockStore;
+
+use crate::{
+    blockstores::{AnyBlockStore, ListedBlock},
+    fs_blockstore::FsBlockStore,
+};
+
+/// how long the write-back flusher waits before trying a failed block again
+const FLUSH_RETRY_DELAY: Duration = Duration::from_secs(5);
+/// how many lookups go by between logging the cache's hit rates
+const LOG_STATS_EVERY: u64 = 10_000;
+
+/// when writes get to the remote store
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum CacheMode {
+    /// a put doesn't return until the remote store has the block
+    WriteThrough,
+    /// a put returns once the block is cached, and it's copied to the remote store in the background.
+    /// blocks that haven't been copied yet are lost if the process dies!
+    WriteBack,
+}
+
+/// the in-memory tier: blocks, most recently used first, up to `capacity` bytes of them
+struct MemoryCache {
+    blocks: LruCache<Cid, Vec<u8>>,
+    bytes: usize,
+    capacity: usize,
+}
+
+impl MemoryCache {
+    fn get(&mut self, cid: &Cid) -> Option<Vec<u8>> {
+        self.blocks.get(cid).cloned()
+    }
+
+    fn insert(&mut self, cid: Cid, bytes: Vec<u8>) {
+        // a block bigger than the whole cache would just push everything else out
+        if bytes.len() > self.capacity {
+            return;
+        }
+        self.bytes += bytes.len();
+        if let Some(old) = self.blocks.put(cid, bytes) {
+            self.bytes -= old.len();
+        }
+        while self.bytes > self.capacity {
+            match self.blocks.pop_lru() {
+                Some((_, evicted)) => self.bytes -= evicted.len(),
+                None => break,
+            }
+        }
+    }
+
+    fn remove(&mut self, cid: &Cid) {
+        if let Some(removed) = self.blocks.pop(cid) {
+            self.bytes -= removed.len();
+        }
+    }
+}
+
+/// how the cache is doing
+#[derive(Debug, Default)]
+pub struct CacheMetrics {
+    pub memory_hits: AtomicU64,
+    pub disk_hits: AtomicU64,
+    pub misses: AtomicU64,
+}
+
+impl CacheMetrics {
+    fn lookups(&self) -> u64 {
+        self.memory_hits.load(Ordering::Relaxed)
+            + self.disk_hits.load(Ordering::Relaxed)
+            + self.misses.load(Ordering::Relaxed)
+    }
+}
+
+impl std::fmt::Display for CacheMetrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} memory hits, {} disk hits, {} misses",
+            self.memory_hits.load(Ordering::Relaxed),
+            self.disk_hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed)
+        )
+    }
+}
+
+/// a caching layer over another blockstore: a bounded LRU in memory, then optionally a directory on local disk,
+/// then the remote store. blocks never change, so there's nothing to invalidate.
+/// the disk tier isn't evicted from, so give it a disk that can hold everything.
+#[derive(Clone)]
+pub struct TieredBlockStore {
+    remote: Box<AnyBlockStore>,
+    memory: Arc<Mutex<MemoryCache>>,
+    disk: Option<FsBlockStore>,
+    mode: CacheMode,
+    /// in write-back mode: blocks that haven't made it to the remote store yet, and where to send them
+    pending: Arc<Mutex<HashMap<Cid, Vec<u8>>>>,
+    flusher: Option<mpsc::UnboundedSender<Cid>>,
+    metrics: Arc<CacheMetrics>,
+}
+
+impl std::fmt::Debug for TieredBlockStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TieredBlockStore")
+            .field("remote", &self.remote)
+            .field("disk", &self.disk)
+            .field("mode", &self.mode)
+            .field("metrics", &self.metrics)
+            .finish()
+    }
+}
+
+impl TieredBlockStore {
+    pub fn new(
+        remote: AnyBlockStore,
+        memory_capacity: usize,
+        disk: Option<FsBlockStore>,
+        mode: CacheMode,
+    ) -> Self {
+        let pending = Arc::new(Mutex::new(HashMap::new()));
+        let flusher = match mode {
+            CacheMode::WriteThrough => None,
+            CacheMode::WriteBack => Some(spawn_flusher(remote.clone(), pending.clone())),
+        };
+        Self {
+            remote: Box::new(remote),
+            memory: Arc::new(Mutex::new(MemoryCache {
+                blocks: LruCache::unbounded(),
+                bytes: 0,
+                capacity: memory_capacity,
+            })),
+            disk,
+            mode,
+            pending,
+            flusher,
+            metrics: Default::default(),
+        }
+    }
+
+    /// how the cache has done since it was made, across every clone
+    pub fn metrics(&self) -> &CacheMetrics {
+        &self.metrics
+    }
+
+    /// lists the blocks in the remote store. blocks still waiting to be written back aren't in it yet.
+    pub async fn list_blocks(&self) -> Result<Vec<ListedBlock>> {
+        self.remote.list_blocks().await
+    }
+
+    /// removes a block from the remote store and every cache, and stops it being written back if it hasn't been yet
+    pub async fn delete_block(&self, cid: &Cid) -> Result<()> {
+        self.pending.lock().unwrap().remove(cid);
+        self.memory.lock().unwrap().remove(cid);
+        if let Some(disk) = &self.disk {
+            disk.delete_block(cid).await?;
+        }
+        self.remote.delete_block(cid).await
+    }
+
+    fn remember(&self, cid: Cid, bytes: Vec<u8>) {
+        self.memory.lock().unwrap().insert(cid, bytes);
+    }
+
+    fn count(&self, counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+        if self.metrics().lookups().is_multiple_of(LOG_STATS_EVERY) {
+            log::debug!("tiered blockstore: {}", self.metrics());
+        }
+    }
+}
+
+/// copies write-back blocks to the remote store, one at a time, until they all make it.
+/// remote blockstore futures aren't Send, so this gets a thread of its own rather than a task.
+fn spawn_flusher(
+    mut remote: AnyBlockStore,
+    pending: Arc<Mutex<HashMap<Cid, Vec<u8>>>>,
+) -> mpsc::UnboundedSender<Cid> {
+    let (sender, mut receiver) = mpsc::unbounded_channel::<Cid>();
+    let retry = sender.clone();
+    let handle = tokio::runtime::Handle::current();
+    std::thread::spawn(move || {
+        handle.block_on(async move {
+            while let Some(cid) = receiver.recv().await {
+                let Some(bytes) = pending.lock().unwrap().get(&cid).cloned() else {
+                    continue;
+                };
+                let codec = match IpldCodec::try_from(cid.codec()) {
+                    Ok(codec) => codec,
+                    Err(e) => {
+                        log::error!(
+                            "tiered blockstore: can't flush block {} with an unknown codec: {}",
+                            cid,
+                            e
+                        );
+                        continue;
+                    }
+                };
+                match remote.put_block(bytes, codec).await {
+                    Ok(_) => {
+                        // it was deleted while we were writing it back, so the write has to be undone
+                        if pending.lock().unwrap().remove(&cid).is_none() {
+                            if let Err(e) = remote.delete_block(&cid).await {
+                                log::error!(
+                                    "tiered blockstore: couldn't delete block {} after flushing it, though it was deleted while it was being flushed. error was {}",
+                                    cid,
+                                    e
+                                );
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        log::error!(
+                            "tiered blockstore: couldn't flush block {}. trying again in {:?}. error was {}",
+                            cid,
+                            FLUSH_RETRY_DELAY,
+                            e
+                        );
+                        tokio::time::sleep(FLUSH_RETRY_DELAY).await;
+                        let _ = retry.send(cid);
+                    }
+                }
+            }
+        })
+    });
+    sender
+}
+
+#[async_trait(?Send)]
+impl BlockStore for TieredBlockStore {
+    async fn put_block(&mut self, bytes: Vec<u8>, codec: IpldCodec) -> Result<Cid> {
+        let cid = match self.mode {
+            CacheMode::WriteThrough => self.remote.put_block(bytes.clone(), codec).await?,
+            CacheMode::WriteBack => {
+                let hash = Code::Sha2_256.digest(&bytes);
+                Cid::new(Version::V1, codec.into(), hash)?
+            }
+        };
+        if let Some(disk) = &mut self.disk {
+            if let Err(e) = disk.put_block(bytes.clone(), codec).await {
+                log::warn!("tiered blockstore: couldn't cache block {} on disk: {}", cid, e);
+            }
+        }
+        if let Some(flusher) = &self.flusher {
+            self.pending.lock().unwrap().insert(cid, bytes.clone());
+            flusher.send(cid)?;
+        }
+        self.remember(cid, bytes);
+
+        Ok(cid)
+    }
+
+    async fn get_block<'a>(&'a self, cid: &Cid) -> Result<Cow<'a, Vec<u8>>> {
+        if let Some(bytes) = self.memory.lock().unwrap().get(cid) {
+            self.count(&self.metrics.memory_hits);
+            return Ok(Cow::Owned(bytes));
+        }
+        if let Some(bytes) = self.pending.lock().unwrap().get(cid).cloned() {
+            self.count(&self.metrics.memory_hits);
+            return Ok(Cow::Owned(bytes));
+        }
+        if let Some(disk) = &self.disk {
+            if let Ok(bytes) = disk.get_block(cid).await {
+                self.count(&self.metrics.disk_hits);
+                let bytes = bytes.into_owned();
+                self.remember(*cid, bytes.clone());
+                return Ok(Cow::Owned(bytes));
+            }
+        }
+        self.count(&self.metrics.misses);
+        let bytes = self.remote.get_block(cid).await?.into_owned();
+        if let Some(disk) = &self.disk {
+            // put_block wants &mut, but a clone writes to the same directory
+            let codec = IpldCodec::try_from(cid.codec())?;
+            if let Err(e) = disk.clone().put_block(bytes.clone(), codec).await {
+                log::warn!("tiered blockstore: couldn't cache block {} on disk: {}", cid, e);
+            }
+        }
+        self.remember(*cid, bytes.clone());
+
+        Ok(Cow::Owned(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, net::SocketAddr};
+
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Body, Request, Response, Server,
+    };
+    use reqwest::Url;
+    use tokio::sync::Notify;
+
+    use crate::{kubo_blockstore::KuboBlockStore, mutex_memory_blockstore::MutexMemoryBlockStore};
+
+    use super::*;
+
+    fn cid_of(bytes: &[u8]) -> Cid {
+        Cid::new_v1(IpldCodec::Raw.into(), Code::Sha2_256.digest(bytes))
+    }
+
+    #[test]
+    fn the_memory_tier_evicts_least_recently_used_first_by_bytes() {
+        let mut cache = MemoryCache {
+            blocks: LruCache::unbounded(),
+            bytes: 0,
+            capacity: 10,
+        };
+        let (a, b, c) = (cid_of(b"a"), cid_of(b"b"), cid_of(b"c"));
+        cache.insert(a, vec![0; 4]);
+        cache.insert(b, vec![0; 4]);
+        assert!(cache.get(&a).is_some());
+        cache.insert(c, vec![0; 4]);
+        assert!(cache.get(&b).is_none());
+        assert!(cache.get(&a).is_some() && cache.get(&c).is_some());
+        assert_eq!(cache.bytes, 8);
+        // too big to cache at all, so it doesn't push anything out
+        cache.insert(b, vec![0; 11]);
+        assert!(cache.get(&b).is_none());
+        assert_eq!(cache.bytes, 8);
+        cache.remove(&a);
+        assert_eq!(cache.bytes, 4);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn lookups_are_counted_by_tier() {
+        let remote = MutexMemoryBlockStore::new();
+        let mut store = TieredBlockStore::new(
+            AnyBlockStore::Memory(remote.clone()),
+            10,
+            None,
+            CacheMode::WriteThrough,
+        );
+        let a = store.put_block(vec![1; 4], IpldCodec::Raw).await.unwrap();
+        let b = store.put_block(vec![2; 4], IpldCodec::Raw).await.unwrap();
+        store.get_block(&a).await.unwrap();
+        store.put_block(vec![3; 4], IpldCodec::Raw).await.unwrap();
+        // b was pushed out, so it's read from the remote store, which put it there on the way through
+        assert_eq!(*store.get_block(&b).await.unwrap(), vec![2; 4]);
+        assert_eq!(remote.list_blocks().unwrap().len(), 3);
+        let metrics = store.metrics();
+        assert_eq!(metrics.memory_hits.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.misses.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.to_string(), "1 memory hits, 0 disk hits, 1 misses");
+    }
+
+    /// waits for `what` to come true, for up to a second
+    async fn eventually(what: impl Fn() -> bool) {
+        for _ in 0..100 {
+            if what() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("it never happened");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn write_back_flushes_to_the_remote_store() {
+        let remote = MutexMemoryBlockStore::new();
+        let mut store = TieredBlockStore::new(
+            AnyBlockStore::Memory(remote.clone()),
+            0,
+            None,
+            CacheMode::WriteBack,
+        );
+        let cid = store.put_block(b"hello".to_vec(), IpldCodec::Raw).await.unwrap();
+        // the memory tier can't hold anything, so this comes from the blocks waiting to be flushed, or the remote store
+        assert_eq!(*store.get_block(&cid).await.unwrap(), b"hello");
+        eventually(|| remote.get_stored(&cid).is_ok()).await;
+        eventually(|| store.pending.lock().unwrap().is_empty()).await;
+    }
+
+    /// a kubo whose block/put doesn't answer until it's told to, and which records which methods it was called with
+    struct StallingKubo {
+        endpoint: Url,
+        /// notified when a block/put comes in
+        arrived: Arc<Notify>,
+        /// notify to let the block/put answer
+        release: Arc<Notify>,
+        calls: Arc<Mutex<Vec<String>>>,
+    }
+
+    fn stalling_kubo(cid: Cid) -> StallingKubo {
+        let (arrived, release) = (Arc::new(Notify::new()), Arc::new(Notify::new()));
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let (served_arrived, served_release, recorded) = (arrived.clone(), release.clone(), calls.clone());
+        let make_service = make_service_fn(move |_| {
+            let (arrived, release, calls) = (served_arrived.clone(), served_release.clone(), recorded.clone());
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let method = request.uri().path().trim_start_matches("/api/v0/").to_string();
+                    let (arrived, release, calls) = (arrived.clone(), release.clone(), calls.clone());
+                    async move {
+                        if method == "block/put" {
+                            arrived.notify_one();
+                            release.notified().await;
+                        }
+                        calls.lock().unwrap().push(method);
+                        Ok::<_, Infallible>(Response::new(Body::from(format!(r#"{{"Key":"{}"}}"#, cid))))
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let endpoint = Url::parse(&format!("http://{}", server.local_addr())).unwrap();
+        tokio::spawn(server);
+        StallingKubo {
+            endpoint,
+            arrived,
+            release,
+            calls,
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn blocks_deleted_while_being_flushed_are_deleted_again_after() {
+        let cid = cid_of(b"hello");
+        let StallingKubo {
+            endpoint,
+            arrived,
+            release,
+            calls,
+        } = stalling_kubo(cid);
+        let remote = AnyBlockStore::Kubo(KuboBlockStore::new(endpoint, false));
+        let mut store = TieredBlockStore::new(remote, 1024, None, CacheMode::WriteBack);
+        store.put_block(b"hello".to_vec(), IpldCodec::Raw).await.unwrap();
+
+        arrived.notified().await;
+        store.delete_block(&cid).await.unwrap();
+        assert!(store.get_block(&cid).await.is_err());
+        release.notify_one();
+        // the flush lands after the delete, so the flusher takes it back out
+        let writes = || -> Vec<String> {
+            let calls = calls.lock().unwrap();
+            calls.iter().filter(|method| *method != "block/get").cloned().collect()
+        };
+        eventually(|| writes().len() == 3).await;
+        assert_eq!(writes(), ["block/rm", "block/put", "block/rm"]);
+    }
+}
+
@@ -0,0 +1,288 @@
+//! just enough UnixFS to store files the way IPFS does, so public buckets' objects can be fetched from any gateway.
+//! files are trees of dag-pb nodes over raw leaves, like `ipfs add --cid-version 1 --raw-leaves` makes.
+
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Result};
+use libipld::{codec::Codec, pb::DagPbCodec, Cid, Ipld, IpldCodec};
+use wnfs::BlockStore;
+
+/// how much of a file goes in each leaf. the same as kubo's default.
+pub const CHUNK_SIZE: usize = 256 * 1024;
+/// how many children a node gets. the same as kubo's default.
+const MAX_LINKS: usize = 174;
+/// the UnixFS Data type of a file
+const FILE_TYPE: u64 = 2;
+
+/// a link to a file, or to part of one
+#[derive(Debug, Clone, Copy)]
+pub struct UnixfsLink {
+    pub cid: Cid,
+    /// how many bytes of the file are under the link
+    pub size: u64,
+    /// how many bytes of blocks are under the link, which dag-pb links carry
+    pub tsize: u64,
+}
+
+fn put_varint(out: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        out.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+/// a protobuf field of wire type 0
+fn put_varint_field(out: &mut Vec<u8>, field: u64, n: u64) {
+    put_varint(out, field << 3);
+    put_varint(out, n);
+}
+
+/// a protobuf field of wire type 2
+fn put_bytes_field(out: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    put_varint(out, field << 3 | 2);
+    put_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+/// a dag-pb node for a file made of `links`. links come before data, as dag-pb wants.
+fn file_node(links: &[UnixfsLink]) -> Vec<u8> {
+    let mut node = Vec::new();
+    for link in links {
+        let mut pb_link = Vec::new();
+        put_bytes_field(&mut pb_link, 1, &link.cid.to_bytes());
+        put_bytes_field(&mut pb_link, 2, b"");
+        put_varint_field(&mut pb_link, 3, link.tsize);
+        put_bytes_field(&mut node, 2, &pb_link);
+    }
+    let mut data = Vec::new();
+    put_varint_field(&mut data, 1, FILE_TYPE);
+    put_varint_field(&mut data, 3, links.iter().map(|link| link.size).sum());
+    for link in links {
+        put_varint_field(&mut data, 4, link.size);
+    }
+    put_bytes_field(&mut node, 1, &data);
+    node
+}
+
+/// stores a run of a file as raw leaves
+pub async fn write_leaves(content: &[u8], store: &mut impl BlockStore) -> Result<Vec<UnixfsLink>> {
+    let mut leaves = Vec::with_capacity(content.len() / CHUNK_SIZE + 1);
+    for chunk in content.chunks(CHUNK_SIZE) {
+        let cid = store.put_block(chunk.to_vec(), IpldCodec::Raw).await?;
+        leaves.push(UnixfsLink {
+            cid,
+            size: chunk.len() as u64,
+            tsize: chunk.len() as u64,
+        });
+    }
+    Ok(leaves)
+}
+
+/// builds a file out of its leaves (or any runs of it, in order), and returns the link to its root
+pub async fn write_tree(mut links: Vec<UnixfsLink>, store: &mut impl BlockStore) -> Result<UnixfsLink> {
+    if links.is_empty() {
+        let node = file_node(&[]);
+        let tsize = node.len() as u64;
+        let cid = store.put_block(node, IpldCodec::DagPb).await?;
+        return Ok(UnixfsLink { cid, size: 0, tsize });
+    }
+    while links.len() > 1 {
+        let mut parents = Vec::with_capacity(links.len() / MAX_LINKS + 1);
+        for children in links.chunks(MAX_LINKS) {
+            let node = file_node(children);
+            let tsize = node.len() as u64 + children.iter().map(|child| child.tsize).sum::<u64>();
+            let cid = store.put_block(node, IpldCodec::DagPb).await?;
+            parents.push(UnixfsLink {
+                cid,
+                size: children.iter().map(|child| child.size).sum(),
+                tsize,
+            });
+        }
+        links = parents;
+    }
+    Ok(links[0])
+}
+
+/// a protobuf field's number, and its value: a varint, or length-delimited bytes
+type Field = (u64, Result<u64, Vec<u8>>);
+
+/// the fields of a protobuf message we care about: varints, and length-delimited bytes
+fn read_fields(mut message: &[u8]) -> Result<Vec<Field>> {
+    fn varint(bytes: &mut &[u8]) -> Result<u64> {
+        let mut n = 0u64;
+        for shift in (0..64).step_by(7) {
+            let (&byte, rest) = bytes
+                .split_first()
+                .ok_or_else(|| anyhow!("unixfs: truncated varint"))?;
+            *bytes = rest;
+            n |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        Err(anyhow!("unixfs: varint too long"))
+    }
+    let mut fields = Vec::new();
+    while !message.is_empty() {
+        let key = varint(&mut message)?;
+        match key & 7 {
+            0 => fields.push((key >> 3, Ok(varint(&mut message)?))),
+            2 => {
+                let len = varint(&mut message)? as usize;
+                if len > message.len() {
+                    return Err(anyhow!("unixfs: truncated field"));
+                }
+                let (bytes, rest) = message.split_at(len);
+                fields.push((key >> 3, Err(bytes.to_vec())));
+                message = rest;
+            }
+            wire_type => return Err(anyhow!("unixfs: unexpected wire type {}", wire_type)),
+        }
+    }
+    Ok(fields)
+}
+
+/// the links and UnixFS data of a dag-pb node
+fn decode_node(bytes: &[u8]) -> Result<(Vec<Cid>, Vec<u8>)> {
+    let Ipld::Map(node) = DagPbCodec.decode::<Ipld>(bytes)? else {
+        return Err(anyhow!("unixfs: dag-pb node isn't a map"));
+    };
+    let links = match node.get("Links") {
+        Some(Ipld::List(links)) => links
+            .iter()
+            .map(|link| match link {
+                Ipld::Map(link) => match link.get("Hash") {
+                    Some(Ipld::Link(cid)) => Ok(*cid),
+                    _ => Err(anyhow!("unixfs: link without a hash")),
+                },
+                _ => Err(anyhow!("unixfs: link isn't a map")),
+            })
+            .collect::<Result<_>>()?,
+        _ => Vec::new(),
+    };
+    let data = match node.get("Data") {
+        Some(Ipld::Bytes(data)) => data.clone(),
+        _ => Vec::new(),
+    };
+    Ok((links, data))
+}
+
+/// how big the file at `cid` is, from its root alone
+pub async fn file_size(cid: &Cid, store: &impl BlockStore) -> Result<u64> {
+    let bytes = store.get_block(cid).await?;
+    match IpldCodec::try_from(cid.codec())? {
+        IpldCodec::Raw => Ok(bytes.len() as u64),
+        IpldCodec::DagPb => {
+            let (_, data) = decode_node(&bytes)?;
+            let fields: BTreeMap<u64, _> = read_fields(&data)?.into_iter().collect();
+            match fields.get(&3) {
+                Some(Ok(size)) => Ok(*size),
+                _ => Err(anyhow!("unixfs: {} doesn't say how big it is", cid)),
+            }
+        }
+        codec => Err(anyhow!("unixfs: {} is {:?}, not a file", cid, codec)),
+    }
+}
+
+/// reads a file a block at a time, whoever wrote it, as long as it's a file.
+/// the first `skip` bytes aren't handed out, and whole subtrees of them aren't even fetched when their parents say how big they are.
+pub struct FileReader {
+    /// what's left to read, next last, with how many bytes of the file are under each if we know
+    stack: Vec<(Cid, Option<u64>)>,
+    skip: u64,
+}
+
+impl FileReader {
+    pub fn new(cid: Cid, skip: u64) -> Self {
+        Self {
+            stack: vec![(cid, None)],
+            skip,
+        }
+    }
+
+    /// the next run of the file's bytes, or None once it's all been read
+    pub async fn next_chunk(&mut self, store: &impl BlockStore) -> Result<Option<Vec<u8>>> {
+        // depth first, left to right, is the order of the file's bytes
+        while let Some((cid, size)) = self.stack.pop() {
+            if let Some(size) = size.filter(|size| *size <= self.skip) {
+                self.skip -= size;
+                continue;
+            }
+            let bytes = store.get_block(&cid).await?;
+            let mut chunk = match IpldCodec::try_from(cid.codec())? {
+                IpldCodec::Raw => bytes.into_owned(),
+                IpldCodec::DagPb => {
+                    let (links, data) = decode_node(&bytes)?;
+                    let fields = read_fields(&data)?;
+                    if !fields
+                        .iter()
+                        .any(|(field, value)| *field == 1 && matches!(value, Ok(FILE_TYPE) | Ok(0)))
+                    {
+                        return Err(anyhow!("unixfs: {} isn't part of a file", cid));
+                    }
+                    // data in a node comes before the data of its children
+                    let mut content = Vec::new();
+                    let mut sizes = Vec::new();
+                    for (field, value) in fields {
+                        match (field, value) {
+                            (2, Err(data)) => content.extend(data),
+                            (4, Ok(size)) => sizes.push(size),
+                            _ => {}
+                        }
+                    }
+                    // blocksizes only help if there's one for every link
+                    if sizes.len() == links.len() {
+                        self.stack
+                            .extend(links.into_iter().zip(sizes.into_iter().map(Some)).rev());
+                    } else {
+                        self.stack.extend(links.into_iter().map(|link| (link, None)).rev());
+                    }
+                    content
+                }
+                codec => return Err(anyhow!("unixfs: {} is {:?}, not part of a file", cid, codec)),
+            };
+            let skipped = self.skip.min(chunk.len() as u64);
+            chunk.drain(..skipped as usize);
+            self.skip -= skipped;
+            if !chunk.is_empty() {
+                return Ok(Some(chunk));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// reads the whole file at `cid`, whoever wrote it, as long as it's a file
+pub async fn read_file(cid: &Cid, store: &impl BlockStore) -> Result<Vec<u8>> {
+    let mut content = Vec::new();
+    let mut reader = FileReader::new(*cid, 0);
+    while let Some(chunk) = reader.next_chunk(store).await? {
+        content.extend(chunk);
+    }
+    Ok(content)
+}
+
+#[cfg(test)]
+mod tests {
+    use wnfs::MemoryBlockStore;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn reads_skip_ahead() {
+        let store = &mut MemoryBlockStore::default();
+        let content: Vec<u8> = (0..3 * CHUNK_SIZE + 5).map(|i| i as u8).collect();
+        let leaves = write_leaves(&content, store).await.unwrap();
+        let file = write_tree(leaves, store).await.unwrap();
+        assert_eq!(read_file(&file.cid, store).await.unwrap(), content);
+
+        let skip = CHUNK_SIZE + 3;
+        let mut reader = FileReader::new(file.cid, skip as u64);
+        let mut read = Vec::new();
+        while let Some(chunk) = reader.next_chunk(store).await.unwrap() {
+            read.extend(chunk);
+        }
+        assert_eq!(read, content[skip..]);
+    }
+}
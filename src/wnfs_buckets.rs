@@ -0,0 +1,1261 @@
+use std::{collections::BTreeSet, future::Future, pin::Pin, rc::Rc, str::FromStr, sync::Arc};
+
+use anyhow::anyhow;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::{channel::mpsc, SinkExt, Stream, StreamExt, TryStreamExt};
+use md5::{Digest, Md5};
+use rand::RngCore;
+use s3s::{s3_error, S3Result};
+use serde::{Deserialize, Serialize};
+use wnfs::{
+    ipld::{Cid, Ipld},
+    private::{PrivateDirectory, PrivateFile, PrivateForest, PrivateNode},
+    public::{PublicDirectory, PublicNode},
+    BlockStore, Metadata, Namefilter,
+};
+
+use crate::{
+    blockstores::AnyBlockStore,
+    bucket_registry::{
+        is_snapshot, saturated_name_hash, snapshot_id, BucketRegistry, BucketRoot, RootCommit, Snapshot,
+        StoredPrivateRef, SNAPSHOT_SEPARATOR,
+    },
+    unixfs::{self, UnixfsLink},
+};
+
+/// wnfs futures aren't Send (they're full of Rc), so they can't be held across awaits in the S3 handlers.
+/// this runs one to completion on a blocking thread and hands back its output.
+pub(crate) async fn run_wnfs<F, Fut, T>(f: F) -> S3Result<T>
+where
+    F: FnOnce() -> Fut + Send + 'static,
+    Fut: Future<Output = S3Result<T>>,
+    T: Send + 'static,
+{
+    let handle = tokio::runtime::Handle::current();
+    tokio::task::spawn_blocking(move || handle.block_on(f()))
+        .await
+        .map_err(|e| {
+            log::error!("wnfs buckets: wnfs task panicked: {}", e);
+            s3_error!(InternalError, "internal error")
+        })?
+}
+
+/// run_wnfs for everything that isn't answering an S3 request, like gc and the admin commands,
+/// which hand back the whole error rather than an S3 one.
+pub(crate) async fn run_wnfs_anyhow<F, Fut, T>(f: F) -> anyhow::Result<T>
+where
+    F: FnOnce() -> Fut + Send + 'static,
+    Fut: Future<Output = anyhow::Result<T>>,
+    T: Send + 'static,
+{
+    let handle = tokio::runtime::Handle::current();
+    tokio::task::spawn_blocking(move || handle.block_on(f())).await?
+}
+
+fn transmute_result_for_s3error<T>(res: anyhow::Result<T>) -> S3Result<T> {
+    res.map_err(|e| {
+        log::error!("wnfs error: {:?}", e);
+        s3_error!(InternalError, "internal error")
+    })
+}
+
+/// where composite objects' segment lists live in a bucket's tree, so they can't collide with real keys
+pub(crate) const COMPOSITE_DIR: &str = ".s3-composite";
+/// how much of an object we buffer before encrypting it into a segment
+pub(crate) const SEGMENT_SIZE: usize = 16 * 1024 * 1024;
+/// where a plain file's metadata says how big it is, so it can be listed without being read
+const SIZE_METADATA: &str = "size";
+/// where a plain file's metadata keeps the etag it was written with
+const ETAG_METADATA: &str = "etag";
+/// how many chunks of an object can be read ahead of the client it's going to
+const READ_AHEAD: usize = 4;
+
+/// how many times a change to a bucket is redone on top of other changes that beat it to the registry before giving up
+const ROOT_UPDATE_ATTEMPTS: usize = 5;
+
+fn root_update_conflict(bucket_name: &str) -> s3s::S3Error {
+    s3_error!(
+        OperationAborted,
+        "Bucket {} kept changing while we tried to write to it, try again",
+        bucket_name
+    )
+}
+
+/// a run of an object's bytes, stored as a file in a forest of its own.
+/// segments don't belong to any bucket until a composite object links them in.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Segment {
+    pub forest_cid: String,
+    pub file_ref: StoredPrivateRef,
+    pub size: u64,
+}
+
+/// an object made out of segments that were written before it was, like a completed multipart upload.
+/// linking one into a bucket only writes the list, so it costs the same however big the object is.
+/// it can't be a PrivateFile made of the segments' blocks: wnfs encrypts all of a file's blocks under one key of the file's,
+/// finds them by labels made from that key, and cuts them all to the same size, none of which it lets us set.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CompositeObject {
+    pub segments: Vec<Segment>,
+    /// the etag the object was written with, quotes and all
+    pub etag: String,
+}
+
+/// turns an object key into the path segments of the file that holds it
+pub(crate) fn key_to_path(key: &str) -> Vec<String> {
+    key.split('/').map(|s| s.to_string()).collect()
+}
+
+/// loads a private bucket's forest and root directory out of the blockstore
+pub(crate) async fn open_bucket(
+    root: &BucketRoot,
+    store: &impl BlockStore,
+) -> anyhow::Result<(Rc<PrivateForest>, Rc<PrivateDirectory>)> {
+    let BucketRoot::Private { forest_cid, root_ref } = root else {
+        return Err(anyhow!("bucket is public, so it has no forest"));
+    };
+    let forest_cid = Cid::from_str(forest_cid)?;
+    let forest: Rc<PrivateForest> = Rc::new(store.get_deserializable(&forest_cid).await?);
+    let root_dir = forest
+        .get(&root_ref.to_private_ref()?, PrivateForest::resolve_lowest, store)
+        .await?
+        .ok_or_else(|| anyhow!("root directory missing from forest {}", forest_cid))?
+        .as_dir()?;
+    Ok((forest, root_dir))
+}
+
+/// stores a bucket's forest after a change, and returns the root to point the bucket at
+pub(crate) async fn store_root(
+    forest: &Rc<PrivateForest>,
+    root_dir: &Rc<PrivateDirectory>,
+    store: &mut impl BlockStore,
+) -> anyhow::Result<BucketRoot> {
+    let forest_cid = store.put_async_serializable(forest).await?;
+    Ok(BucketRoot::Private {
+        forest_cid: forest_cid.to_string(),
+        root_ref: StoredPrivateRef::of(&root_dir.header),
+    })
+}
+
+/// loads a public bucket's root directory out of the blockstore
+pub(crate) async fn open_public(
+    public_root_cid: &str,
+    store: &impl BlockStore,
+) -> anyhow::Result<Rc<PublicDirectory>> {
+    let root_cid = Cid::from_str(public_root_cid)?;
+    Ok(Rc::new(store.get_deserializable(&root_cid).await?))
+}
+
+/// stores a public bucket's root directory after a change, and returns the root to point the bucket at
+pub(crate) async fn store_public_root(
+    root_dir: &Rc<PublicDirectory>,
+    store: &mut impl BlockStore,
+) -> anyhow::Result<BucketRoot> {
+    let root_cid = store.put_async_serializable(root_dir).await?;
+    Ok(BucketRoot::Public {
+        public_root_cid: root_cid.to_string(),
+    })
+}
+
+/// where a composite object's segment list lives
+fn composite_path(key: &str) -> Vec<String> {
+    let mut path = vec![COMPOSITE_DIR.to_string()];
+    path.extend(key_to_path(key));
+    path
+}
+
+/// finds the segments of every composite object in a bucket.
+/// segment lists are encrypted inside the bucket, so opening it is the only way to know which segments it uses.
+/// public buckets don't have composite objects: their files are UnixFS all the way down.
+pub(crate) async fn composite_segments(
+    root: &BucketRoot,
+    store: &impl BlockStore,
+) -> anyhow::Result<Vec<Segment>> {
+    if root.is_public() {
+        return Ok(Vec::new());
+    }
+    let (forest, root_dir) = open_bucket(root, store).await?;
+    let mut segments = Vec::new();
+    let mut dirs = vec![vec![COMPOSITE_DIR.to_string()]];
+    while let Some(dir) = dirs.pop() {
+        let entries = match root_dir.clone().ls(&dir, true, forest.clone(), store).await {
+            Ok(op) => op.result,
+            // the bucket has never had a composite object
+            Err(_) if dir.len() == 1 => continue,
+            Err(e) => return Err(e),
+        };
+        for (name, _) in entries {
+            let mut path = dir.clone();
+            path.push(name);
+            // anything that isn't a file is a directory of more segment lists
+            match root_dir.clone().read(&path, true, forest.clone(), store).await {
+                Ok(op) => {
+                    let composite: CompositeObject = serde_json::from_slice(&op.result)?;
+                    segments.extend(composite.segments);
+                }
+                Err(_) => dirs.push(path),
+            }
+        }
+    }
+    Ok(segments)
+}
+
+/// reads the content of a segment back out of its forest
+pub(crate) async fn read_segment(segment: &Segment, store: &impl BlockStore) -> anyhow::Result<Vec<u8>> {
+    let forest_cid = Cid::from_str(&segment.forest_cid)?;
+    let forest: PrivateForest = store.get_deserializable(&forest_cid).await?;
+    let file = forest
+        .get(&segment.file_ref.to_private_ref()?, PrivateForest::resolve_lowest, store)
+        .await?
+        .ok_or_else(|| anyhow!("segment missing from forest {}", forest_cid))?
+        .as_file()?;
+    file_content(&file, &forest, store).await
+}
+
+/// reads a private file's whole content. PrivateFile::get_content panics on a block it can't read, this errors instead.
+pub(crate) async fn file_content(
+    file: &PrivateFile,
+    forest: &PrivateForest,
+    store: &impl BlockStore,
+) -> anyhow::Result<Vec<u8>> {
+    let mut content = Vec::new();
+    let mut chunks = Box::pin(file.stream_content(0, forest, store));
+    while let Some(chunk) = chunks.next().await {
+        content.extend_from_slice(&chunk?);
+    }
+    Ok(content)
+}
+
+/// writes a plain file at `path`, replacing whatever was there, with its size and `etag` in its metadata.
+/// PrivateDirectory::write can't take metadata, so the file it writes is swapped in the forest for a copy that has it.
+/// the directory only points at the file's name and keys, not its block, so it doesn't need to change.
+pub(crate) async fn write_plain_file(
+    root_dir: Rc<PrivateDirectory>,
+    path: &[String],
+    content: Vec<u8>,
+    etag: &str,
+    forest: Rc<PrivateForest>,
+    store: &mut impl BlockStore,
+    rng: &mut impl RngCore,
+) -> anyhow::Result<(Rc<PrivateForest>, Rc<PrivateDirectory>)> {
+    let size = content.len();
+    let op = root_dir
+        .write(path, true, Utc::now(), content, forest, store, rng)
+        .await?;
+    let Some(PrivateNode::File(file)) = op
+        .root_dir
+        .clone()
+        .get_node(path, true, op.forest.clone(), store)
+        .await?
+        .result
+    else {
+        return Err(anyhow!("the file just written to {:?} isn't there", path));
+    };
+    let mut file = (*file).clone();
+    file.metadata
+        .0
+        .insert(SIZE_METADATA.to_string(), Ipld::Integer(size as i128));
+    file.metadata
+        .0
+        .insert(ETAG_METADATA.to_string(), Ipld::String(etag.to_string()));
+    let (forest, _) = op
+        .forest
+        .remove_encrypted(&saturated_name_hash(&file.header), store)
+        .await?;
+    let forest = forest
+        .put(
+            file.header.get_saturated_name(),
+            &file.header.derive_private_ref(),
+            &PrivateNode::File(Rc::new(file)),
+            store,
+            rng,
+        )
+        .await?;
+    Ok((forest, op.root_dir))
+}
+
+/// what we can say about an object without handing over its content
+#[derive(Debug, Clone)]
+pub struct ObjectInfo {
+    pub key: String,
+    pub size: u64,
+    /// the cid of the block the object's file is encrypted into, which is what the bucket's forest points at.
+    /// for a composite object, that's the file holding its segment list.
+    /// in a public bucket, it's the root of the object's UnixFS file, which any IPFS gateway can serve.
+    pub cid: Cid,
+    /// quotes and all
+    pub etag: String,
+    pub last_modified: DateTime<Utc>,
+}
+
+/// the etag a plain file was written with
+pub(crate) fn file_etag(file: &PrivateFile) -> anyhow::Result<String> {
+    match file.metadata.0.get(ETAG_METADATA) {
+        Some(Ipld::String(etag)) => Ok(etag.clone()),
+        _ => Err(anyhow!("file has no etag")),
+    }
+}
+
+/// when a file or a public one was last written
+fn modified(metadata: &Metadata) -> anyhow::Result<DateTime<Utc>> {
+    metadata
+        .get_modified()
+        .ok_or_else(|| anyhow!("file has no modification time"))
+}
+
+/// public files have nowhere to keep an etag, so theirs is made from their cid, which only changes with their content.
+/// it's hashed so it doesn't give the cid away to clients that didn't ask for it.
+fn public_etag(cid: &Cid) -> String {
+    format!("\"{}\"", hex::encode(Md5::digest(cid.to_bytes())))
+}
+
+/// the cid a forest keeps a file's node under
+async fn file_cid(
+    file: &PrivateFile,
+    forest: &PrivateForest,
+    store: &impl BlockStore,
+) -> anyhow::Result<Cid> {
+    let name_hash = saturated_name_hash(&file.header);
+    forest
+        .get_encrypted(&name_hash, store)
+        .await?
+        .and_then(|cids| cids.iter().next().copied())
+        .ok_or_else(|| anyhow!("file missing from forest"))
+}
+
+/// the file at `path`, if there is one
+async fn get_file(
+    root_dir: &Rc<PrivateDirectory>,
+    path: &[String],
+    forest: &Rc<PrivateForest>,
+    store: &impl BlockStore,
+) -> Option<Rc<PrivateFile>> {
+    match root_dir.clone().get_node(path, true, forest.clone(), store).await {
+        Ok(op) => match op.result {
+            Some(PrivateNode::File(file)) => Some(file),
+            _ => None,
+        },
+        Err(e) => {
+            log::debug!("wnfs buckets: couldn't get {:?}: {}", path, e);
+            None
+        }
+    }
+}
+
+/// works out an object's info, whether it's a plain file or a composite
+async fn object_info_in(
+    root_dir: &Rc<PrivateDirectory>,
+    forest: &Rc<PrivateForest>,
+    key: &str,
+    store: &impl BlockStore,
+) -> anyhow::Result<Option<ObjectInfo>> {
+    if let Some(file) = get_file(root_dir, &key_to_path(key), forest, store).await {
+        let Some(Ipld::Integer(size)) = file.metadata.0.get(SIZE_METADATA) else {
+            anyhow::bail!("{} has no size", key);
+        };
+        return Ok(Some(ObjectInfo {
+            key: key.to_string(),
+            size: *size as u64,
+            cid: file_cid(&file, forest, store).await?,
+            etag: file_etag(&file)?,
+            last_modified: modified(&file.metadata)?,
+        }));
+    }
+    if let Some(file) = get_file(root_dir, &composite_path(key), forest, store).await {
+        let composite: CompositeObject =
+            serde_json::from_slice(&file_content(&file, forest, store).await?)?;
+        return Ok(Some(ObjectInfo {
+            key: key.to_string(),
+            size: composite.segments.iter().map(|segment| segment.size).sum(),
+            cid: file_cid(&file, forest, store).await?,
+            etag: composite.etag,
+            last_modified: modified(&file.metadata)?,
+        }));
+    }
+    Ok(None)
+}
+
+/// every key in a bucket starting with `prefix`, plain files and composites alike, in order
+async fn keys_in(
+    root_dir: &Rc<PrivateDirectory>,
+    forest: &Rc<PrivateForest>,
+    prefix: &str,
+    store: &impl BlockStore,
+) -> anyhow::Result<BTreeSet<String>> {
+    let mut keys = BTreeSet::new();
+    let mut dirs = vec![(vec![], false), (vec![COMPOSITE_DIR.to_string()], true)];
+    while let Some((dir, composite)) = dirs.pop() {
+        let entries = match root_dir.clone().ls(&dir, true, forest.clone(), store).await {
+            Ok(op) => op.result,
+            // the bucket has never had a composite object
+            Err(_) if composite && dir.len() == 1 => continue,
+            Err(e) => return Err(e),
+        };
+        for (name, _) in entries {
+            // composite objects get their own pass
+            if !composite && dir.is_empty() && name == COMPOSITE_DIR {
+                continue;
+            }
+            let mut path = dir.clone();
+            path.push(name);
+            let key = if composite {
+                path[1..].join("/")
+            } else {
+                path.join("/")
+            };
+            match root_dir.clone().get_node(&path, true, forest.clone(), store).await?.result {
+                Some(PrivateNode::File(_)) if key.starts_with(prefix) => {
+                    keys.insert(key);
+                }
+                Some(PrivateNode::Dir(_)) => {
+                    // only go where keys with the prefix could be
+                    let dir_key = format!("{}/", key);
+                    if dir_key.starts_with(prefix) || prefix.starts_with(&dir_key) {
+                        dirs.push((path, composite));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(keys)
+}
+
+/// works out the info of an object in a public bucket
+async fn public_object_info_in(
+    root_dir: &Rc<PublicDirectory>,
+    key: &str,
+    store: &impl BlockStore,
+) -> anyhow::Result<Option<ObjectInfo>> {
+    let node = match root_dir.clone().get_node(&key_to_path(key), store).await {
+        Ok(op) => op.result,
+        Err(e) => {
+            log::debug!("wnfs buckets: couldn't get {}: {}", key, e);
+            return Ok(None);
+        }
+    };
+    let Some(PublicNode::File(file)) = node else {
+        return Ok(None);
+    };
+    let cid = *file.get_content_cid();
+    Ok(Some(ObjectInfo {
+        key: key.to_string(),
+        size: unixfs::file_size(&cid, store).await?,
+        cid,
+        etag: public_etag(&cid),
+        last_modified: modified(file.get_metadata())?,
+    }))
+}
+
+/// every key in a public bucket starting with `prefix`, in order
+async fn public_keys_in(
+    root_dir: &Rc<PublicDirectory>,
+    prefix: &str,
+    store: &impl BlockStore,
+) -> anyhow::Result<BTreeSet<String>> {
+    let mut keys = BTreeSet::new();
+    let mut dirs: Vec<Vec<String>> = vec![vec![]];
+    while let Some(dir) = dirs.pop() {
+        for (name, _) in root_dir.clone().ls(&dir, store).await?.result {
+            let mut path = dir.clone();
+            path.push(name);
+            let key = path.join("/");
+            match root_dir.clone().get_node(&path, store).await?.result {
+                Some(PublicNode::File(_)) if key.starts_with(prefix) => {
+                    keys.insert(key);
+                }
+                Some(PublicNode::Dir(_)) => {
+                    // only go where keys with the prefix could be
+                    let dir_key = format!("{}/", key);
+                    if dir_key.starts_with(prefix) || prefix.starts_with(&dir_key) {
+                        dirs.push(path);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(keys)
+}
+
+/// reads up to a segment's worth of a stream, keeping whatever's left of the last chunk in `rest` for next time.
+/// None once the stream is done.
+async fn next_segment<S>(body: &mut Pin<Box<S>>, rest: &mut Bytes) -> anyhow::Result<Option<Vec<u8>>>
+where
+    S: Stream<Item = anyhow::Result<Bytes>>,
+{
+    let mut buffer = Vec::with_capacity(SEGMENT_SIZE);
+    while buffer.len() < SEGMENT_SIZE {
+        if rest.is_empty() {
+            match body.next().await {
+                Some(chunk) => *rest = chunk?,
+                None => break,
+            }
+        }
+        let take = rest.len().min(SEGMENT_SIZE - buffer.len());
+        buffer.extend_from_slice(&rest.split_to(take));
+    }
+    Ok((!buffer.is_empty()).then_some(buffer))
+}
+
+/// checks the inclusive byte range `first..=last` against an object `size` bytes long.
+/// `last` past the end of the object is clamped, like S3 does.
+pub fn satisfiable_range(size: u64, first: u64, last: u64) -> S3Result<(u64, u64)> {
+    if first >= size || first > last {
+        return Err(s3_error!(
+            InvalidRange,
+            "The requested range is not satisfiable"
+        ));
+    }
+    Ok((first, last.min(size - 1)))
+}
+
+/// an object's bytes, as they're read
+pub type ObjectBody = mpsc::Receiver<std::io::Result<Bytes>>;
+
+/// hands an object's bytes to whoever's reading it, cut down to the range they asked for
+struct BodySender {
+    tx: mpsc::Sender<std::io::Result<Bytes>>,
+    /// how many bytes to throw away before the range starts. readers that can skip ahead without reading take it themselves.
+    skip: u64,
+    /// how many bytes of the range are still to be sent
+    left: u64,
+}
+
+impl BodySender {
+    /// sends whatever of `chunk` is in the range. false once the range is done or nobody's listening, so reading can stop.
+    async fn send(&mut self, mut chunk: Vec<u8>) -> bool {
+        let skipped = self.skip.min(chunk.len() as u64);
+        chunk.drain(..skipped as usize);
+        self.skip -= skipped;
+        chunk.truncate(self.left.min(chunk.len() as u64) as usize);
+        self.left -= chunk.len() as u64;
+        if !chunk.is_empty() && self.tx.send(Ok(Bytes::from(chunk))).await.is_err() {
+            return false;
+        }
+        self.left > 0
+    }
+}
+
+/// reads an object out of a bucket into `body` a chunk at a time, putting it back together if it's a composite
+async fn send_object(
+    root: &BucketRoot,
+    key: &str,
+    store: &impl BlockStore,
+    body: &mut BodySender,
+) -> anyhow::Result<()> {
+    if let BucketRoot::Public { public_root_cid } = root {
+        let root_dir = open_public(public_root_cid, store).await?;
+        let content_cid = root_dir.read(&key_to_path(key), store).await?.result;
+        let mut reader = unixfs::FileReader::new(content_cid, std::mem::take(&mut body.skip));
+        while let Some(chunk) = reader.next_chunk(store).await? {
+            if !body.send(chunk).await {
+                break;
+            }
+        }
+        return Ok(());
+    }
+    let (forest, root_dir) = open_bucket(root, store).await?;
+    if let Some(file) = get_file(&root_dir, &key_to_path(key), &forest, store).await {
+        let mut chunks = Box::pin(file.stream_content(0, &forest, store));
+        while let Some(chunk) = chunks.next().await {
+            if !body.send(chunk?).await {
+                break;
+            }
+        }
+        return Ok(());
+    }
+    let file = get_file(&root_dir, &composite_path(key), &forest, store)
+        .await
+        .ok_or_else(|| anyhow!("{} isn't in the bucket", key))?;
+    let composite: CompositeObject = serde_json::from_slice(&file_content(&file, &forest, store).await?)?;
+    for segment in &composite.segments {
+        // segments wholly before the range don't need reading at all
+        if segment.size <= body.skip {
+            body.skip -= segment.size;
+            continue;
+        }
+        if !body.send(read_segment(segment, store).await?).await {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// the WNFS trees behind our S3 buckets
+pub struct WnfsBuckets {
+    registry: Arc<BucketRegistry>,
+    blockstore: AnyBlockStore,
+}
+
+impl WnfsBuckets {
+    pub fn new(registry: Arc<BucketRegistry>, blockstore: AnyBlockStore) -> Self {
+        Self {
+            registry,
+            blockstore,
+        }
+    }
+
+    /// the store all the buckets' blocks are in
+    pub fn blockstore(&self) -> AnyBlockStore {
+        self.blockstore.clone()
+    }
+
+    /// the root to read a bucket from. `bucket--snap--snapshot` reads a snapshot of the bucket instead.
+    async fn get_root(&self, bucket_name: &str) -> S3Result<BucketRoot> {
+        let root = match bucket_name.split_once(SNAPSHOT_SEPARATOR) {
+            Some((bucket_name, snapshot_name)) => self
+                .registry
+                .get_snapshot(bucket_name, snapshot_name)
+                .await?
+                .map(|snapshot| snapshot.root),
+            None => self.registry.get_root(bucket_name).await?,
+        };
+        root.ok_or_else(|| s3_error!(NoSuchBucket, "The specified bucket does not exist"))
+    }
+
+    /// the root of a bucket that's about to be changed, which snapshots can't be
+    async fn get_writable_root(&self, bucket_name: &str) -> S3Result<BucketRoot> {
+        if is_snapshot(bucket_name) {
+            return Err(s3_error!(AccessDenied, "Snapshots are read-only"));
+        }
+        self.get_root(bucket_name).await
+    }
+
+    /// makes sure a root from the past can still be opened, before anything is pointed at it.
+    /// gc only keeps roots within its retention alive, so older ones can have lost their blocks.
+    /// the objects under the root aren't checked.
+    async fn check_root(&self, root: &BucketRoot) -> S3Result<()> {
+        let root = root.clone();
+        let store = self.blockstore.clone();
+        run_wnfs(move || async move {
+            transmute_result_for_s3error(match &root {
+                BucketRoot::Private { .. } => open_bucket(&root, &store).await.map(|_| ()),
+                BucketRoot::Public { public_root_cid } => {
+                    open_public(public_root_cid, &store).await.map(|_| ())
+                }
+            })
+        })
+        .await
+    }
+
+    /// streams an object out of a bucket as it's read, a block or a segment at a time.
+    /// with a range, only the inclusive bytes `first..=last` are sent, and as little as the object's layout allows is read
+    /// before them. check it with `satisfiable_range` first. an error partway through ends the stream with it.
+    pub async fn read_object(
+        &self,
+        bucket_name: &str,
+        key: &str,
+        range: Option<(u64, u64)>,
+    ) -> S3Result<ObjectBody> {
+        let root = self.get_root(bucket_name).await?;
+        let store = self.blockstore.clone();
+        let key = key.to_string();
+        let (tx, rx) = mpsc::channel(READ_AHEAD);
+        let (skip, left) = match range {
+            Some((first, last)) => (first, last - first + 1),
+            None => (0, u64::MAX),
+        };
+        let mut body = BodySender { tx, skip, left };
+        tokio::spawn(run_wnfs(move || async move {
+            if let Err(e) = send_object(&root, &key, &store, &mut body).await {
+                log::error!("wnfs buckets: couldn't read {}: {:?}", key, e);
+                let error = std::io::Error::other(e.to_string());
+                let _ = body.tx.send(Err(error)).await;
+            }
+            Ok(())
+        }));
+        Ok(rx)
+    }
+
+    /// looks up an object's size and cid
+    pub async fn object_info(&self, bucket_name: &str, key: &str) -> S3Result<ObjectInfo> {
+        let root = self.get_root(bucket_name).await?;
+        let store = self.blockstore.clone();
+        let key = key.to_string();
+        run_wnfs(move || async move {
+            transmute_result_for_s3error(
+                async {
+                    if let BucketRoot::Public { public_root_cid } = &root {
+                        let root_dir = open_public(public_root_cid, &store).await?;
+                        return public_object_info_in(&root_dir, &key, &store).await;
+                    }
+                    let (forest, root_dir) = open_bucket(&root, &store).await?;
+                    object_info_in(&root_dir, &forest, &key, &store).await
+                }
+                .await,
+            )?
+            .ok_or_else(|| s3_error!(NoSuchKey, "The specified key does not exist."))
+        })
+        .await
+    }
+
+    /// every key in a bucket starting with `prefix`, in key order, without looking into the objects
+    pub async fn list_keys(&self, bucket_name: &str, prefix: &str) -> S3Result<BTreeSet<String>> {
+        let root = self.get_root(bucket_name).await?;
+        let store = self.blockstore.clone();
+        let prefix = prefix.to_string();
+        run_wnfs(move || async move {
+            transmute_result_for_s3error(
+                async {
+                    if let BucketRoot::Public { public_root_cid } = &root {
+                        let root_dir = open_public(public_root_cid, &store).await?;
+                        return public_keys_in(&root_dir, &prefix, &store).await;
+                    }
+                    let (forest, root_dir) = open_bucket(&root, &store).await?;
+                    keys_in(&root_dir, &forest, &prefix, &store).await
+                }
+                .await,
+            )
+        })
+        .await
+    }
+
+    /// looks up the info of each of `keys`, in order. keys that have gone since they were listed are left out.
+    pub async fn objects_info(&self, bucket_name: &str, keys: Vec<String>) -> S3Result<Vec<ObjectInfo>> {
+        let root = self.get_root(bucket_name).await?;
+        let store = self.blockstore.clone();
+        run_wnfs(move || async move {
+            transmute_result_for_s3error(
+                async {
+                    let mut objects = Vec::with_capacity(keys.len());
+                    if let BucketRoot::Public { public_root_cid } = &root {
+                        let root_dir = open_public(public_root_cid, &store).await?;
+                        for key in &keys {
+                            objects.extend(public_object_info_in(&root_dir, key, &store).await?);
+                        }
+                        return Ok(objects);
+                    }
+                    let (forest, root_dir) = open_bucket(&root, &store).await?;
+                    for key in &keys {
+                        objects.extend(object_info_in(&root_dir, &forest, key, &store).await?);
+                    }
+                    Ok(objects)
+                }
+                .await,
+            )
+        })
+        .await
+    }
+
+    /// encrypts some bytes into a new segment
+    pub async fn write_segment(&self, content: Vec<u8>) -> S3Result<Segment> {
+        let mut store = self.blockstore.clone();
+        run_wnfs(move || async move {
+            let rng = &mut rand::thread_rng();
+            let size = content.len() as u64;
+            transmute_result_for_s3error(
+                async {
+                    let (file, forest) = PrivateFile::with_content(
+                        Namefilter::default(),
+                        Utc::now(),
+                        content,
+                        Rc::new(PrivateForest::new()),
+                        &mut store,
+                        rng,
+                    )
+                    .await?;
+                    let file_ref = StoredPrivateRef::of(&file.header);
+                    let forest = forest
+                        .put(
+                            file.header.get_saturated_name(),
+                            &file.header.derive_private_ref(),
+                            &PrivateNode::File(Rc::new(file)),
+                            &mut store,
+                            rng,
+                        )
+                        .await?;
+                    let forest_cid = store.put_async_serializable(&forest).await?;
+                    Ok(Segment {
+                        forest_cid: forest_cid.to_string(),
+                        file_ref,
+                        size,
+                    })
+                }
+                .await,
+            )
+        })
+        .await
+    }
+
+    /// cuts a stream of bytes into segments as it comes in, so at most one segment's worth is ever in memory
+    pub async fn write_segments<S>(&self, body: S) -> S3Result<Vec<Segment>>
+    where
+        S: Stream<Item = anyhow::Result<Bytes>> + Send,
+    {
+        let mut body = Box::pin(body);
+        let mut rest = Bytes::new();
+        let mut segments = Vec::new();
+        while let Some(content) = transmute_result_for_s3error(next_segment(&mut body, &mut rest).await)? {
+            segments.push(self.write_segment(content).await?);
+        }
+        Ok(segments)
+    }
+
+    /// stores some of a public object's bytes as UnixFS leaves
+    async fn write_leaves(&self, content: Vec<u8>) -> S3Result<Vec<UnixfsLink>> {
+        let mut store = self.blockstore.clone();
+        run_wnfs(move || async move {
+            transmute_result_for_s3error(unixfs::write_leaves(&content, &mut store).await)
+        })
+        .await
+    }
+
+    /// makes a UnixFS file out of a public object's leaves, and writes it into its bucket at `key`, replacing whatever was there.
+    /// `root` is the bucket's root as the caller last saw it; if someone else has moved it since, the write is redone on top.
+    async fn link_public(
+        &self,
+        bucket_name: &str,
+        mut root: BucketRoot,
+        key: &str,
+        leaves: Vec<UnixfsLink>,
+        actor: &str,
+    ) -> S3Result<()> {
+        for _ in 0..ROOT_UPDATE_ATTEMPTS {
+            let mut store = self.blockstore.clone();
+            let path = key_to_path(key);
+            let leaves = leaves.clone();
+            let previous = root.clone();
+            let new_root = run_wnfs(move || async move {
+                transmute_result_for_s3error(
+                    async {
+                        let BucketRoot::Public { public_root_cid } = &previous else {
+                            return Err(anyhow!("bucket isn't public"));
+                        };
+                        let root_dir = open_public(public_root_cid, &store).await?;
+                        let file = unixfs::write_tree(leaves, &mut store).await?;
+                        let op = root_dir.write(&path, file.cid, Utc::now(), &store).await?;
+                        store_public_root(&op.root_dir, &mut store).await
+                    }
+                    .await,
+                )
+            })
+            .await?;
+            if self.registry.set_root(bucket_name, Some(&root), &new_root, actor).await? {
+                return Ok(());
+            }
+            root = self.get_writable_root(bucket_name).await?;
+        }
+        Err(root_update_conflict(bucket_name))
+    }
+
+    /// writes a plain file into a private bucket at `key`, replacing whatever was there.
+    /// `root` is the bucket's root as the caller last saw it; if someone else has moved it since, the write is redone on top.
+    async fn link_plain(
+        &self,
+        bucket_name: &str,
+        mut root: BucketRoot,
+        key: &str,
+        content: Vec<u8>,
+        etag: &str,
+        actor: &str,
+    ) -> S3Result<()> {
+        for _ in 0..ROOT_UPDATE_ATTEMPTS {
+            let mut store = self.blockstore.clone();
+            let path = key_to_path(key);
+            let composite_path = composite_path(key);
+            let content = content.clone();
+            let etag = etag.to_string();
+            let previous = root.clone();
+            let new_root = run_wnfs(move || async move {
+                let rng = &mut rand::thread_rng();
+                transmute_result_for_s3error(
+                    async {
+                        let (forest, root_dir) = open_bucket(&previous, &store).await?;
+                        // a composite left at the key would keep its segments from gc, and come back if the file went
+                        let (forest, root_dir) = match root_dir
+                            .clone()
+                            .rm(&composite_path, true, forest.clone(), &mut store, rng)
+                            .await
+                        {
+                            Ok(op) => (op.forest, op.root_dir),
+                            Err(_) => (forest, root_dir),
+                        };
+                        let (forest, root_dir) =
+                            write_plain_file(root_dir, &path, content, &etag, forest, &mut store, rng)
+                                .await?;
+                        store_root(&forest, &root_dir, &mut store).await
+                    }
+                    .await,
+                )
+            })
+            .await?;
+            if self.registry.set_root(bucket_name, Some(&root), &new_root, actor).await? {
+                return Ok(());
+            }
+            root = self.get_writable_root(bucket_name).await?;
+        }
+        Err(root_update_conflict(bucket_name))
+    }
+
+    /// writes a whole object into a bucket at `key`, replacing whatever was there, under `etag`.
+    /// private buckets get it as a plain file, which wnfs can only write out of memory, so the whole object is read in first.
+    /// public ones get a UnixFS file, written as it streams in.
+    pub async fn write_object<S>(
+        &self,
+        bucket_name: &str,
+        key: &str,
+        body: S,
+        etag: &str,
+        actor: &str,
+    ) -> S3Result<()>
+    where
+        S: Stream<Item = anyhow::Result<Bytes>> + Send,
+    {
+        let root = self.get_writable_root(bucket_name).await?;
+        if !root.is_public() {
+            let content = transmute_result_for_s3error(
+                body.try_fold(Vec::new(), |mut content, chunk| async move {
+                    content.extend_from_slice(&chunk);
+                    Ok(content)
+                })
+                .await,
+            )?;
+            return self.link_plain(bucket_name, root, key, content, etag, actor).await;
+        }
+        let mut body = Box::pin(body);
+        let mut rest = Bytes::new();
+        let mut leaves = Vec::new();
+        while let Some(content) = transmute_result_for_s3error(next_segment(&mut body, &mut rest).await)? {
+            leaves.extend(self.write_leaves(content).await?);
+        }
+        self.link_public(bucket_name, root, key, leaves, actor).await
+    }
+
+    /// makes a new, empty bucket. anything in a public bucket can be read by anyone who has its cid.
+    /// of two racing creates, only one succeeds.
+    pub async fn create_bucket(&self, bucket_name: &str, public: bool, actor: &str) -> S3Result<()> {
+        if is_snapshot(bucket_name) {
+            return Err(s3_error!(
+                InvalidBucketName,
+                "Bucket names can't contain {}",
+                SNAPSHOT_SEPARATOR
+            ));
+        }
+        if self.registry.get_root(bucket_name).await?.is_some() {
+            return Err(s3_error!(
+                BucketAlreadyExists,
+                "The requested bucket name is not available."
+            ));
+        }
+        let mut store = self.blockstore.clone();
+        let root = run_wnfs(move || async move {
+            transmute_result_for_s3error(
+                async {
+                    if public {
+                        return store_public_root(&Rc::new(PublicDirectory::new(Utc::now())), &mut store)
+                            .await;
+                    }
+                    let rng = &mut rand::thread_rng();
+                    let root_dir = Rc::new(PrivateDirectory::new(Namefilter::default(), Utc::now(), rng));
+                    // the root directory has to be in the forest for the bucket to be opened
+                    let forest = Rc::new(PrivateForest::new())
+                        .put(
+                            root_dir.header.get_saturated_name(),
+                            &root_dir.header.derive_private_ref(),
+                            &PrivateNode::Dir(root_dir.clone()),
+                            &mut store,
+                            rng,
+                        )
+                        .await?;
+                    store_root(&forest, &root_dir, &mut store).await
+                }
+                .await,
+            )
+        })
+        .await?;
+        if !self.registry.set_root(bucket_name, None, &root, actor).await? {
+            return Err(s3_error!(
+                BucketAlreadyExists,
+                "The requested bucket name is not available."
+            ));
+        }
+        Ok(())
+    }
+
+    /// points a bucket back at the root it had at `at`, or with `fork_into`, makes a new bucket out of that root
+    /// and leaves the original alone. either way it's a new commit, so a restore can be undone like any other change.
+    /// gc only keeps the roots of commits within its retention alive, so going back further can find blocks missing.
+    /// the old root is opened before anything is pointed at it, but the objects under it aren't checked.
+    /// a fork of a private bucket shares its keys with the original. snapshots can't be restored or forked into.
+    pub async fn restore_bucket(
+        &self,
+        bucket_name: &str,
+        at: DateTime<Utc>,
+        fork_into: Option<&str>,
+        actor: &str,
+    ) -> S3Result<RootCommit> {
+        let commit = self
+            .registry
+            .commit_at(bucket_name, at)
+            .await?
+            .ok_or_else(|| s3_error!(NoSuchBucket, "Bucket {} didn't exist at {}", bucket_name, at))?;
+        let target = fork_into.unwrap_or(bucket_name);
+        if is_snapshot(target) {
+            return Err(s3_error!(AccessDenied, "Snapshots are read-only"));
+        }
+        if fork_into.is_some() && self.registry.get_root(target).await?.is_some() {
+            return Err(s3_error!(
+                BucketAlreadyExists,
+                "The requested bucket name is not available."
+            ));
+        }
+        self.check_root(&commit.root).await?;
+        log::info!(
+            "wnfs buckets: pointing {} at the root {} had at {} ({})",
+            target,
+            bucket_name,
+            commit.committed_at,
+            commit.root.root_cid()
+        );
+        for _ in 0..ROOT_UPDATE_ATTEMPTS {
+            // a fork makes a new bucket, which mustn't exist; a restore goes on top of whatever the bucket has now
+            let previous = match fork_into {
+                Some(_) => None,
+                None => Some(self.get_root(target).await?),
+            };
+            if self.registry.set_root(target, previous.as_ref(), &commit.root, actor).await? {
+                return Ok(commit);
+            }
+            if fork_into.is_some() {
+                return Err(s3_error!(
+                    BucketAlreadyExists,
+                    "The requested bucket name is not available."
+                ));
+            }
+        }
+        Err(root_update_conflict(target))
+    }
+
+    /// pins a bucket's current root, or the one it had at `at`, as a snapshot called `snapshot_name`,
+    /// which can then be read as the bucket `bucket--snap--snapshot_name`.
+    /// that has to be a valid bucket name, so snapshot names are held to the same rules, and the two together to its length.
+    pub async fn create_snapshot(
+        &self,
+        bucket_name: &str,
+        snapshot_name: &str,
+        at: Option<DateTime<Utc>>,
+        actor: &str,
+    ) -> S3Result<Snapshot> {
+        if is_snapshot(bucket_name) {
+            return Err(s3_error!(InvalidArgument, "Snapshots can't be snapshotted"));
+        }
+        if snapshot_name.contains(SNAPSHOT_SEPARATOR)
+            || !s3s::path::check_bucket_name(&snapshot_id(bucket_name, snapshot_name))
+        {
+            return Err(s3_error!(
+                InvalidArgument,
+                "Snapshot names can't contain {}, and {} has to be a valid bucket name",
+                SNAPSHOT_SEPARATOR,
+                snapshot_id(bucket_name, snapshot_name)
+            ));
+        }
+        let root = match at {
+            Some(at) => {
+                let commit = self.registry.commit_at(bucket_name, at).await?.ok_or_else(|| {
+                    s3_error!(NoSuchBucket, "Bucket {} didn't exist at {}", bucket_name, at)
+                })?;
+                self.check_root(&commit.root).await?;
+                commit.root
+            }
+            None => self.get_root(bucket_name).await?,
+        };
+        let snapshot = Snapshot {
+            bucket: bucket_name.to_string(),
+            name: snapshot_name.to_string(),
+            root,
+            created_at: Utc::now(),
+            actor: actor.to_string(),
+        };
+        self.registry.create_snapshot(&snapshot).await?;
+        Ok(snapshot)
+    }
+
+    /// links a composite object into a bucket at `key`, replacing whatever was there.
+    /// public buckets can't have encrypted segments in them, so there the segments are copied out into a UnixFS file,
+    /// a segment at a time, and left for gc.
+    pub async fn link_composite(
+        &self,
+        bucket_name: &str,
+        key: &str,
+        composite: CompositeObject,
+        actor: &str,
+    ) -> S3Result<()> {
+        let mut root = self.get_writable_root(bucket_name).await?;
+        if root.is_public() {
+            let mut leaves = Vec::new();
+            for segment in composite.segments {
+                let store = self.blockstore.clone();
+                let content = run_wnfs(move || async move {
+                    transmute_result_for_s3error(read_segment(&segment, &store).await)
+                })
+                .await?;
+                leaves.extend(self.write_leaves(content).await?);
+            }
+            return self.link_public(bucket_name, root, key, leaves, actor).await;
+        }
+        let composite = transmute_result_for_s3error(
+            serde_json::to_vec(&composite).map_err(anyhow::Error::from),
+        )?;
+        // someone else can move the root while we write, in which case the link is redone on top of what they did
+        for _ in 0..ROOT_UPDATE_ATTEMPTS {
+            let mut store = self.blockstore.clone();
+            let path = key_to_path(key);
+            let composite_path = composite_path(key);
+            let composite = composite.clone();
+            let previous = root.clone();
+            let new_root = run_wnfs(move || async move {
+                let rng = &mut rand::thread_rng();
+                transmute_result_for_s3error(
+                    async {
+                        let (forest, root_dir) = open_bucket(&previous, &store).await?;
+                        // a plain file at the key would shadow the composite
+                        let (forest, root_dir) = match root_dir
+                            .clone()
+                            .rm(&path, true, forest.clone(), &mut store, rng)
+                            .await
+                        {
+                            Ok(op) => (op.forest, op.root_dir),
+                            Err(_) => (forest, root_dir),
+                        };
+                        let op = root_dir
+                            .write(
+                                &composite_path,
+                                true,
+                                Utc::now(),
+                                composite,
+                                forest,
+                                &mut store,
+                                rng,
+                            )
+                            .await?;
+                        store_root(&op.forest, &op.root_dir, &mut store).await
+                    }
+                    .await,
+                )
+            })
+            .await?;
+            if self.registry.set_root(bucket_name, Some(&root), &new_root, actor).await? {
+                return Ok(());
+            }
+            root = self.get_writable_root(bucket_name).await?;
+        }
+        Err(root_update_conflict(bucket_name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mutex_memory_blockstore::MutexMemoryBlockStore;
+
+    use super::*;
+
+    fn memory_buckets() -> WnfsBuckets {
+        WnfsBuckets::new(
+            Arc::new(BucketRegistry::in_memory()),
+            AnyBlockStore::Memory(MutexMemoryBlockStore::new()),
+        )
+    }
+
+    async fn put(buckets: &WnfsBuckets, bucket_name: &str, key: &str, content: &'static str) {
+        let body = futures::stream::iter([Ok(Bytes::from(content))]);
+        buckets.write_object(bucket_name, key, body, "\"test\"", "test").await.unwrap();
+    }
+
+    async fn get(buckets: &WnfsBuckets, bucket_name: &str, key: &str) -> String {
+        let body: Vec<Bytes> = buckets
+            .read_object(bucket_name, key, None)
+            .await
+            .unwrap()
+            .map(|chunk| chunk.unwrap())
+            .collect()
+            .await;
+        String::from_utf8(body.concat()).unwrap()
+    }
+
+    /// a bucket with "one" and then "two" at `key`, and a time in between
+    async fn bucket_with_history(buckets: &WnfsBuckets, public: bool) -> DateTime<Utc> {
+        buckets.create_bucket("photos", public, "test").await.unwrap();
+        put(buckets, "photos", "cat.txt", "one").await;
+        let between = Utc::now();
+        tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+        put(buckets, "photos", "cat.txt", "two").await;
+        between
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn restore_goes_back_with_a_new_commit() {
+        for public in [false, true] {
+            let buckets = memory_buckets();
+            let between = bucket_with_history(&buckets, public).await;
+
+            let commit = buckets.restore_bucket("photos", between, None, "admin").await.unwrap();
+            assert_eq!(get(&buckets, "photos", "cat.txt").await, "one");
+            let history = buckets.registry.list_bucket_commits("photos").await.unwrap();
+            assert_eq!(history.len(), 4);
+            let last = history.last().unwrap();
+            assert_eq!((&last.root, last.actor.as_str()), (&commit.root, "admin"));
+            // which can itself be undone
+            buckets.restore_bucket("photos", Utc::now(), None, "admin").await.unwrap();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn fork_leaves_the_original_alone() {
+        let buckets = memory_buckets();
+        let between = bucket_with_history(&buckets, false).await;
+
+        buckets.restore_bucket("photos", between, Some("photos-then"), "admin").await.unwrap();
+        assert_eq!(get(&buckets, "photos-then", "cat.txt").await, "one");
+        assert_eq!(get(&buckets, "photos", "cat.txt").await, "two");
+        // forks only make new buckets
+        let err = buckets
+            .restore_bucket("photos", between, Some("photos-then"), "admin")
+            .await
+            .unwrap_err();
+        assert_eq!(*err.code(), s3s::S3ErrorCode::BucketAlreadyExists);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn restore_before_the_bucket_existed_fails() {
+        let buckets = memory_buckets();
+        let before = Utc::now();
+        tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+        bucket_with_history(&buckets, false).await;
+        let err = buckets.restore_bucket("photos", before, None, "admin").await.unwrap_err();
+        assert_eq!(*err.code(), s3s::S3ErrorCode::NoSuchBucket);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn plain_objects_replace_composites() {
+        let buckets = memory_buckets();
+        buckets.create_bucket("photos", false, "test").await.unwrap();
+        let segments = buckets
+            .write_segments(futures::stream::iter([Ok(Bytes::from("composite"))]))
+            .await
+            .unwrap();
+        buckets
+            .link_composite(
+                "photos",
+                "cat.txt",
+                CompositeObject {
+                    segments,
+                    etag: "\"test\"".to_string(),
+                },
+                "test",
+            )
+            .await
+            .unwrap();
+        assert_eq!(get(&buckets, "photos", "cat.txt").await, "composite");
+
+        put(&buckets, "photos", "cat.txt", "plain").await;
+        assert_eq!(get(&buckets, "photos", "cat.txt").await, "plain");
+        assert_eq!(buckets.object_info("photos", "cat.txt").await.unwrap().size, 5);
+        // the composite is gone, not just hidden behind the file
+        let root = buckets.get_root("photos").await.unwrap();
+        let store = buckets.blockstore();
+        let (forest, root_dir) = open_bucket(&root, &store).await.unwrap();
+        assert!(get_file(&root_dir, &composite_path("cat.txt"), &forest, &store).await.is_none());
+    }
+}
@@ -1,52 +1,290 @@
-use std::{sync::Arc};
+use std::{collections::BTreeSet, sync::Arc};
+
+use chrono::{DateTime, Utc};
+use hyper::HeaderMap;
 
 use s3s::{
     dto::{
-        AbortMultipartUploadInput, AbortMultipartUploadOutput, CompleteMultipartUploadInput,
-        CompleteMultipartUploadOutput, CopyObjectInput, CopyObjectOutput, CreateBucketInput,
-        CreateBucketOutput, CreateMultipartUploadInput, CreateMultipartUploadOutput,
-        DeleteBucketCorsInput, DeleteBucketCorsOutput, DeleteBucketInput, DeleteBucketOutput,
-        DeleteObjectInput, DeleteObjectOutput, GetBucketAclInput, GetBucketAclOutput,
-        GetBucketCorsInput, GetBucketCorsOutput, GetBucketLifecycleConfigurationInput,
+        AbortIncompleteMultipartUpload, AbortMultipartUploadInput, AbortMultipartUploadOutput,
+        BucketCannedACL,
+        CompleteMultipartUploadInput, CompleteMultipartUploadOutput, CopyObjectInput,
+        CopyObjectOutput, CopyPartResult, CopySource, CreateBucketInput, CreateBucketOutput,
+        CreateMultipartUploadInput, CreateMultipartUploadOutput, DeleteBucketCorsInput,
+        DeleteBucketCorsOutput, DeleteBucketInput, DeleteBucketLifecycleInput,
+        DeleteBucketLifecycleOutput, DeleteBucketOutput, DeleteObjectInput, DeleteObjectOutput,
+        ExpirationStatus, GetBucketAclInput, GetBucketAclOutput, GetBucketCorsInput,
+        GetBucketCorsOutput, GetBucketLifecycleConfigurationInput,
         GetBucketLifecycleConfigurationOutput, GetBucketLocationInput, GetBucketLocationOutput,
         GetBucketLoggingInput, GetBucketLoggingOutput, GetBucketVersioningInput,
         GetBucketVersioningOutput, GetObjectAclInput, GetObjectAclOutput, GetObjectInput,
         GetObjectOutput, HeadBucketInput, HeadBucketOutput, HeadObjectInput, HeadObjectOutput,
-        ListBucketsInput, ListBucketsOutput, ListObjectsInput, ListObjectsOutput,
-        ListObjectsV2Input, ListObjectsV2Output, PutBucketAclInput, PutBucketAclOutput,
-        PutBucketCorsInput, PutBucketCorsOutput, PutObjectAclInput, PutObjectAclOutput,
-        PutObjectInput, PutObjectOutput, UploadPartInput, UploadPartOutput,
+        LifecycleRule, LifecycleRuleFilter, ListBucketsInput, ListBucketsOutput,
+        ListMultipartUploadsInput, ListMultipartUploadsOutput, ListObjectsInput, ListObjectsOutput,
+        CommonPrefix, ListObjectsV2Input, ListObjectsV2Output, Metadata, MultipartUpload, Object, Owner,
+        Range, StreamingBlob, PutBucketAclInput,
+        PutBucketAclOutput, PutBucketCorsInput, PutBucketCorsOutput,
+        PutBucketLifecycleConfigurationInput, PutBucketLifecycleConfigurationOutput,
+        PutObjectAclInput, PutObjectAclOutput, PutObjectInput, PutObjectOutput, Timestamp,
+        UploadPartCopyInput, UploadPartCopyOutput, UploadPartInput, UploadPartOutput,
     },
+    auth::Credentials,
     s3_error, S3Request, S3Result, S3,
 };
 
-use crate::{multipart_uploads::CloudStorageForMultipartConstruction, banyan_s3_auth::BanyanS3Auth};
+use crate::{
+    banyan_s3_auth::BanyanS3Auth,
+    bucket_registry::{is_snapshot, snapshotted_bucket, AbortIncompleteUploadRule, BucketLifecycle, BucketRegistry},
+    multipart_uploads::CloudStorageForMultipartConstruction,
+    wnfs_buckets::{satisfiable_range, WnfsBuckets},
+};
 
 pub struct WnfsS3Service {
-    //blockstore: MutexMemoryBlockStore,
-    multipart_cloud_storage: CloudStorageForMultipartConstruction,
+    buckets: Arc<WnfsBuckets>,
+    registry: Arc<BucketRegistry>,
+    multipart_cloud_storage: Arc<CloudStorageForMultipartConstruction>,
     auth: Arc<BanyanS3Auth>,
 }
 
 impl WnfsS3Service {
-    pub async fn new(auth: Arc<BanyanS3Auth>) -> Self {
+    pub fn new(
+        auth: Arc<BanyanS3Auth>,
+        registry: Arc<BucketRegistry>,
+        buckets: Arc<WnfsBuckets>,
+        multipart_cloud_storage: Arc<CloudStorageForMultipartConstruction>,
+    ) -> Self {
         Self {
-            //blockstore: MutexMemoryBlockStore::new(),
-            multipart_cloud_storage: CloudStorageForMultipartConstruction::new().await,
+            buckets,
+            registry,
+            multipart_cloud_storage,
             auth: auth.clone(),
         }
     }
 }
 
+/// a request header asking for objects' cids, with `true`. GetObject and HeadObject answer with the cid in `x-amz-meta-cid`,
+/// and ListObjectsV2 in each object's `Owner/ID`, which is the only field of a listed object that isn't spoken for.
+/// without it, responses are what any S3 client expects.
+const INCLUDE_CID_HEADER: &str = "x-banyan-include-cid";
+/// the most keys ListObjectsV2 returns at once, and how many it returns when it isn't told
+const MAX_KEYS: usize = 1000;
+
+fn wants_cid(headers: &HeaderMap) -> bool {
+    headers
+        .get(INCLUDE_CID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.eq_ignore_ascii_case("true"))
+}
+
+/// who's making a request, as the bucket's commit log records it
+fn actor(credentials: &Option<Credentials>) -> String {
+    credentials
+        .as_ref()
+        .map_or_else(|| "anonymous".to_string(), |credentials| credentials.access_key.clone())
+}
+
+/// the metadata to answer with, which only has anything in it when the cid was asked for
+fn cid_metadata(wants_cid: bool, cid: &wnfs::ipld::Cid) -> Option<Metadata> {
+    wants_cid.then(|| {
+        let mut metadata = Metadata::new();
+        metadata.insert("cid".to_string(), cid.to_string());
+        metadata
+    })
+}
+
+/// parses an x-amz-copy-source-range header, `bytes=first-last`, into an inclusive (first, last)
+fn parse_copy_source_range(range: &str) -> S3Result<(u64, u64)> {
+    range
+        .strip_prefix("bytes=")
+        .and_then(|range| range.split_once('-'))
+        .and_then(|(first, last)| Some((first.parse().ok()?, last.parse().ok()?)))
+        .ok_or_else(|| {
+            s3_error!(
+                InvalidArgument,
+                "The x-amz-copy-source-range value must be of the form bytes=first-last"
+            )
+        })
+}
+
+/// s3s answers every GetObject with a 200, even with only part of the object in it. this makes those a 206,
+/// and has to be put around the whole service.
+pub fn partial_content(mut response: hyper::Response<s3s::Body>) -> hyper::Response<s3s::Body> {
+    if response.status() == hyper::StatusCode::OK
+        && response.headers().contains_key(hyper::header::CONTENT_RANGE)
+    {
+        *response.status_mut() = hyper::StatusCode::PARTIAL_CONTENT;
+    }
+    response
+}
+
+/// an S3 timestamp
+fn timestamp(time: DateTime<Utc>) -> Timestamp {
+    Timestamp::from(std::time::SystemTime::from(time))
+}
+
+/// answers a GetObject, with the body streamed as it's read
+async fn get_object_output(
+    buckets: &WnfsBuckets,
+    input: GetObjectInput,
+    wants_cid: bool,
+) -> S3Result<GetObjectOutput> {
+    let bucket = input.bucket;
+    let key = input.key;
+    let info = buckets.object_info(&bucket, &key).await?;
+    let range = match input.range {
+        None => None,
+        Some(Range::Int { first, last }) => Some(satisfiable_range(
+            info.size,
+            first,
+            last.unwrap_or(u64::MAX),
+        )?),
+        Some(Range::Suffix { length }) => Some(satisfiable_range(
+            info.size,
+            info.size.saturating_sub(length),
+            u64::MAX,
+        )?),
+    };
+    let body = buckets.read_object(&bucket, &key, range).await?;
+    let (content_length, content_range) = match range {
+        None => (info.size, None),
+        Some((first, last)) => (
+            last - first + 1,
+            Some(format!("bytes {}-{}/{}", first, last, info.size)),
+        ),
+    };
+    Ok(GetObjectOutput {
+        body: Some(StreamingBlob::wrap(body)),
+        content_length: content_length as i64,
+        content_range,
+        metadata: cid_metadata(wants_cid, &info.cid),
+        e_tag: Some(info.etag),
+        last_modified: Some(timestamp(info.last_modified)),
+        ..Default::default()
+    })
+}
+
+/// answers a HeadObject
+async fn head_object_output(
+    buckets: &WnfsBuckets,
+    input: HeadObjectInput,
+    wants_cid: bool,
+) -> S3Result<HeadObjectOutput> {
+    let info = buckets.object_info(&input.bucket, &input.key).await?;
+    Ok(HeadObjectOutput {
+        content_length: info.size as i64,
+        metadata: cid_metadata(wants_cid, &info.cid),
+        e_tag: Some(info.etag),
+        last_modified: Some(timestamp(info.last_modified)),
+        ..Default::default()
+    })
+}
+
+/// answers a ListObjectsV2, looking into only the objects on the page
+async fn list_objects_v2_output(
+    buckets: &WnfsBuckets,
+    input: ListObjectsV2Input,
+    wants_cid: bool,
+) -> S3Result<ListObjectsV2Output> {
+    let prefix = input.prefix.clone().unwrap_or_default();
+    let max_keys = match input.max_keys {
+        Some(max_keys) if max_keys > 0 => (max_keys as usize).min(MAX_KEYS),
+        _ => MAX_KEYS,
+    };
+    // the continuation token is the last key we handed out, or the last rolled-up prefix followed by
+    // the highest character there is, which sorts after every key under it
+    let after = input
+        .continuation_token
+        .clone()
+        .or_else(|| input.start_after.clone())
+        .unwrap_or_default();
+
+    // only the keys that make it onto this page get looked into
+    let mut keys = Vec::new();
+    let mut common_prefixes = BTreeSet::new();
+    let mut last = None;
+    let mut is_truncated = false;
+    for key in buckets.list_keys(&input.bucket, &prefix).await? {
+        if key <= after {
+            continue;
+        }
+        // keys with the delimiter after the prefix are rolled up into one prefix
+        let common_prefix = input.delimiter.as_deref().and_then(|delimiter| {
+            key[prefix.len()..]
+                .find(delimiter)
+                .map(|i| key[..prefix.len() + i + delimiter.len()].to_string())
+        });
+        if let Some(common_prefix) = &common_prefix {
+            if common_prefixes.contains(common_prefix) {
+                continue;
+            }
+        }
+        if keys.len() + common_prefixes.len() == max_keys {
+            is_truncated = true;
+            break;
+        }
+        match common_prefix {
+            Some(common_prefix) => {
+                last = Some(format!("{}\u{FFFF}", common_prefix));
+                common_prefixes.insert(common_prefix);
+            }
+            None => {
+                last = Some(key.clone());
+                keys.push(key);
+            }
+        }
+    }
+    let contents: Vec<_> = buckets
+        .objects_info(&input.bucket, keys)
+        .await?
+        .into_iter()
+        .map(|object| Object {
+            owner: wants_cid.then(|| Owner {
+                id: Some(object.cid.to_string()),
+                display_name: None,
+            }),
+            key: Some(object.key),
+            size: object.size as i64,
+            e_tag: Some(object.etag),
+            last_modified: Some(timestamp(object.last_modified)),
+            ..Default::default()
+        })
+        .collect();
+
+    Ok(ListObjectsV2Output {
+        key_count: (contents.len() + common_prefixes.len()) as i32,
+        contents: Some(contents),
+        common_prefixes: Some(
+            common_prefixes
+                .into_iter()
+                .map(|prefix| CommonPrefix {
+                    prefix: Some(prefix),
+                })
+                .collect(),
+        ),
+        is_truncated,
+        next_continuation_token: if is_truncated { last } else { None },
+        continuation_token: input.continuation_token,
+        start_after: input.start_after,
+        max_keys: max_keys as i32,
+        delimiter: input.delimiter,
+        prefix: input.prefix,
+        name: Some(input.bucket),
+        ..Default::default()
+    })
+}
+
 #[async_trait::async_trait]
 impl S3 for WnfsS3Service {
     async fn abort_multipart_upload(
         &self,
         req: S3Request<AbortMultipartUploadInput>,
     ) -> S3Result<AbortMultipartUploadOutput> {
+        // snapshots can't be written to
+        if is_snapshot(&req.input.bucket) {
+            return Err(s3_error!(AccessDenied, "Snapshots are read-only"));
+        }
         if !self.auth.as_ref().has_write_permission_to_bucket(
             req.credentials,
-            req.input.bucket,
+            req.input.bucket.clone(),
         )? {
             return Err(s3_error!(
                 AccessDenied,
@@ -55,7 +293,7 @@ impl S3 for WnfsS3Service {
         };
 
         self.multipart_cloud_storage
-            .cleanup_upload(
+            .abort_upload(
                 req.input.bucket.into(),
                 req.input.key.into(),
                 req.input.upload_id.into(),
@@ -70,23 +308,56 @@ impl S3 for WnfsS3Service {
         &self,
         req: S3Request<CompleteMultipartUploadInput>,
     ) -> S3Result<CompleteMultipartUploadOutput> {
+        // snapshots can't be written to
+        if is_snapshot(&req.input.bucket) {
+            return Err(s3_error!(AccessDenied, "Snapshots are read-only"));
+        }
+        let actor = actor(&req.credentials);
         if !self.auth.as_ref().has_write_permission_to_bucket(
             req.credentials,
-            req.input.bucket,
+            req.input.bucket.clone(),
         )? {
             return Err(s3_error!(
                 AccessDenied,
                 "You do not have write permission to this bucket"
             ));
         };
-        self.multipart_cloud_storage
+        // the parts the client says it uploaded, and the etags it got back for them
+        let parts = req
+            .input
+            .multipart_upload
+            .and_then(|upload| upload.parts)
+            .filter(|parts| !parts.is_empty())
+            .ok_or_else(|| {
+                s3_error!(
+                    MalformedXML,
+                    "The XML you provided was not well-formed or did not validate against our published schema."
+                )
+            })?;
+        let expected_etags = parts
+            .into_iter()
+            .map(|part| match part.e_tag {
+                Some(e_tag) => Ok((part.part_number as u32, e_tag)),
+                None => Err(s3_error!(
+                    InvalidPart,
+                    "One or more of the specified parts could not be found or the specified entity tag might not have matched the part's entity tag."
+                )),
+            })
+            .collect::<S3Result<_>>()?;
+        let e_tag = self
+            .multipart_cloud_storage
             .finish_upload(
-                req.input.bucket.into(),
-                req.input.key.into(),
+                req.input.bucket.clone().into(),
+                req.input.key.clone().into(),
                 req.input.upload_id.into(),
+                expected_etags,
+                &actor,
             )
             .await?;
         Ok(CompleteMultipartUploadOutput {
+            bucket: Some(req.input.bucket),
+            key: Some(req.input.key),
+            e_tag: Some(e_tag),
             ..Default::default()
         })
     }
@@ -98,23 +369,51 @@ impl S3 for WnfsS3Service {
         ))
     }
 
+    /// a public-read ACL makes a public bucket: unencrypted, so its objects can be fetched by cid from any IPFS gateway
     async fn create_bucket(
         &self,
-        _req: S3Request<CreateBucketInput>,
+        req: S3Request<CreateBucketInput>,
     ) -> S3Result<CreateBucketOutput> {
-        Err(s3_error!(
-            NotImplemented,
-            "CreateBucket is not implemented yet"
-        ))
+        let actor = actor(&req.credentials);
+        if !self.auth.as_ref().has_write_permission_to_bucket(
+            req.credentials,
+            req.input.bucket.clone(),
+        )? {
+            return Err(s3_error!(
+                AccessDenied,
+                "You do not have write permission to this bucket"
+            ));
+        };
+        let public = match req.input.acl.as_ref().map(|acl| acl.as_str()) {
+            None | Some(BucketCannedACL::PRIVATE) => false,
+            Some(BucketCannedACL::PUBLIC_READ) => true,
+            Some(acl) => {
+                return Err(s3_error!(
+                    NotImplemented,
+                    "Buckets can only be private or public-read, not {}",
+                    acl
+                ))
+            }
+        };
+        self.buckets
+            .create_bucket(&req.input.bucket, public, &actor)
+            .await?;
+        Ok(CreateBucketOutput {
+            location: Some(format!("/{}", req.input.bucket)),
+        })
     }
 
     async fn create_multipart_upload(
         &self,
         req: S3Request<CreateMultipartUploadInput>,
     ) -> S3Result<CreateMultipartUploadOutput> {
+        // the upload could never be completed
+        if is_snapshot(&req.input.bucket) {
+            return Err(s3_error!(AccessDenied, "Snapshots are read-only"));
+        }
         if !self.auth.as_ref().has_write_permission_to_bucket(
             req.credentials,
-            req.input.bucket,
+            req.input.bucket.clone(),
         )? {
             return Err(s3_error!(
                 AccessDenied,
@@ -126,16 +425,16 @@ impl S3 for WnfsS3Service {
         // create multipart upload
         self.multipart_cloud_storage
             .create_multipart_upload_folder(
-                req.input.bucket.into(),
-                req.input.key.into(),
-                uuid.into(),
+                req.input.bucket.clone().into(),
+                req.input.key.clone().into(),
+                uuid.clone().into(),
             )
             .await?;
         // return that uuid
         Ok(CreateMultipartUploadOutput {
             bucket: Some(req.input.bucket),
             key: Some(req.input.key),
-            upload_id: Some(uuid.to_string()),
+            upload_id: Some(uuid),
             ..Default::default()
         })
     }
@@ -192,12 +491,139 @@ impl S3 for WnfsS3Service {
 
     async fn get_bucket_lifecycle_configuration(
         &self,
-        _req: S3Request<GetBucketLifecycleConfigurationInput>,
+        req: S3Request<GetBucketLifecycleConfigurationInput>,
     ) -> S3Result<GetBucketLifecycleConfigurationOutput> {
-        Err(s3_error!(
-            NotImplemented,
-            "GetBucketLifecycleConfiguration is not implemented yet"
-        ))
+        if !self.auth.as_ref().has_write_permission_to_bucket(
+            req.credentials,
+            req.input.bucket.clone(),
+        )? {
+            return Err(s3_error!(
+                AccessDenied,
+                "You do not have permission to this bucket"
+            ));
+        };
+        let lifecycle = self.registry.get_lifecycle(&req.input.bucket).await?;
+        // deleting a lifecycle configuration leaves an empty one behind
+        let Some(lifecycle) = lifecycle
+            .filter(|lifecycle| !lifecycle.abort_incomplete_multipart_upload.is_empty())
+        else {
+            return Err(s3_error!(
+                NoSuchLifecycleConfiguration,
+                "The lifecycle configuration does not exist"
+            ));
+        };
+        let rules = lifecycle
+            .abort_incomplete_multipart_upload
+            .into_iter()
+            .map(|rule| LifecycleRule {
+                abort_incomplete_multipart_upload: Some(AbortIncompleteMultipartUpload {
+                    days_after_initiation: rule.days_after_initiation as i32,
+                }),
+                expiration: None,
+                filter: Some(LifecycleRuleFilter::Prefix(rule.prefix)),
+                id: rule.id,
+                noncurrent_version_expiration: None,
+                noncurrent_version_transitions: None,
+                prefix: None,
+                status: ExpirationStatus::from_static(ExpirationStatus::ENABLED),
+                transitions: None,
+            })
+            .collect();
+        Ok(GetBucketLifecycleConfigurationOutput {
+            rules: Some(rules),
+        })
+    }
+
+    async fn put_bucket_lifecycle_configuration(
+        &self,
+        req: S3Request<PutBucketLifecycleConfigurationInput>,
+    ) -> S3Result<PutBucketLifecycleConfigurationOutput> {
+        // snapshots can't be written to
+        if is_snapshot(&req.input.bucket) {
+            return Err(s3_error!(AccessDenied, "Snapshots are read-only"));
+        }
+        if !self.auth.as_ref().has_write_permission_to_bucket(
+            req.credentials,
+            req.input.bucket.clone(),
+        )? {
+            return Err(s3_error!(
+                AccessDenied,
+                "You do not have write permission to this bucket"
+            ));
+        };
+        let mut lifecycle = BucketLifecycle::default();
+        let rules = req
+            .input
+            .lifecycle_configuration
+            .map(|configuration| configuration.rules)
+            .unwrap_or_default();
+        for rule in rules {
+            if rule.expiration.is_some()
+                || rule.transitions.is_some()
+                || rule.noncurrent_version_expiration.is_some()
+                || rule.noncurrent_version_transitions.is_some()
+            {
+                return Err(s3_error!(
+                    NotImplemented,
+                    "Only AbortIncompleteMultipartUpload lifecycle rules are supported"
+                ));
+            }
+            if rule.status.as_str() != ExpirationStatus::ENABLED {
+                continue;
+            }
+            let prefix = match rule.filter {
+                Some(LifecycleRuleFilter::Prefix(prefix)) => prefix,
+                Some(_) => {
+                    return Err(s3_error!(
+                        NotImplemented,
+                        "Only prefix filters are supported in lifecycle rules"
+                    ))
+                }
+                None => rule.prefix.unwrap_or_default(),
+            };
+            if let Some(abort) = rule.abort_incomplete_multipart_upload {
+                if abort.days_after_initiation < 1 {
+                    return Err(s3_error!(
+                        InvalidArgument,
+                        "DaysAfterInitiation must be a positive integer"
+                    ));
+                }
+                lifecycle
+                    .abort_incomplete_multipart_upload
+                    .push(AbortIncompleteUploadRule {
+                        id: rule.id,
+                        prefix,
+                        days_after_initiation: abort.days_after_initiation as u32,
+                    });
+            }
+        }
+        self.registry
+            .set_lifecycle(&req.input.bucket, &lifecycle)
+            .await?;
+        Ok(PutBucketLifecycleConfigurationOutput {})
+    }
+
+    async fn delete_bucket_lifecycle(
+        &self,
+        req: S3Request<DeleteBucketLifecycleInput>,
+    ) -> S3Result<DeleteBucketLifecycleOutput> {
+        // snapshots can't be written to
+        if is_snapshot(&req.input.bucket) {
+            return Err(s3_error!(AccessDenied, "Snapshots are read-only"));
+        }
+        if !self.auth.as_ref().has_write_permission_to_bucket(
+            req.credentials,
+            req.input.bucket.clone(),
+        )? {
+            return Err(s3_error!(
+                AccessDenied,
+                "You do not have write permission to this bucket"
+            ));
+        };
+        self.registry
+            .set_lifecycle(&req.input.bucket, &BucketLifecycle::default())
+            .await?;
+        Ok(DeleteBucketLifecycleOutput {})
     }
 
     async fn get_bucket_location(
@@ -227,6 +653,44 @@ impl S3 for WnfsS3Service {
         ))
     }
 
+    async fn list_multipart_uploads(
+        &self,
+        req: S3Request<ListMultipartUploadsInput>,
+    ) -> S3Result<ListMultipartUploadsOutput> {
+        if !self.auth.as_ref().has_write_permission_to_bucket(
+            req.credentials,
+            req.input.bucket.clone(),
+        )? {
+            return Err(s3_error!(
+                AccessDenied,
+                "You do not have permission to this bucket"
+            ));
+        };
+        let manifests = self
+            .multipart_cloud_storage
+            .list_uploads(req.input.bucket.clone().into())
+            .await?;
+        let prefix = req.input.prefix.unwrap_or_default();
+        let uploads = manifests
+            .into_iter()
+            .filter(|manifest| manifest.key.starts_with(&prefix))
+            .map(|manifest| MultipartUpload {
+                initiated: Some(Timestamp::from(std::time::SystemTime::from(
+                    manifest.initiated,
+                ))),
+                key: Some(manifest.key),
+                upload_id: Some(manifest.upload_id),
+                ..Default::default()
+            })
+            .collect();
+        Ok(ListMultipartUploadsOutput {
+            bucket: Some(req.input.bucket),
+            prefix: Some(prefix),
+            uploads: Some(uploads),
+            ..Default::default()
+        })
+    }
+
     async fn list_objects(&self, _req: S3Request<ListObjectsInput>) -> S3Result<ListObjectsOutput> {
         Err(s3_error!(
             NotImplemented,
@@ -244,11 +708,18 @@ impl S3 for WnfsS3Service {
         ))
     }
 
-    async fn get_object(&self, _req: S3Request<GetObjectInput>) -> S3Result<GetObjectOutput> {
-        Err(s3_error!(
-            NotImplemented,
-            "GetObject is not implemented yet"
-        ))
+    async fn get_object(&self, req: S3Request<GetObjectInput>) -> S3Result<GetObjectOutput> {
+        // a snapshot is as readable as the bucket it's of
+        if !self.auth.as_ref().has_write_permission_to_bucket(
+            req.credentials,
+            snapshotted_bucket(&req.input.bucket).to_string(),
+        )? {
+            return Err(s3_error!(
+                AccessDenied,
+                "You do not have permission to this bucket"
+            ));
+        };
+        get_object_output(&self.buckets, req.input, wants_cid(&req.headers)).await
     }
 
     async fn get_object_acl(
@@ -263,12 +734,19 @@ impl S3 for WnfsS3Service {
 
     async fn list_objects_v2(
         &self,
-        _req: S3Request<ListObjectsV2Input>,
+        req: S3Request<ListObjectsV2Input>,
     ) -> S3Result<ListObjectsV2Output> {
-        Err(s3_error!(
-            NotImplemented,
-            "ListObjectsV2 is not implemented yet"
-        ))
+        // a snapshot is as readable as the bucket it's of
+        if !self.auth.as_ref().has_write_permission_to_bucket(
+            req.credentials,
+            snapshotted_bucket(&req.input.bucket).to_string(),
+        )? {
+            return Err(s3_error!(
+                AccessDenied,
+                "You do not have permission to this bucket"
+            ));
+        };
+        list_objects_v2_output(&self.buckets, req.input, wants_cid(&req.headers)).await
     }
 
     async fn head_bucket(&self, _req: S3Request<HeadBucketInput>) -> S3Result<HeadBucketOutput> {
@@ -288,11 +766,18 @@ impl S3 for WnfsS3Service {
         ))
     }
 
-    async fn head_object(&self, _req: S3Request<HeadObjectInput>) -> S3Result<HeadObjectOutput> {
-        Err(s3_error!(
-            NotImplemented,
-            "HeadObject is not implemented yet"
-        ))
+    async fn head_object(&self, req: S3Request<HeadObjectInput>) -> S3Result<HeadObjectOutput> {
+        // a snapshot is as readable as the bucket it's of
+        if !self.auth.as_ref().has_write_permission_to_bucket(
+            req.credentials,
+            snapshotted_bucket(&req.input.bucket).to_string(),
+        )? {
+            return Err(s3_error!(
+                AccessDenied,
+                "You do not have permission to this bucket"
+            ));
+        };
+        head_object_output(&self.buckets, req.input, wants_cid(&req.headers)).await
     }
 
     async fn put_bucket_cors(
@@ -323,10 +808,14 @@ impl S3 for WnfsS3Service {
     }
 
     async fn upload_part(&self, req: S3Request<UploadPartInput>) -> S3Result<UploadPartOutput> {
+        // snapshots can't be written to
+        if is_snapshot(&req.input.bucket) {
+            return Err(s3_error!(AccessDenied, "Snapshots are read-only"));
+        }
         // check write access 
         if !self.auth.as_ref().has_write_permission_to_bucket(
             req.credentials,
-            req.input.bucket,
+            req.input.bucket.clone(),
         )? {
             return Err(s3_error!(
                 AccessDenied,
@@ -337,9 +826,9 @@ impl S3 for WnfsS3Service {
         if !self
             .multipart_cloud_storage
             .check_upload_exists(
-                req.input.bucket.into(),
-                req.input.key.into(),
-                req.input.upload_id.into(),
+                req.input.bucket.clone().into(),
+                req.input.key.clone().into(),
+                req.input.upload_id.clone().into(),
             )
             .await?
         {
@@ -348,22 +837,292 @@ impl S3 for WnfsS3Service {
                 "The specified multipart upload does not exist. The upload ID might be invalid, or the multipart upload might have been aborted or completed."
             ));
         }
-        if req.input.body.is_none() {
+        let Some(body) = req.input.body else {
             return Err(s3_error!(NotImplemented, "UploadPart without a body???"));
-        }
+        };
         // stick it in the upload part table
-        self.multipart_cloud_storage
+        let e_tag = self
+            .multipart_cloud_storage
             .upload_part(
                 req.input.bucket.into(),
                 req.input.key.into(),
                 req.input.upload_id.into(),
                 req.input.part_number as u32,
-                req.input.body.unwrap(),
+                req.input.content_md5,
+                body,
             )
             .await?;
         // done
         Ok(UploadPartOutput {
+            e_tag: Some(e_tag),
             ..Default::default()
         })
     }
+
+    async fn upload_part_copy(
+        &self,
+        req: S3Request<UploadPartCopyInput>,
+    ) -> S3Result<UploadPartCopyOutput> {
+        // snapshots can't be written to
+        if is_snapshot(&req.input.bucket) {
+            return Err(s3_error!(AccessDenied, "Snapshots are read-only"));
+        }
+        if !self.auth.as_ref().has_write_permission_to_bucket(
+            req.credentials.clone(),
+            req.input.bucket.clone(),
+        )? {
+            return Err(s3_error!(
+                AccessDenied,
+                "You do not have write permission to this bucket"
+            ));
+        };
+        if !self
+            .multipart_cloud_storage
+            .check_upload_exists(
+                req.input.bucket.clone().into(),
+                req.input.key.clone().into(),
+                req.input.upload_id.clone().into(),
+            )
+            .await?
+        {
+            return Err(s3_error!(
+                NoSuchUpload,
+                "The specified multipart upload does not exist. The upload ID might be invalid, or the multipart upload might have been aborted or completed."
+            ));
+        }
+        let (source_bucket, source_key) = match &req.input.copy_source {
+            CopySource::Bucket { bucket, key, .. } => (bucket.to_string(), key.to_string()),
+            CopySource::AccessPoint { .. } => {
+                return Err(s3_error!(
+                    NotImplemented,
+                    "Copying from an access point is not supported"
+                ))
+            }
+        };
+        // copying out of a bucket is reading it, which needs permission of its own.
+        // a snapshot is as readable as the bucket it's of.
+        if !self.auth.as_ref().has_write_permission_to_bucket(
+            req.credentials,
+            snapshotted_bucket(&source_bucket).to_string(),
+        )?
+        {
+            return Err(s3_error!(
+                AccessDenied,
+                "You do not have permission to the source bucket"
+            ));
+        };
+        // stream the bytes we want out of wnfs, and stage them like any other part
+        let range = match req.input.copy_source_range {
+            Some(range) => {
+                let (first, last) = parse_copy_source_range(&range)?;
+                let info = self.buckets.object_info(&source_bucket, &source_key).await?;
+                Some(satisfiable_range(info.size, first, last)?)
+            }
+            None => None,
+        };
+        let body = self
+            .buckets
+            .read_object(&source_bucket, &source_key, range)
+            .await?;
+        let e_tag = self
+            .multipart_cloud_storage
+            .upload_part(
+                req.input.bucket.into(),
+                req.input.key.into(),
+                req.input.upload_id.into(),
+                req.input.part_number as u32,
+                None,
+                body,
+            )
+            .await?;
+        Ok(UploadPartCopyOutput {
+            copy_part_result: Some(CopyPartResult {
+                e_tag: Some(e_tag),
+                last_modified: Some(Timestamp::from(std::time::SystemTime::now())),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use s3s::service::S3ServiceBuilder;
+
+    use super::*;
+    use crate::{blockstores::AnyBlockStore, mutex_memory_blockstore::MutexMemoryBlockStore};
+
+    /// the read handlers without the permission check in front of them
+    struct Reads(WnfsBuckets);
+
+    #[async_trait::async_trait]
+    impl S3 for Reads {
+        async fn get_object(&self, req: S3Request<GetObjectInput>) -> S3Result<GetObjectOutput> {
+            get_object_output(&self.0, req.input, wants_cid(&req.headers)).await
+        }
+
+        async fn head_object(&self, req: S3Request<HeadObjectInput>) -> S3Result<HeadObjectOutput> {
+            head_object_output(&self.0, req.input, wants_cid(&req.headers)).await
+        }
+
+        async fn list_objects_v2(
+            &self,
+            req: S3Request<ListObjectsV2Input>,
+        ) -> S3Result<ListObjectsV2Output> {
+            list_objects_v2_output(&self.0, req.input, wants_cid(&req.headers)).await
+        }
+    }
+
+    /// a service over a private bucket called photos with a few objects in it
+    async fn photos() -> s3s::service::S3Service {
+        let buckets = WnfsBuckets::new(
+            Arc::new(BucketRegistry::in_memory()),
+            AnyBlockStore::Memory(MutexMemoryBlockStore::new()),
+        );
+        buckets.create_bucket("photos", false, "test").await.unwrap();
+        for key in ["a/1.txt", "a/2.txt", "b.txt", "c.txt"] {
+            let body = futures::stream::iter([Ok(bytes::Bytes::from(format!("content of {}", key)))]);
+            let etag = format!("\"etag of {}\"", key);
+            buckets.write_object("photos", key, body, &etag, "test").await.unwrap();
+        }
+        S3ServiceBuilder::new(Reads(buckets)).build()
+    }
+
+    async fn send(
+        service: &s3s::service::S3Service,
+        request: hyper::Request<s3s::Body>,
+    ) -> (hyper::StatusCode, HeaderMap, String) {
+        let response = partial_content(service.call(request).await.unwrap());
+        let (parts, body) = response.into_parts();
+        let body = hyper::body::to_bytes(body).await.unwrap();
+        (parts.status, parts.headers, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    fn get(uri: &str) -> hyper::http::request::Builder {
+        hyper::Request::get(format!("http://localhost{}", uri))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn objects_are_read_with_their_etags() {
+        let service = photos().await;
+        let (status, headers, body) = send(&service, get("/photos/b.txt").body(s3s::Body::empty()).unwrap()).await;
+        assert_eq!(status, 200);
+        assert_eq!(body, "content of b.txt");
+        assert_eq!(headers["etag"], "\"etag of b.txt\"");
+        assert!(headers.contains_key("last-modified"));
+        assert!(!headers.contains_key("x-amz-meta-cid"));
+
+        let head = hyper::Request::head("http://localhost/photos/b.txt")
+            .header(INCLUDE_CID_HEADER, "true")
+            .body(s3s::Body::empty())
+            .unwrap();
+        let (status, head_headers, _) = send(&service, head).await;
+        assert_eq!(status, 200);
+        assert_eq!(head_headers["content-length"], "16");
+        assert_eq!(head_headers["etag"], headers["etag"]);
+        assert_eq!(head_headers["last-modified"], headers["last-modified"]);
+        let cid = head_headers["x-amz-meta-cid"].to_str().unwrap();
+        assert!(wnfs::ipld::Cid::try_from(cid).is_ok());
+
+        let (status, _, _) = send(&service, get("/photos/nope.txt").body(s3s::Body::empty()).unwrap()).await;
+        assert_eq!(status, 404);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn ranges_are_read_and_checked() {
+        let service = photos().await;
+        // "content of b.txt" is 16 bytes long
+        for (range, status, content_range, content) in [
+            ("bytes=0-6", 206, "bytes 0-6/16", "content"),
+            ("bytes=11-", 206, "bytes 11-15/16", "b.txt"),
+            ("bytes=-5", 206, "bytes 11-15/16", "b.txt"),
+            ("bytes=11-100", 206, "bytes 11-15/16", "b.txt"),
+        ] {
+            let request = get("/photos/b.txt").header("range", range).body(s3s::Body::empty()).unwrap();
+            let (got_status, headers, body) = send(&service, request).await;
+            assert_eq!(got_status, status, "{}", range);
+            assert_eq!(headers["content-range"], content_range, "{}", range);
+            assert_eq!(headers["content-length"], content.len().to_string().as_str(), "{}", range);
+            assert_eq!(body, content, "{}", range);
+        }
+        let request = get("/photos/b.txt").header("range", "bytes=16-").body(s3s::Body::empty()).unwrap();
+        let (status, _, _) = send(&service, request).await;
+        assert_eq!(status, 416);
+    }
+
+    /// percent-encodes all of a query parameter's value
+    fn encode(value: &str) -> String {
+        value.bytes().map(|byte| format!("%{:02X}", byte)).collect()
+    }
+
+    /// the values of every `<tag>` in some xml, in order
+    fn values<'a>(xml: &'a str, tag: &str) -> Vec<&'a str> {
+        let open = format!("<{}>", tag);
+        let close = format!("</{}>", tag);
+        xml.split(open.as_str())
+            .skip(1)
+            .map(|rest| rest.split(close.as_str()).next().unwrap())
+            .collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn listings_page_with_continuation_tokens() {
+        let service = photos().await;
+        let mut keys = Vec::new();
+        let mut uri = "/photos?list-type=2&max-keys=3".to_string();
+        loop {
+            let (status, _, body) = send(&service, get(&uri).body(s3s::Body::empty()).unwrap()).await;
+            assert_eq!(status, 200);
+            assert!(values(&body, "ETag").iter().all(|etag| etag.starts_with("&quot;etag of ")));
+            assert_eq!(values(&body, "LastModified").len(), values(&body, "Key").len());
+            keys.extend(values(&body, "Key").into_iter().map(str::to_string));
+            match values(&body, "NextContinuationToken").first() {
+                Some(token) => {
+                    assert_eq!(values(&body, "IsTruncated"), ["true"]);
+                    uri = format!(
+                        "/photos?list-type=2&max-keys=3&continuation-token={}",
+                        encode(token)
+                    );
+                }
+                None => break,
+            }
+        }
+        assert_eq!(keys, ["a/1.txt", "a/2.txt", "b.txt", "c.txt"]);
+
+        // rolled-up prefixes take up a key of the page, and aren't repeated on the next one
+        let uri = "/photos?list-type=2&max-keys=2&delimiter=%2F";
+        let (_, _, body) = send(&service, get(uri).body(s3s::Body::empty()).unwrap()).await;
+        assert_eq!(values(&body, "Prefix"), ["a/"]);
+        assert_eq!(values(&body, "Key"), ["b.txt"]);
+        let token = values(&body, "NextContinuationToken")[0].to_string();
+        let uri = format!("{}&continuation-token={}", uri, encode(&token));
+        let (_, _, body) = send(&service, get(&uri).body(s3s::Body::empty()).unwrap()).await;
+        assert_eq!(values(&body, "Key"), ["c.txt"]);
+        assert_eq!(values(&body, "IsTruncated"), ["false"]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn listings_cap_max_keys_and_only_give_cids_when_asked() {
+        let service = photos().await;
+        let (_, _, body) = send(
+            &service,
+            get("/photos?list-type=2&max-keys=5000").body(s3s::Body::empty()).unwrap(),
+        )
+        .await;
+        assert_eq!(values(&body, "MaxKeys"), ["1000"]);
+        assert!(values(&body, "ID").is_empty());
+
+        let request = get("/photos?list-type=2&prefix=b")
+            .header(INCLUDE_CID_HEADER, "true")
+            .body(s3s::Body::empty())
+            .unwrap();
+        let (_, _, body) = send(&service, request).await;
+        let head = hyper::Request::head("http://localhost/photos/b.txt")
+            .header(INCLUDE_CID_HEADER, "true")
+            .body(s3s::Body::empty())
+            .unwrap();
+        let (_, headers, _) = send(&service, head).await;
+        assert_eq!(values(&body, "ID"), [headers["x-amz-meta-cid"].to_str().unwrap()]);
+    }
 }